@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    error::{PwdError, PwdResult},
+    ffi,
+    security::{SecString, SecVec},
+};
+
+/// How long the agent keeps the keys without being used, by default
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+// how long a peer may take to send or read a message
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+// how often the idle timeout is checked
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// the requests and responses are a single short line
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+/// The requests sent to the agent, one per connection
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+enum Request {
+    /// the cached key of a vault
+    Key { vault: PathBuf },
+    /// cache the key of a vault, hex encoded
+    Unlock { vault: PathBuf, key: SecString },
+    /// forget all the keys
+    Lock,
+    /// forget all the keys and exit
+    Stop,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+enum Response {
+    /// the hex encoded key
+    Key {
+        key: SecString,
+    },
+    /// the agent doesn't hold the key
+    Locked,
+    Ok,
+}
+
+/// The keys held by the agent, by canonical vault path
+struct Keys {
+    keys: HashMap<PathBuf, SecVec<u8>>,
+    last_used: Instant,
+}
+
+impl Keys {
+    /// Forget the keys when they weren't used for `timeout`
+    fn expire(&mut self, timeout: Duration) {
+        if self.last_used.elapsed() >= timeout {
+            self.keys.clear();
+        }
+    }
+}
+
+/// A daemon caching the derived vault keys, so the commands don't
+/// prompt the master password and run the key derivation each time.
+///
+/// The agent listens on a unix socket only accessible by its owner,
+/// and rejects the connections from processes of other users. The
+/// keys are forgotten when they aren't used for the idle timeout.
+pub struct Agent {
+    listener: UnixListener,
+    socket_path: PathBuf,
+    idle_timeout: Duration,
+    keys: Arc<Mutex<Keys>>,
+}
+
+impl Agent {
+    /// Listen on the socket, replacing the one left by a killed agent
+    pub fn bind<P: AsRef<Path>>(socket_path: P, idle_timeout: Duration) -> PwdResult<Self> {
+        let socket_path = socket_path.as_ref();
+
+        if is_running(socket_path) {
+            return Err(PwdError::AgentAlreadyRunning);
+        }
+        // only a stale socket is removed, never another file
+        if let Ok(metadata) = fs::symlink_metadata(socket_path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(socket_path)?;
+            }
+        }
+
+        if let Some(socket_dir) = socket_path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(socket_dir)?;
+        }
+
+        let listener = UnixListener::bind(socket_path)?;
+        // the other users are rejected by the peer check anyway,
+        // if they connect before the permissions are set
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+
+        Ok(Self {
+            listener,
+            socket_path: socket_path.to_path_buf(),
+            idle_timeout,
+            keys: Arc::new(Mutex::new(Keys {
+                keys: HashMap::new(),
+                last_used: Instant::now(),
+            })),
+        })
+    }
+
+    /// Answer the requests until the agent is stopped
+    pub fn run(self) -> PwdResult<()> {
+        // wipe the keys on time, even if the agent isn't used
+        let keys = Arc::clone(&self.keys);
+        let idle_timeout = self.idle_timeout;
+        thread::spawn(move || loop {
+            thread::sleep(IDLE_CHECK_INTERVAL.min(idle_timeout));
+            keys.lock().unwrap().expire(idle_timeout);
+        });
+
+        for stream in self.listener.incoming() {
+            match stream.and_then(|stream| self.answer(stream)) {
+                Ok(true) => break,
+                Ok(false) => {}
+                // a failed request doesn't stop the agent
+                Err(error) => eprintln!("pwdeck agent: {}", error),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a connection, returning whether the agent was stopped
+    fn answer(&self, mut stream: UnixStream) -> io::Result<bool> {
+        check_peer(&stream)?;
+        set_timeouts(&stream)?;
+
+        let request: Request = read_message(&stream)?;
+
+        let mut keys = self.keys.lock().unwrap();
+        keys.expire(self.idle_timeout);
+
+        let mut stop = false;
+        let response = match request {
+            Request::Key { vault } => match keys.keys.get(&vault) {
+                Some(key) => {
+                    let response = Response::Key {
+                        key: encode_key(key),
+                    };
+                    keys.last_used = Instant::now();
+                    response
+                }
+                None => Response::Locked,
+            },
+            Request::Unlock { vault, key } => {
+                keys.keys.insert(vault, decode_key(&key)?);
+                keys.last_used = Instant::now();
+                Response::Ok
+            }
+            Request::Lock => {
+                keys.keys.clear();
+                Response::Ok
+            }
+            Request::Stop => {
+                keys.keys.clear();
+                stop = true;
+                Response::Ok
+            }
+        };
+        drop(keys);
+
+        write_message(&mut stream, &response)?;
+
+        Ok(stop)
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+/// Whether an agent listens on the socket
+pub fn is_running<P: AsRef<Path>>(socket_path: P) -> bool {
+    UnixStream::connect(socket_path).is_ok()
+}
+
+/// Get the key of the vault cached by the agent, `None` if it doesn't hold it
+pub fn cached_key<P, Q>(socket_path: P, vault_path: Q) -> PwdResult<Option<SecVec<u8>>>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let vault = fs::canonicalize(vault_path)?;
+
+    match send(socket_path.as_ref(), &Request::Key { vault })? {
+        Response::Key { key } => Ok(Some(decode_key(&key)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Cache the key of the vault in the agent
+pub fn unlock<P, Q>(socket_path: P, vault_path: Q, key: &[u8]) -> PwdResult<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let request = Request::Unlock {
+        vault: fs::canonicalize(vault_path)?,
+        key: encode_key(key),
+    };
+
+    send(socket_path.as_ref(), &request).map(|_| ())
+}
+
+/// Make the agent forget all the keys
+pub fn lock<P: AsRef<Path>>(socket_path: P) -> PwdResult<()> {
+    send(socket_path.as_ref(), &Request::Lock).map(|_| ())
+}
+
+/// Stop the agent, its keys are forgotten
+pub fn stop<P: AsRef<Path>>(socket_path: P) -> PwdResult<()> {
+    send(socket_path.as_ref(), &Request::Stop).map(|_| ())
+}
+
+/// Send a request to the agent and read its response
+fn send(socket_path: &Path, request: &Request) -> PwdResult<Response> {
+    let mut stream = UnixStream::connect(socket_path).map_err(|error| match error.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => PwdError::AgentNotRunning,
+        _ => error.into(),
+    })?;
+    // the keys are only sent to an agent of the same user
+    check_peer(&stream)?;
+    set_timeouts(&stream)?;
+
+    write_message(&mut stream, request)?;
+
+    Ok(read_message(&stream)?)
+}
+
+fn set_timeouts(stream: &UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))
+}
+
+/// Read a JSON message, up to the end of the line
+fn read_message<T: DeserializeOwned>(stream: &UnixStream) -> io::Result<T> {
+    let mut reader = BufReader::new(stream.take(MAX_MESSAGE_SIZE));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    // the messages may hold a key
+    let line = SecString::from(line);
+    serde_json::from_str(&line).map_err(io::Error::from)
+}
+
+fn write_message<T: Serialize>(stream: &mut UnixStream, message: &T) -> io::Result<()> {
+    let line = SecString::from(serde_json::to_string(message)? + "\n");
+
+    stream.write_all(line.as_bytes())
+}
+
+/// Reject the peers running as another user
+fn check_peer(stream: &UnixStream) -> io::Result<()> {
+    // SAFETY: geteuid has no preconditions
+    let uid = unsafe { ffi::geteuid() };
+
+    if peer_uid(stream)? != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the socket peer belongs to another user",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials = ffi::UCred::default();
+    let mut len = std::mem::size_of::<ffi::UCred>() as u32;
+
+    // SAFETY: the file descriptor is valid while `stream` lives,
+    // and `credentials` has the size given in `len`
+    let result = unsafe {
+        ffi::getsockopt(
+            stream.as_raw_fd(),
+            ffi::SOL_SOCKET,
+            ffi::SO_PEERCRED,
+            &mut credentials,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(credentials.uid)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+
+    // SAFETY: the file descriptor is valid while `stream` lives
+    if unsafe { ffi::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(uid)
+}
+
+fn encode_key(key: &[u8]) -> SecString {
+    let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hex.into()
+}
+
+fn decode_key(hex: &str) -> io::Result<SecVec<u8>> {
+    let key = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid key encoding"))
+        })
+        .collect::<io::Result<Vec<u8>>>()?;
+
+    Ok(key.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // any existing file, the agent doesn't read the vaults
+    const VAULT_PATH: &str = "Cargo.toml";
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn start_agent(socket_path: &str, idle_timeout: Duration) -> thread::JoinHandle<()> {
+        let agent = Agent::bind(socket_path, idle_timeout).unwrap();
+        thread::spawn(move || agent.run().unwrap())
+    }
+
+    #[test]
+    fn cache_keys() {
+        const SOCKET_PATH: &str = "target/debug_agent.sock";
+        let agent = start_agent(SOCKET_PATH, DEFAULT_IDLE_TIMEOUT);
+
+        assert!(cached_key(SOCKET_PATH, VAULT_PATH).unwrap().is_none());
+
+        unlock(SOCKET_PATH, VAULT_PATH, KEY).unwrap();
+        let key = cached_key(SOCKET_PATH, VAULT_PATH).unwrap().unwrap();
+        assert_eq!(key.as_slice(), KEY);
+
+        lock(SOCKET_PATH).unwrap();
+        assert!(cached_key(SOCKET_PATH, VAULT_PATH).unwrap().is_none());
+
+        stop(SOCKET_PATH).unwrap();
+        agent.join().unwrap();
+        assert!(!Path::new(SOCKET_PATH).exists());
+        assert!(matches!(lock(SOCKET_PATH), Err(PwdError::AgentNotRunning)));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        const SOCKET_PATH: &str = "target/debug_agent_idle.sock";
+        let agent = start_agent(SOCKET_PATH, Duration::from_millis(200));
+
+        unlock(SOCKET_PATH, VAULT_PATH, KEY).unwrap();
+        assert!(cached_key(SOCKET_PATH, VAULT_PATH).unwrap().is_some());
+
+        thread::sleep(Duration::from_millis(300));
+        assert!(cached_key(SOCKET_PATH, VAULT_PATH).unwrap().is_none());
+
+        stop(SOCKET_PATH).unwrap();
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn private_socket() {
+        const SOCKET_PATH: &str = "target/debug_agent_private.sock";
+        let agent = start_agent(SOCKET_PATH, DEFAULT_IDLE_TIMEOUT);
+
+        let mode = fs::metadata(SOCKET_PATH).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // a single agent per socket
+        assert!(matches!(
+            Agent::bind(SOCKET_PATH, DEFAULT_IDLE_TIMEOUT),
+            Err(PwdError::AgentAlreadyRunning)
+        ));
+
+        stop(SOCKET_PATH).unwrap();
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn key_encoding() {
+        assert_eq!(encode_key(&[0, 0xab, 0x10]).as_str(), "00ab10");
+        assert_eq!(decode_key("00ab10").unwrap().as_slice(), [0, 0xab, 0x10]);
+        assert!(decode_key("abc").is_err());
+        assert!(decode_key("zz").is_err());
+    }
+}
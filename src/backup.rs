@@ -0,0 +1,227 @@
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{PwdError, PwdResult},
+    password,
+    vault::{KdfParams, Vault, VaultSchema},
+};
+
+/// The magic bytes at the start of the backup files
+const BUNDLE_MAGIC: &[u8; 4] = b"PWDB";
+/// The current backup format version
+const BUNDLE_VERSION: u16 = 1;
+/// The size of the magic, the version and the manifest length
+const BUNDLE_HEADER_SIZE: usize = 10;
+
+/// The plaintext description of a backup, readable without its passphrase
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// when the backup was created, in unix seconds
+    pub created_at: u64,
+    pub groups: usize,
+    pub entries: usize,
+    /// the SHA-256 of the encrypted vault, hex encoded
+    pub checksum: String,
+}
+
+/// An encrypted backup of the vault.
+///
+/// The bundle starts with the `PWDB` magic, the format version and the
+/// JSON manifest, followed by the entries encrypted in the vault file
+/// format, with their own passphrase and KDF params.
+pub struct Bundle {
+    pub manifest: Manifest,
+    // the encrypted vault
+    payload: Vec<u8>,
+}
+
+impl Bundle {
+    /// Encrypt the schema with the given passphrase
+    pub fn create(schema: &VaultSchema, passphrase: &str, kdf: KdfParams) -> PwdResult<Self> {
+        let payload = Vault::seal_schema(schema, passphrase, kdf)?;
+
+        let manifest = Manifest {
+            created_at: password::now(),
+            groups: schema.passwords.len(),
+            entries: schema.entry_count(),
+            checksum: checksum(&payload),
+        };
+
+        Ok(Self { manifest, payload })
+    }
+
+    /// Read the backup file, without checking its content
+    pub fn from_file<P: AsRef<Path>>(path: P) -> PwdResult<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    fn from_bytes(buffer: &[u8]) -> PwdResult<Self> {
+        if buffer.len() < BUNDLE_HEADER_SIZE || &buffer[..4] != BUNDLE_MAGIC {
+            return Err(PwdError::InvalidBackupFile);
+        }
+
+        let version = u16::from_le_bytes(buffer[4..6].try_into().unwrap());
+        if version != BUNDLE_VERSION {
+            return Err(PwdError::UnsupportedVaultVersion(version));
+        }
+
+        let manifest_len = u32::from_le_bytes(buffer[6..10].try_into().unwrap()) as usize;
+        let rest = &buffer[BUNDLE_HEADER_SIZE..];
+        if manifest_len > rest.len() {
+            return Err(PwdError::InvalidBackupFile);
+        }
+
+        let (manifest, payload) = rest.split_at(manifest_len);
+        let manifest = serde_json::from_slice(manifest).map_err(|_| PwdError::InvalidBackupFile)?;
+
+        Ok(Self {
+            manifest,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// The content of the backup file
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let manifest = serde_json::to_vec(&self.manifest)?;
+
+        let mut buffer =
+            Vec::with_capacity(BUNDLE_HEADER_SIZE + manifest.len() + self.payload.len());
+        buffer.write_all(BUNDLE_MAGIC)?;
+        buffer.write_u16::<LittleEndian>(BUNDLE_VERSION)?;
+        buffer.write_u32::<LittleEndian>(manifest.len() as u32)?;
+        buffer.write_all(&manifest)?;
+        buffer.write_all(&self.payload)?;
+
+        Ok(buffer)
+    }
+
+    /// Check the encrypted entries against the manifest checksum,
+    /// which doesn't need the passphrase
+    pub fn verify(&self) -> PwdResult<()> {
+        if checksum(&self.payload) != self.manifest.checksum {
+            return Err(PwdError::InvalidBackupFile);
+        }
+
+        Ok(())
+    }
+
+    /// Verify and decrypt the backup entries
+    pub fn open(&self, passphrase: &str) -> PwdResult<VaultSchema> {
+        self.verify()?;
+
+        let schema = Vault::from_bytes(self.payload.clone(), passphrase)
+            .map_err(|error| match error {
+                PwdError::InvalidVaultFile | PwdError::UnsupportedVaultVersion(_) => {
+                    PwdError::InvalidBackupFile
+                }
+                error => error,
+            })?
+            .into_schema();
+
+        // the manifest is not encrypted, so it's checked against the entries
+        if schema.entry_count() != self.manifest.entries
+            || schema.passwords.len() != self.manifest.groups
+        {
+            return Err(PwdError::InvalidBackupFile);
+        }
+
+        Ok(schema)
+    }
+}
+
+/// The hex encoded SHA-256 of the data
+fn checksum(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{password::Entry, vault::TEST_KDF};
+
+    const PASSPHRASE: &str = "backup passphrase";
+
+    fn test_bundle() -> Bundle {
+        let mut schema = VaultSchema::default();
+        schema.passwords.insert(
+            String::from("Github"),
+            vec![Entry::new("me", "123"), Entry::new("work", "456")],
+        );
+        schema
+            .passwords
+            .insert(String::from("Reddit"), vec![Entry::new("user", "abc")]);
+
+        Bundle::create(&schema, PASSPHRASE, TEST_KDF).unwrap()
+    }
+
+    #[test]
+    fn backup_roundtrip() {
+        let bundle = test_bundle();
+        assert_eq!(bundle.manifest.groups, 2);
+        assert_eq!(bundle.manifest.entries, 3);
+
+        let bundle = Bundle::from_bytes(&bundle.to_bytes().unwrap()).unwrap();
+        bundle.verify().unwrap();
+
+        let schema = bundle.open(PASSPHRASE).unwrap();
+        assert_eq!(schema.entry_count(), 3);
+        assert_eq!(schema.passwords["Reddit"][0].password().as_str(), "abc");
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        assert!(matches!(
+            test_bundle().open("wrong"),
+            Err(PwdError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn corrupted_backup() {
+        let mut buffer = test_bundle().to_bytes().unwrap();
+        let last = buffer.len() - 1;
+        buffer[last] ^= 1;
+
+        // detected by the checksum, without the passphrase
+        let bundle = Bundle::from_bytes(&buffer).unwrap();
+        assert!(matches!(bundle.verify(), Err(PwdError::InvalidBackupFile)));
+    }
+
+    #[test]
+    fn tampered_manifest() {
+        let mut bundle = test_bundle();
+        bundle.manifest.entries = 2;
+
+        assert!(bundle.verify().is_ok());
+        assert!(matches!(
+            bundle.open(PASSPHRASE),
+            Err(PwdError::InvalidBackupFile)
+        ));
+    }
+
+    #[test]
+    fn not_a_backup() {
+        assert!(matches!(
+            Bundle::from_bytes(b"PWDK\x01\x00"),
+            Err(PwdError::InvalidBackupFile)
+        ));
+
+        let mut buffer = test_bundle().to_bytes().unwrap();
+        // the manifest length goes past the end of the file
+        buffer[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            Bundle::from_bytes(&buffer),
+            Err(PwdError::InvalidBackupFile)
+        ));
+    }
+}
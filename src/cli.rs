@@ -5,18 +5,79 @@
 //  - Better command interface
 
 use std::{
-    fs::{File, OpenOptions},
-    io,
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
 };
 
 use clap::{AppSettings, Arg, SubCommand};
 
 use crate::{
-    generator::{GenerationMethod, Generator},
-    password::Entry,
-    vault::Vault,
+    agent::{self, Agent},
+    backup::Bundle,
+    error::{PwdError, PwdResult},
+    export::{self, ExportFormat},
+    generator::{CharClass, GenerationMethod, Generator, RandomPolicy},
+    import::{self, ImportFormat},
+    lock::VaultLock,
+    otp::Otp,
+    output::{EntriesOutput, EntryOutput, HistoryOutput, OtpOutput},
+    password::{CustomField, Entry, EntryUpdate},
+    query::{FuzzyMatch, MatchKind, Pattern, Query},
+    security::SecString,
+    shell::Shell,
+    ui::App,
+    vault::{short_id, KdfAlgorithm, KdfParams, MergeConflict, Resolution, Vault},
 };
 
+/// Exit code for a wrong master password
+const EXIT_AUTHENTICATION_FAILED: i32 = 2;
+/// Exit code for a corrupted or unreadable vault or backup file
+const EXIT_INVALID_VAULT: i32 = 3;
+/// Exit code for an invalid password entry
+const EXIT_INVALID_PASSWORD: i32 = 4;
+/// Exit code for I/O errors
+const EXIT_IO: i32 = 5;
+/// Exit code for out of range key derivation params
+const EXIT_INVALID_KDF_PARAMS: i32 = 6;
+/// Exit code for a vault locked by another process
+const EXIT_VAULT_LOCKED: i32 = 7;
+/// Exit code for unknown entry IDs and groups
+const EXIT_NOT_FOUND: i32 = 8;
+/// Exit code for queries matching several entries
+const EXIT_AMBIGUOUS: i32 = 9;
+/// Exit code for malformed import files
+const EXIT_INVALID_IMPORT: i32 = 10;
+/// Exit code for an agent not running, or already running
+const EXIT_AGENT: i32 = 11;
+
+/// How many matches `find` prints by default
+const DEFAULT_FIND_LIMIT: usize = 10;
+
+/// How long a command waits for another process to release the vault
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The default unlock time targeted by the KDF calibration, in milliseconds
+const DEFAULT_UNLOCK_TARGET: u64 = 500;
+
+/// How long `agent` waits for the background agent to listen
+const AGENT_START_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the read commands print their results
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// the schema documented in the README
+    Json,
+    /// tab separated values, one entry per line
+    Plain,
+    /// human readable, the default
+    Tree,
+}
+
 pub struct CLI<'a>(clap::ArgMatches<'a>);
 
 impl<'a> CLI<'a> {
@@ -25,13 +86,20 @@ impl<'a> CLI<'a> {
             .version("0.1.0")
             .author("Protoshark <protoshark@pm.me>")
             .about("A simple password manager")
-            .after_help("NOTE: The vault file can be configured via PWDECK_VAULT environment variable")
+            .after_help("NOTE: The vault file can be configured via PWDECK_VAULT environment variable, and the agent socket via PWDECK_AGENT_SOCK")
             .settings(&[
                 AppSettings::GlobalVersion,
                 AppSettings::DisableHelpSubcommand,
-                AppSettings::ArgsNegateSubcommands,
                 AppSettings::SubcommandRequiredElseHelp,
             ])
+            .arg(Arg::with_name("format")
+                .long("format")
+                .help("The output format of the read commands (get, find, history and otp), or of the export")
+                .long_help("The output format of the read commands (get, find, history and otp): json, plain or tree (the default). The export supports json (the default) and csv")
+                .takes_value(true)
+                .possible_values(&["json", "plain", "tree", "csv"])
+                .global(true)
+            )
             // pwdeck generate
             .subcommand(
                 SubCommand::with_name("generate")
@@ -56,7 +124,12 @@ impl<'a> CLI<'a> {
                             .takes_value(true)
                             .display_order(3)
                             .required_if("method", "diceware")
-                    ).display_order(0),
+                    ).arg(Arg::with_name("show-entropy")
+                            .long("show-entropy")
+                            .help("Show the entropy of the password, in bits")
+                            .display_order(16)
+                    ).args(&random_policy_args())
+                    .display_order(0),
             )
             // pwdeck new
             .subcommand(
@@ -77,7 +150,292 @@ impl<'a> CLI<'a> {
                             .required(true)
                             .takes_value(true)
                             .display_order(1),
-                    ).display_order(1),
+                    ).arg(Arg::with_name("kdf")
+                            .long("kdf")
+                            .help("The key derivation function (scrypt or argon2id), used when creating the vault")
+                            .takes_value(true)
+                            .possible_values(&["scrypt", "argon2id"])
+                            .display_order(2),
+                    ).args(&entry_field_args())
+                    .args(&kdf_cost_args())
+                    .display_order(1),
+            )
+            // pwdeck passwd
+            .subcommand(
+                SubCommand::with_name("passwd")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Change the vault master password")
+                    .display_order(3),
+            )
+            // pwdeck rekey
+            .subcommand(
+                SubCommand::with_name("rekey")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Change the vault key derivation function or its cost")
+                    .arg(Arg::with_name("kdf")
+                            .long("kdf")
+                            .help("The key derivation function (scrypt or argon2id)")
+                            .takes_value(true)
+                            .possible_values(&["scrypt", "argon2id"])
+                            .display_order(0),
+                    ).args(&kdf_cost_args())
+                    .display_order(4),
+            )
+            // pwdeck edit
+            .subcommand(
+                SubCommand::with_name("edit")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Edit a vault entry")
+                    .arg(Arg::with_name("id")
+                            .help("The ID of the entry to edit, or a unique prefix of it")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("username")
+                            .long("username")
+                            .short("u")
+                            .help("The new username")
+                            .takes_value(true)
+                            .display_order(0),
+                    ).arg(Arg::with_name("password")
+                            .long("password")
+                            .short("p")
+                            .help("Change the password, reading it like `new` does")
+                            .display_order(1),
+                    ).args(&entry_field_args())
+                    .arg(Arg::with_name("remove-field")
+                            .long("remove-field")
+                            .value_name("name")
+                            .help("Remove a custom field")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .display_order(20),
+                    ).display_order(5),
+            )
+            // pwdeck mv
+            .subcommand(
+                SubCommand::with_name("mv")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Move an entry to another group, or rename a group")
+                    .arg(Arg::with_name("source")
+                            .help("The ID of the entry to move (or the group name with --group)")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("destination")
+                            .help("The destination group (or the new group name with --group)")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("group")
+                            .long("group")
+                            .short("g")
+                            .help("Rename the group <source> to <destination>")
+                    ).display_order(6),
+            )
+            // pwdeck rm
+            .subcommand(
+                SubCommand::with_name("rm")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Remove an entry from the vault")
+                    .arg(Arg::with_name("id")
+                            .help("The ID of the entry to remove, or a unique prefix of it")
+                            .required(true)
+                            .takes_value(true)
+                    ).display_order(7),
+            )
+            // pwdeck history
+            .subcommand(
+                SubCommand::with_name("history")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("List the previous passwords of an entry, or restore one")
+                    .arg(Arg::with_name("id")
+                            .help("The ID of the entry, or a unique prefix of it")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("restore")
+                            .long("restore")
+                            .short("r")
+                            .value_name("n")
+                            .help("Restore the password with the given number in the listing")
+                            .takes_value(true)
+                    ).arg(Arg::with_name("reveal")
+                            .long("reveal")
+                            .help("Show the previous passwords")
+                    ).display_order(8),
+            )
+            // pwdeck find
+            .subcommand(
+                SubCommand::with_name("find")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Find entries by group, name, URL or tag, allowing typos")
+                    .arg(Arg::with_name("query")
+                            .help("The text to search")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("limit")
+                            .long("limit")
+                            .short("n")
+                            .help("The maximum number of matches to show [default: 10]")
+                            .takes_value(true)
+                    ).display_order(9),
+            )
+            // pwdeck export
+            .subcommand(
+                SubCommand::with_name("export")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Export the vault to a plaintext JSON or CSV file")
+                    .arg(Arg::with_name("output")
+                            .long("output")
+                            .short("o")
+                            .value_name("file")
+                            .help("The file to create")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("unsafe-plaintext")
+                            .long("unsafe-plaintext")
+                            .help("Acknowledge that the passwords are written unencrypted")
+                    ).display_order(10),
+            )
+            // pwdeck import
+            .subcommand(
+                SubCommand::with_name("import")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Import the entries exported by another password manager")
+                    .arg(Arg::with_name("file")
+                            .help("The exported file")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("from")
+                            .long("from")
+                            .value_name("manager")
+                            .help("The password manager which exported the file")
+                            .possible_values(&["bitwarden", "keepass", "1password", "chrome", "firefox"])
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("dry-run")
+                            .long("dry-run")
+                            .help("Show what would be imported, without changing the vault")
+                    ).display_order(11),
+            )
+            // pwdeck backup
+            .subcommand(
+                SubCommand::with_name("backup")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Write an encrypted backup of the vault, with its own passphrase")
+                    .arg(Arg::with_name("output")
+                            .long("output")
+                            .short("o")
+                            .value_name("file")
+                            .help("The backup file to create")
+                            .required(true)
+                            .takes_value(true)
+                            .display_order(0),
+                    ).arg(Arg::with_name("kdf")
+                            .long("kdf")
+                            .help("The key derivation function of the backup (scrypt or argon2id) [default: the vault one]")
+                            .takes_value(true)
+                            .possible_values(&["scrypt", "argon2id"])
+                            .display_order(1),
+                    ).args(&kdf_cost_args())
+                    .display_order(12),
+            )
+            // pwdeck restore
+            .subcommand(
+                SubCommand::with_name("restore")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Restore the entries of an encrypted backup")
+                    .arg(Arg::with_name("file")
+                            .help("The backup file")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("replace")
+                            .long("replace")
+                            .help("Replace all the vault entries instead of adding the missing ones")
+                    ).arg(Arg::with_name("verify")
+                            .long("verify")
+                            .help("Only check the backup integrity, without the passphrase")
+                            .conflicts_with("replace")
+                    ).display_order(13),
+            )
+            // pwdeck merge
+            .subcommand(
+                SubCommand::with_name("merge")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Merge the entries of another copy of the vault")
+                    .arg(Arg::with_name("other")
+                            .help("The other vault file, e.g. a conflicting copy made by a sync tool")
+                            .required(true)
+                            .takes_value(true)
+                    ).arg(Arg::with_name("strategy")
+                            .long("strategy")
+                            .help("How the entries changed in both vaults are merged")
+                            .long_help("How the entries changed in both vaults are merged: ask for each one (the default), keep ours, take theirs, or keep the newer one")
+                            .takes_value(true)
+                            .possible_values(&["ask", "ours", "theirs", "newer"])
+                    ).display_order(14),
+            )
+            // pwdeck otp
+            .subcommand(
+                SubCommand::with_name("otp")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Print the current TOTP/HOTP code of an entry")
+                    .arg(Arg::with_name("id")
+                            .help("The entry ID (or a unique prefix of it), or a query matching a single entry")
+                            .required(true)
+                            .takes_value(true)
+                    ).display_order(15),
+            )
+            // pwdeck agent
+            .subcommand(
+                SubCommand::with_name("agent")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Start an agent caching the vault key, so the commands don't ask the master password")
+                    .arg(Arg::with_name("timeout")
+                            .long("timeout")
+                            .value_name("seconds")
+                            .help("Forget the key when it isn't used for this long [default: 900]")
+                            .takes_value(true)
+                            .display_order(0)
+                    ).arg(Arg::with_name("foreground")
+                            .long("foreground")
+                            .help("Run the agent in the foreground, instead of in the background")
+                            .display_order(1)
+                    ).arg(Arg::with_name("stop")
+                            .long("stop")
+                            .help("Stop the running agent")
+                            .conflicts_with_all(&["timeout", "foreground"])
+                            .display_order(2)
+                    ).display_order(16),
+            )
+            // pwdeck unlock
+            .subcommand(
+                SubCommand::with_name("unlock")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Unlock the vault in the agent")
+                    .display_order(17),
+            )
+            // pwdeck lock
+            .subcommand(
+                SubCommand::with_name("lock")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Make the agent forget the vault key")
+                    .display_order(18),
+            )
+            // pwdeck shell
+            .subcommand(
+                SubCommand::with_name("shell")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Unlock the vault once and run commands on it interactively")
+                    .arg(Arg::with_name("autosave")
+                            .long("autosave")
+                            .help("Save the vault after every change, instead of on `save`")
+                    ).display_order(19),
+            )
+            // pwdeck ui
+            .subcommand(
+                SubCommand::with_name("ui")
+                    .setting(clap::AppSettings::DisableVersion)
+                    .about("Browse and edit the vault in a terminal UI")
+                    .display_order(20),
             )
             // pwdeck list
             .subcommand(
@@ -85,23 +443,45 @@ impl<'a> CLI<'a> {
                     .setting(clap::AppSettings::DisableVersion)
                     .about("List vault entries")
                     .arg(Arg::with_name("id")
-                        .help("The entry ID to get the password")
+                        .help("The entry ID (or a unique prefix of it) to get the password")
+                        .long_help("The entry ID (or a unique prefix of it) to get the password. If no entry has this ID, it is searched like `pwdeck find` does, and the password is printed if a single entry matches confidently")
                         .takes_value(true)
                     ).arg(Arg::with_name("service")
                         .long("service")
                         .short("s")
                         .help("Filter entries matching service")
+                        .long_help("Filter entries matching service. The service must contain the filter, unless it has glob wildcards (* and ?) or --exact is passed")
                         .takes_value(true)
+                        .conflicts_with("id")
                         .display_order(0)
                     ).arg(Arg::with_name("username")
                         .long("username")
                         .short("u")
                         .help("Filter entries matching username")
+                        .long_help("Filter entries matching username. The username must contain the filter, unless it has glob wildcards (* and ?) or --exact is passed")
                         .takes_value(true)
+                        .conflicts_with("id")
                         .display_order(1)
+                    ).arg(Arg::with_name("exact")
+                        .long("exact")
+                        .help("Match the filters exactly")
+                        .display_order(4)
+                    ).arg(Arg::with_name("case-sensitive")
+                        .long("case-sensitive")
+                        .help("Don't ignore the case when matching the filters")
+                        .display_order(5)
+                    ).arg(Arg::with_name("details")
+                        .long("details")
+                        .short("d")
+                        .help("Show all the entry fields instead of the password")
+                        .requires("id")
+                        .display_order(2)
+                    ).arg(Arg::with_name("reveal")
+                        .long("reveal")
+                        .help("Show the passwords and the secret fields with --details or --format json")
+                        .display_order(3)
                     )
             );
-        // TODO: more commands such as export, import, ...
 
         Self(app.get_matches())
     }
@@ -112,30 +492,315 @@ impl<'a> CLI<'a> {
 
         // println!("{}", vault_file);
 
-        match args.subcommand() {
+        let result = match args.subcommand() {
             ("generate", Some(generate_args)) => handle_generate(generate_args),
             ("new", Some(new_args)) => handle_new(new_args),
             ("get", Some(list_args)) => handle_get(list_args),
-            _ => {}
+            ("passwd", Some(_)) => handle_passwd(),
+            ("rekey", Some(rekey_args)) => handle_rekey(rekey_args),
+            ("edit", Some(edit_args)) => handle_edit(edit_args),
+            ("mv", Some(mv_args)) => handle_mv(mv_args),
+            ("rm", Some(rm_args)) => handle_rm(rm_args),
+            ("history", Some(history_args)) => handle_history(history_args),
+            ("find", Some(find_args)) => handle_find(find_args),
+            ("export", Some(export_args)) => handle_export(export_args),
+            ("import", Some(import_args)) => handle_import(import_args),
+            ("backup", Some(backup_args)) => handle_backup(backup_args),
+            ("restore", Some(restore_args)) => handle_restore(restore_args),
+            ("merge", Some(merge_args)) => handle_merge(merge_args),
+            ("otp", Some(otp_args)) => handle_otp(otp_args),
+            ("agent", Some(agent_args)) => handle_agent(agent_args),
+            ("unlock", Some(_)) => handle_unlock(),
+            ("lock", Some(_)) => handle_lock(),
+            ("shell", Some(shell_args)) => handle_shell(shell_args),
+            ("ui", Some(_)) => handle_ui(),
+            _ => Ok(()),
+        };
+
+        if let Err(error) = result {
+            eprintln!("{}.", error);
+            std::process::exit(exit_code(&error));
         }
     }
 }
 
-fn handle_generate(args: &clap::ArgMatches) {
-    // parse the password size
-    let password_size: Option<usize> = if let Some(size) = args.value_of("size") {
-        Some(size.parse().unwrap_or_else(|_| {
-            eprintln!("Invalid size: {}", size);
+/// Map an error to the process exit code
+fn exit_code(error: &PwdError) -> i32 {
+    match error {
+        PwdError::AuthenticationFailed => EXIT_AUTHENTICATION_FAILED,
+        PwdError::InvalidVaultFile
+        | PwdError::UnsupportedVaultVersion(_)
+        | PwdError::InvalidBackupFile => EXIT_INVALID_VAULT,
+        PwdError::InvalidKdfParams => EXIT_INVALID_KDF_PARAMS,
+        PwdError::InvalidPassword
+        | PwdError::InvalidPasswordPolicy(_)
+        | PwdError::InvalidOtpSecret => EXIT_INVALID_PASSWORD,
+        PwdError::VaultLocked => EXIT_VAULT_LOCKED,
+        PwdError::EntryNotFound
+        | PwdError::GroupNotFound
+        | PwdError::HistoryNotFound
+        | PwdError::OtpNotFound => EXIT_NOT_FOUND,
+        PwdError::AmbiguousEntry => EXIT_AMBIGUOUS,
+        PwdError::InvalidImportFile(_) => EXIT_INVALID_IMPORT,
+        PwdError::AgentNotRunning | PwdError::AgentAlreadyRunning => EXIT_AGENT,
+        PwdError::IO(_) => EXIT_IO,
+    }
+}
+
+/// The names of the `random_policy_args`
+const RANDOM_POLICY_ARGS: [&str; 12] = [
+    "no-lowercase",
+    "no-uppercase",
+    "no-digits",
+    "no-symbols",
+    "alphabet",
+    "include",
+    "exclude",
+    "no-lookalikes",
+    "min-lowercase",
+    "min-uppercase",
+    "min-digits",
+    "min-symbols",
+];
+
+/// The character options of the random passwords
+fn random_policy_args<'a, 'b>() -> [Arg<'a, 'b>; 12] {
+    [
+        Arg::with_name("no-lowercase")
+            .long("no-lowercase")
+            .help("Don't use lowercase letters")
+            .display_order(4),
+        Arg::with_name("no-uppercase")
+            .long("no-uppercase")
+            .help("Don't use uppercase letters")
+            .display_order(5),
+        Arg::with_name("no-digits")
+            .long("no-digits")
+            .help("Don't use digits")
+            .display_order(6),
+        Arg::with_name("no-symbols")
+            .long("no-symbols")
+            .help("Don't use symbols")
+            .display_order(7),
+        Arg::with_name("alphabet")
+            .long("alphabet")
+            .value_name("chars")
+            .help("The characters to use, instead of the letters, digits and symbols")
+            .takes_value(true)
+            .conflicts_with_all(&["no-lowercase", "no-uppercase", "no-digits", "no-symbols"])
+            .display_order(8),
+        Arg::with_name("include")
+            .long("include")
+            .value_name("chars")
+            .help("Characters to use on top of the others")
+            .takes_value(true)
+            .display_order(9),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("chars")
+            .help("Characters to never use")
+            .takes_value(true)
+            .display_order(10),
+        Arg::with_name("no-lookalikes")
+            .long("no-lookalikes")
+            .help("Don't use the characters easily mistaken for one another (0O1lI|)")
+            .display_order(11),
+        Arg::with_name("min-lowercase")
+            .long("min-lowercase")
+            .value_name("count")
+            .help("The minimum count of lowercase letters")
+            .takes_value(true)
+            .display_order(12),
+        Arg::with_name("min-uppercase")
+            .long("min-uppercase")
+            .value_name("count")
+            .help("The minimum count of uppercase letters")
+            .takes_value(true)
+            .display_order(13),
+        Arg::with_name("min-digits")
+            .long("min-digits")
+            .value_name("count")
+            .help("The minimum count of digits")
+            .takes_value(true)
+            .display_order(14),
+        Arg::with_name("min-symbols")
+            .long("min-symbols")
+            .value_name("count")
+            .help("The minimum count of symbols, any character but ASCII letters and digits")
+            .takes_value(true)
+            .display_order(15),
+    ]
+}
+
+/// The names of the `--kdf` and `kdf_cost_args` options
+const KDF_ARGS: [&str; 5] = [
+    "kdf",
+    "scrypt-logn",
+    "argon2-memory",
+    "argon2-iterations",
+    "calibrate",
+];
+
+/// The KDF cost options, shared by the commands deriving a new key
+fn kdf_cost_args<'a, 'b>() -> [Arg<'a, 'b>; 4] {
+    [
+        Arg::with_name("scrypt-logn")
+            .long("scrypt-logn")
+            .help("The scrypt cost, as log2(N)")
+            .takes_value(true)
+            .display_order(3),
+        Arg::with_name("argon2-memory")
+            .long("argon2-memory")
+            .help("The argon2id memory cost in KiB")
+            .takes_value(true)
+            .display_order(4),
+        Arg::with_name("argon2-iterations")
+            .long("argon2-iterations")
+            .help("The argon2id number of iterations")
+            .takes_value(true)
+            .display_order(5),
+        Arg::with_name("calibrate")
+            .long("calibrate")
+            .value_name("ms")
+            .help("Pick the highest KDF cost that unlocks the vault within the given time")
+            .long_help("Benchmark this machine and pick the highest KDF cost that unlocks the vault within the given time in milliseconds. The default is 500 ms")
+            .takes_value(true)
+            .min_values(0)
+            .conflicts_with_all(&["scrypt-logn", "argon2-memory", "argon2-iterations"])
+            .display_order(6),
+    ]
+}
+
+/// Args to set the entry fields, shared by `new` and `edit`
+fn entry_field_args<'a, 'b>() -> [Arg<'a, 'b>; 6] {
+    [
+        Arg::with_name("url")
+            .long("url")
+            .help("The login URL (an empty value clears it)")
+            .takes_value(true)
+            .display_order(10),
+        Arg::with_name("notes")
+            .long("notes")
+            .help("Free-form notes (an empty value clears them)")
+            .takes_value(true)
+            .display_order(11),
+        Arg::with_name("tag")
+            .long("tag")
+            .short("t")
+            .help("A tag for the entry, can be repeated (replaces the current tags)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .display_order(12),
+        Arg::with_name("field")
+            .long("field")
+            .value_name("name=value")
+            .help("A custom field, can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|field| match field.find('=') {
+                Some(0) | None => Err(String::from("expected <name>=<value>")),
+                Some(_) => Ok(()),
+            })
+            .display_order(13),
+        Arg::with_name("secret-field")
+            .long("secret-field")
+            .value_name("name")
+            .help("A secret custom field (PIN, security answer, ...), its value is prompted")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .display_order(14),
+        Arg::with_name("otp")
+            .long("otp")
+            .help("Prompt a TOTP/HOTP secret, as an otpauth:// URI or a base32 secret")
+            .long_help("Prompt a TOTP/HOTP secret, as an otpauth:// URI or a base32 secret (an empty value clears it). Bare secrets use 6 digits, SHA1 and 30 seconds periods")
+            .display_order(15),
+    ]
+}
+
+/// Parse an optional numeric argument, exiting on invalid values
+fn parse_number<T: FromStr>(args: &clap::ArgMatches, name: &str) -> Option<T> {
+    args.value_of(name).map(|value| {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid {}: {}", name, value);
             std::process::exit(1);
-        }))
+        })
+    })
+}
+
+/// Parse the KDF options on top of the current params
+fn parse_kdf_params(args: &clap::ArgMatches, current: KdfParams) -> KdfParams {
+    let algorithm = match args.value_of("kdf") {
+        Some("argon2id") => KdfAlgorithm::Argon2id,
+        Some(_) => KdfAlgorithm::Scrypt,
+        None => current.algorithm(),
+    };
+
+    if args.is_present("calibrate") {
+        let target = parse_number(args, "calibrate").unwrap_or(DEFAULT_UNLOCK_TARGET);
+
+        println!("Calibrating the key derivation for {} ms...", target);
+        return KdfParams::calibrate(algorithm, Duration::from_millis(target));
+    }
+
+    // the current params are only kept if the algorithm doesn't change
+    let mut params = if algorithm == current.algorithm() {
+        current
     } else {
-        None
+        KdfParams::default_for(algorithm)
     };
 
+    match &mut params {
+        KdfParams::Scrypt(scrypt) => {
+            if args.is_present("argon2-memory") || args.is_present("argon2-iterations") {
+                eprintln!("The argon2 options require the argon2id KDF");
+                std::process::exit(1);
+            }
+
+            if let Some(logn) = parse_number(args, "scrypt-logn") {
+                scrypt.logn = logn;
+            }
+        }
+        KdfParams::Argon2id(argon2) => {
+            if args.is_present("scrypt-logn") {
+                eprintln!("The scrypt options require the scrypt KDF");
+                std::process::exit(1);
+            }
+
+            if let Some(m_cost) = parse_number(args, "argon2-memory") {
+                argon2.m_cost = m_cost;
+            }
+            if let Some(t_cost) = parse_number(args, "argon2-iterations") {
+                argon2.t_cost = t_cost;
+            }
+        }
+    }
+
+    params
+}
+
+fn handle_generate(args: &clap::ArgMatches) -> PwdResult<()> {
+    // parse the password size
+    let password_size: Option<usize> = args.value_of("size").map(|size| {
+        size.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid size: {}", size);
+            std::process::exit(1);
+        })
+    });
+
     // parse the generation method
     let generation_method = match args.value_of("method") {
-        Some("random") | None => GenerationMethod::Random(password_size.unwrap_or(25)),
+        Some("random") | None => {
+            GenerationMethod::Random(parse_random_policy(args, password_size.unwrap_or(25)))
+        }
         Some("diceware") => {
+            if RANDOM_POLICY_ARGS.iter().any(|name| args.is_present(name)) {
+                eprintln!("The character options require the random method");
+                std::process::exit(1);
+            }
+
             let worlist_path = args.value_of("wordlist").unwrap();
             GenerationMethod::Diceware(worlist_path.to_string(), password_size.unwrap_or(5))
         }
@@ -147,145 +812,1197 @@ fn handle_generate(args: &clap::ArgMatches) {
 
     // generate the password
     let password_generator = Generator::from(generation_method);
-    let password = password_generator.password().unwrap();
+    let generated = password_generator.generate()?;
 
-    // print the generated password
-    print!("{}", *password);
+    // print the generated password, and its entropy apart
+    // so that the password can still be piped
+    print!("{}", *generated.password);
+    if args.is_present("show-entropy") {
+        io::stdout().flush()?;
+        eprintln!("\n{:.1} bits of entropy", generated.entropy);
+    }
+
+    Ok(())
+}
+
+/// Parse the character options of the random passwords
+fn parse_random_policy(args: &clap::ArgMatches, length: usize) -> RandomPolicy {
+    let mut policy = RandomPolicy::new(length);
+
+    let disabled = [
+        (CharClass::Lowercase, "no-lowercase"),
+        (CharClass::Uppercase, "no-uppercase"),
+        (CharClass::Digit, "no-digits"),
+        (CharClass::Symbol, "no-symbols"),
+    ];
+    policy.classes.retain(|class| {
+        !disabled
+            .iter()
+            .any(|(c, name)| c == class && args.is_present(name))
+    });
+
+    policy.alphabet = args.value_of("alphabet").map(String::from);
+    policy.include = args.value_of("include").unwrap_or("").to_string();
+    policy.exclude = args.value_of("exclude").unwrap_or("").to_string();
+    policy.exclude_lookalikes = args.is_present("no-lookalikes");
+
+    policy.min_lowercase = parse_number(args, "min-lowercase").unwrap_or(0);
+    policy.min_uppercase = parse_number(args, "min-uppercase").unwrap_or(0);
+    policy.min_digits = parse_number(args, "min-digits").unwrap_or(0);
+    policy.min_symbols = parse_number(args, "min-symbols").unwrap_or(0);
+
+    policy
 }
 
 fn prompt_master(msg: &'static str) -> io::Result<String> {
     rpassword::read_password_from_tty(Some(msg))
 }
 
-fn handle_new(args: &clap::ArgMatches) {
-    let vault_path = crate::vault_path();
+/// Prompt for a new master password and ask to confirm it
+fn prompt_new_master(msg: &'static str) -> io::Result<String> {
+    let master = prompt_master(msg)?;
+    let repeat = prompt_master("confirm the password: ")?;
 
-    let try_open = || OpenOptions::new().write(true).read(true).open(&vault_path);
-
-    let (mut vault, mut vault_file) = match try_open() {
-        Ok(mut file) => {
-            // vault exists
-            let master = prompt_master("master password: ").unwrap();
-
-            // return the vault from the file
-            // TODO: check if the file is a valid vault (i.e. error handling)
-            let vault = Vault::from_file(&mut file, &master).unwrap();
-
-            (vault, file)
-        }
-        Err(error) => {
-            match error.kind() {
-                io::ErrorKind::NotFound => {
-                    println!("Vault doesn't exists, creating a new one.");
-
-                    // create a new master password and confirm it
-                    let master = prompt_master("master_password: ").unwrap();
-                    let repeat = prompt_master("confirm the password: ").unwrap();
-
-                    // check if the passwords matches
-                    if master != repeat {
-                        eprintln!("Passwords doesn't match");
-                        std::process::exit(1);
-                    }
-
-                    // create the vault file
-                    let file = OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .create(true)
-                        .open(&vault_path)
-                        .unwrap_or_else(|error| {
-                            eprintln!("Couldn't create a new file: {}.", error);
-                            std::process::exit(1);
-                        });
-
-                    // create the new vault
-                    let vault = Vault::new(&master);
-
-                    (vault, file)
-                }
-                error => panic!("Could not open the vault: {:?}.", error),
-            }
-        }
-    };
+    // check if the passwords matches
+    if master != repeat {
+        eprintln!("Passwords doesn't match");
+        std::process::exit(1);
+    }
 
-    // get the password entry info
-    let service = args.value_of("service").unwrap();
-    let username = args.value_of("username").unwrap();
+    Ok(master)
+}
 
+/// Read an entry password, from the terminal or from a pipe
+fn read_entry_password() -> io::Result<String> {
     // SAFETY: this is safe only if running on unix
     // TODO: pipe feature only if target_family=unix
     let isatty = unsafe { crate::ffi::isatty(0) } == 1;
 
     // get the password from stdin (checking if running from pipe)
-    let password = if isatty {
+    if isatty {
         // the process is running with no pipes
-        rpassword::prompt_password_stdout("password: ").unwrap()
+        rpassword::prompt_password_stdout("password: ")
     } else {
         // the process is running from a pipe
         // don't display any message
-        rpassword::read_password().unwrap()
+        rpassword::read_password()
+    }
+}
+
+/// Build the entry changes from the `entry_field_args`,
+/// prompting the values of the secret fields
+fn parse_entry_fields(args: &clap::ArgMatches) -> PwdResult<EntryUpdate> {
+    let mut fields = Vec::new();
+    for field in args.values_of("field").into_iter().flatten() {
+        // checked by the arg validator
+        let (name, value) = field.split_at(field.find('=').unwrap());
+        fields.push(CustomField::new(name, &value[1..], false));
+    }
+    for name in args.values_of("secret-field").into_iter().flatten() {
+        let value = rpassword::read_password_from_tty(Some(&format!("{}: ", name)))?;
+        fields.push(CustomField::new(name, &value, true));
+    }
+
+    let otp = if args.is_present("otp") {
+        let secret = rpassword::read_password_from_tty(Some("otp secret: "))?;
+        // stored as an URI, an empty secret clears it
+        match secret.trim() {
+            "" => Some(SecString::from("")),
+            secret => Some(Otp::parse(secret)?.to_uri()),
+        }
+    } else {
+        None
+    };
+
+    let tags = args.values_of("tag").map(|tags| {
+        tags.filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect()
+    });
+
+    Ok(EntryUpdate {
+        url: args.value_of("url").map(String::from),
+        notes: args.value_of("notes").map(Into::into),
+        tags,
+        fields,
+        remove_fields: args
+            .values_of("remove-field")
+            .into_iter()
+            .flatten()
+            .map(String::from)
+            .collect(),
+        otp,
+        ..EntryUpdate::default()
+    })
+}
+
+fn handle_new(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = crate::vault_path();
+    let vault_exists = Path::new(&vault_path).exists();
+
+    if vault_exists && KDF_ARGS.iter().any(|name| args.is_present(name)) {
+        eprintln!("Use `pwdeck rekey` to change the KDF of an existing vault");
+        std::process::exit(1);
+    }
+
+    create_vault_dir(&vault_path);
+
+    // hold the lock until the vault is synced
+    let _lock = lock_vault(&vault_path)?;
+
+    let mut vault = if vault_exists {
+        // vault exists
+        let vault = open_vault(&vault_path)?;
+
+        if vault.needs_upgrade() {
+            println!("Upgrading the vault file to the current format.");
+        }
+
+        vault
+    } else {
+        println!("Vault doesn't exists, creating a new one.");
+
+        // create a new master password and confirm it
+        let master = prompt_new_master("master_password: ")?;
+
+        // create the new vault
+        let default_kdf = KdfParams::default_for(KdfAlgorithm::default());
+        let kdf = parse_kdf_params(args, default_kdf);
+        Vault::with_params(&master, kdf)?
     };
 
+    // get the password entry info
+    let service = args.value_of("service").unwrap();
+    let username = args.value_of("username").unwrap();
+
+    let password = read_entry_password()?;
+
     // create the entry
-    let entry = Entry::new(username, &password);
+    let mut entry = Entry::new(username, &password);
+    entry.update(parse_entry_fields(args)?)?;
 
     // add the new entry to the vault
-    vault.insert_entry(service, entry).unwrap();
+    vault.insert_entry(service, entry)?;
     // sync the file
-    vault.sync(&mut vault_file).unwrap();
+    vault.sync(&vault_path)
 }
 
-fn handle_get(args: &clap::ArgMatches) {
+fn handle_get(args: &clap::ArgMatches) -> PwdResult<()> {
+    let format = output_format(args);
+    let vault_path = existing_vault_path();
+    let vault = open_vault(&vault_path)?;
+
+    if let Some(id) = args.value_of("id") {
+        // search for the entry with the given ID, or for the single
+        // entry matching it as a query
+        let (group, entry) = match vault.resolve_id(id) {
+            Ok(id) => vault.entry(&id).unwrap(),
+            Err(PwdError::EntryNotFound) => confident_match(&vault, id)?,
+            Err(error) => return Err(error),
+        };
+
+        let mut output = EntryOutput::new(group, entry).with_password();
+        if args.is_present("reveal") {
+            output = output.with_secrets();
+        }
+
+        if format == OutputFormat::Json {
+            println!("{}", EntriesOutput::new(vec![output]).to_json());
+        } else if args.is_present("details") {
+            print_entry_details(&output, args.is_present("reveal"));
+        } else {
+            // entry found, print its password
+            let password: &String = entry.password(); // deref cohersion
+            print!("{}", password);
+        }
+
+        Ok(())
+    } else {
+        let mut query = Query::new();
+        if let Some(service) = args.value_of("service") {
+            query = query.group(parse_filter(args, service));
+        }
+        if let Some(username) = args.value_of("username") {
+            query = query.name(parse_filter(args, username));
+        }
+
+        let mut entries: Vec<(&str, &Entry)> = vault.search(&query).collect();
+        // keep the output stable, the vault groups are unordered
+        entries.sort_by_key(|(group, entry)| (*group, entry.name()));
+
+        let entries: Vec<EntryOutput> = entries
+            .into_iter()
+            .map(|(group, entry)| {
+                let output = EntryOutput::new(group, entry);
+                if args.is_present("reveal") {
+                    output.with_secrets()
+                } else {
+                    output
+                }
+            })
+            .collect();
+        let output = EntriesOutput::new(entries);
+
+        match format {
+            OutputFormat::Json => println!("{}", output.to_json()),
+            OutputFormat::Plain => print_plain_entries(&output),
+            OutputFormat::Tree => print_tree(&output, vault.short_id_len()),
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the output format of the read commands
+fn output_format(args: &clap::ArgMatches) -> OutputFormat {
+    match args.value_of("format") {
+        Some("json") => OutputFormat::Json,
+        Some("plain") => OutputFormat::Plain,
+        Some("csv") => {
+            eprintln!("The csv format is only supported by export.");
+            std::process::exit(1);
+        }
+        _ => OutputFormat::Tree,
+    }
+}
+
+/// Print the entries by group, with their IDs shortened to `id_len`
+fn print_tree(output: &EntriesOutput, id_len: usize) {
+    let entries = &output.entries;
+    if entries.is_empty() {
+        println!("No entries found.");
+        return;
+    }
+
+    // the entries of each group are contiguous
+    for (i, entry) in entries.iter().enumerate() {
+        if i == 0 || entries[i - 1].group != entry.group {
+            println!("{}:", entry.group);
+        }
+        let last_of_group = entries
+            .get(i + 1)
+            .is_none_or(|next| next.group != entry.group);
+        let tree_char = if last_of_group { '└' } else { '├' };
+        let id = short_id(entry.id, id_len);
+        println!("  {}── {} {}", tree_char, id, entry.name);
+    }
+
+    println!();
+}
+
+/// Print the entries as tab separated values: ID, group, name,
+/// and the password when it is included
+fn print_plain_entries(output: &EntriesOutput) {
+    for entry in &output.entries {
+        print!("{}\t{}\t{}", entry.id, entry.group, entry.name);
+        if let Some(password) = entry.password {
+            print!("\t{}", password);
+        }
+        println!();
+    }
+}
+
+/// Find the single entry confidently matching the query,
+/// printing the candidates if there are several
+fn confident_match<'a>(vault: &'a Vault, query: &str) -> PwdResult<(&'a str, &'a Entry)> {
+    let matches: Vec<FuzzyMatch> = vault
+        .schema()
+        .fuzzy_search(query)
+        .into_iter()
+        .filter(FuzzyMatch::is_confident)
+        .collect();
+
+    match matches.as_slice() {
+        [] => Err(PwdError::EntryNotFound),
+        [found] => {
+            // tell which entry was picked, without polluting the output
+            eprintln!("{}: {}", found.group, found.entry.name());
+            Ok((found.group, found.entry))
+        }
+        _ => {
+            eprintln!("Matching entries:");
+            for found in &matches {
+                eprintln!("{}", match_line(found, vault.short_id_len()));
+            }
+            Err(PwdError::AmbiguousEntry)
+        }
+    }
+}
+
+/// Format a fuzzy match with its ID shortened to `id_len`
+pub(crate) fn match_line(found: &FuzzyMatch, id_len: usize) -> String {
+    let id = short_id(found.entry.id(), id_len);
+    format!("  {}  {}: {}", id, found.group, found.entry.name())
+}
+
+/// Build a `get` filter pattern
+fn parse_filter(args: &clap::ArgMatches, filter: &str) -> Pattern {
+    let kind = if args.is_present("exact") {
+        MatchKind::Exact
+    } else if filter.contains(&['*', '?'][..]) {
+        MatchKind::Glob
+    } else {
+        MatchKind::Substring
+    };
+
+    let pattern = Pattern::new(filter, kind);
+    if args.is_present("case-sensitive") {
+        pattern
+    } else {
+        pattern.case_insensitive()
+    }
+}
+
+/// Print all the fields of an entry, hiding the secrets unless `reveal` is set
+fn print_entry_details(entry: &EntryOutput, reveal: bool) {
+    const HIDDEN: &str = "********";
+
+    let password = entry.password.filter(|_| reveal).unwrap_or(HIDDEN);
+    println!("id:       {}", entry.id);
+    println!("group:    {}", entry.group);
+    println!("username: {}", entry.name);
+    println!("password: {}", password);
+    if let Some(url) = entry.url {
+        println!("url:      {}", url);
+    }
+    if !entry.tags.is_empty() {
+        println!("tags:     {}", entry.tags.join(", "));
+    }
+    if let Some(notes) = entry.notes {
+        println!("notes:    {}", notes);
+    }
+    for field in &entry.fields {
+        println!("{}: {}", field.name, field.value.unwrap_or(HIDDEN));
+    }
+    if entry.has_otp {
+        println!("otp:      {}", entry.otp.unwrap_or(HIDDEN));
+    }
+    println!("created:  {}", format_timestamp(entry.created_at));
+    println!("updated:  {}", format_timestamp(entry.updated_at));
+}
+
+/// Format a unix timestamp as an UTC date
+fn format_timestamp(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return String::from("unknown");
+    }
+
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+
+    // convert the days since the epoch to a civil date
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Create the vault directory, so the vault can be locked
+fn create_vault_dir(vault_path: &str) {
+    if let Some(vault_dir) = Path::new(vault_path).parent() {
+        fs::create_dir_all(vault_dir).unwrap_or_else(|error| {
+            eprintln!("Couldn't create the vault directory: {}.", error);
+            std::process::exit(1);
+        });
+    }
+}
+
+/// Return the path of the vault, exiting if it doesn't exist
+fn existing_vault_path() -> String {
     let vault_path = crate::vault_path();
 
-    let mut vault_file = File::open(&vault_path).unwrap_or_else(|error| match error.kind() {
-        io::ErrorKind::NotFound => {
-            eprintln!("Vault not found: '{}'.", vault_path);
+    if !Path::new(&vault_path).exists() {
+        eprintln!("Vault not found: '{}'.", vault_path);
+        std::process::exit(1);
+    }
+
+    vault_path
+}
+
+/// Prompt the master password and open the vault with it
+fn unlock_vault(vault_path: &str) -> PwdResult<(Vault, String)> {
+    let master = prompt_master("master password: ")?;
+    let vault = Vault::from_file(vault_path, &master)?;
+
+    Ok((vault, master))
+}
+
+/// Open the vault with the key cached by the agent,
+/// or prompt the master password if it doesn't hold it
+fn open_vault(vault_path: &str) -> PwdResult<Vault> {
+    if let Ok(Some(key)) = agent::cached_key(crate::agent_socket_path(), vault_path) {
+        match Vault::from_file_with_key(vault_path, key) {
+            // the vault was re-keyed since it was unlocked
+            Err(PwdError::AuthenticationFailed) => {}
+            result => return result,
+        }
+    }
+
+    unlock_vault(vault_path).map(|(vault, _)| vault)
+}
+
+/// Replace the key cached by the agent after the vault was re-keyed
+fn update_agent_key(vault_path: &str, vault: &Vault) -> PwdResult<()> {
+    let socket_path = crate::agent_socket_path();
+
+    match agent::cached_key(&socket_path, vault_path) {
+        Ok(Some(_)) => agent::unlock(&socket_path, vault_path, vault.key()),
+        // the agent doesn't hold the key or isn't running
+        _ => Ok(()),
+    }
+}
+
+/// Lock the vault for a command that modifies it,
+/// waiting a bit if another process holds the lock
+fn lock_vault(vault_path: &str) -> PwdResult<VaultLock> {
+    match VaultLock::try_acquire(vault_path) {
+        Err(PwdError::VaultLocked) => {
+            eprintln!("Waiting for another pwdeck process to release the vault...");
+            VaultLock::acquire(vault_path, LOCK_TIMEOUT)
+        }
+        result => result,
+    }
+}
+
+fn handle_passwd() -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let _lock = lock_vault(&vault_path)?;
+    let (mut vault, master) = unlock_vault(&vault_path)?;
+
+    // create the new master password and confirm it
+    let new_master = prompt_new_master("new master password: ")?;
+
+    // re-key and re-encrypt the vault
+    vault.change_master_password(&master, &new_master)?;
+    vault.sync(&vault_path)?;
+    update_agent_key(&vault_path, &vault)?;
+
+    println!("Master password changed.");
+
+    Ok(())
+}
+
+fn handle_rekey(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let _lock = lock_vault(&vault_path)?;
+    // re-keying needs the master password
+    let (mut vault, _) = unlock_vault(&vault_path)?;
+
+    // re-key and re-encrypt the vault
+    let kdf = parse_kdf_params(args, vault.kdf_params());
+    vault.set_kdf_params(kdf)?;
+    vault.sync(&vault_path)?;
+    update_agent_key(&vault_path, &vault)?;
+
+    println!("Vault re-keyed with {}.", kdf);
+
+    Ok(())
+}
+
+fn handle_edit(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let _lock = lock_vault(&vault_path)?;
+    let mut vault = open_vault(&vault_path)?;
+
+    // fail before asking the new password
+    let id = vault.resolve_id(args.value_of("id").unwrap())?;
+
+    let password = if args.is_present("password") {
+        Some(read_entry_password()?.into())
+    } else {
+        None
+    };
+
+    let update = EntryUpdate {
+        name: args.value_of("username").map(String::from),
+        password,
+        ..parse_entry_fields(args)?
+    };
+
+    vault.update_entry(&id, update)?;
+    vault.sync(&vault_path)
+}
+
+fn handle_mv(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let _lock = lock_vault(&vault_path)?;
+    let mut vault = open_vault(&vault_path)?;
+
+    let source = args.value_of("source").unwrap();
+    let destination = args.value_of("destination").unwrap();
+
+    if args.is_present("group") {
+        vault.rename_group(source, destination)?;
+    } else {
+        let id = vault.resolve_id(source)?;
+        vault.move_entry(&id, destination)?;
+    }
+
+    vault.sync(&vault_path)
+}
+
+fn handle_rm(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let _lock = lock_vault(&vault_path)?;
+    let mut vault = open_vault(&vault_path)?;
+
+    let id = vault.resolve_id(args.value_of("id").unwrap())?;
+    let entry = vault.remove_entry(&id)?;
+    vault.sync(&vault_path)?;
+
+    println!("Removed '{}'.", entry.name());
+
+    Ok(())
+}
+
+fn handle_find(args: &clap::ArgMatches) -> PwdResult<()> {
+    let format = output_format(args);
+    let vault_path = existing_vault_path();
+    let vault = open_vault(&vault_path)?;
+
+    let query = args.value_of("query").unwrap();
+    let limit = parse_number(args, "limit").unwrap_or(DEFAULT_FIND_LIMIT);
+
+    let mut matches = vault.schema().fuzzy_search(query);
+    matches.truncate(limit);
+
+    match format {
+        OutputFormat::Json => {
+            let entries = matches
+                .iter()
+                .map(|found| EntryOutput::new(found.group, found.entry).with_score(found.score))
+                .collect();
+            println!("{}", EntriesOutput::new(entries).to_json());
+        }
+        OutputFormat::Plain => {
+            let entries = matches
+                .iter()
+                .map(|found| EntryOutput::new(found.group, found.entry))
+                .collect();
+            print_plain_entries(&EntriesOutput::new(entries));
+        }
+        OutputFormat::Tree if matches.is_empty() => println!("No entries found."),
+        OutputFormat::Tree => {
+            for found in &matches {
+                println!("{}", match_line(found, vault.short_id_len()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_export(args: &clap::ArgMatches) -> PwdResult<()> {
+    let format = match args.value_of("format") {
+        Some("json") | None => ExportFormat::Json,
+        Some("csv") => ExportFormat::Csv,
+        Some(format) => {
+            eprintln!("The {} format is not supported by export.", format);
             std::process::exit(1);
         }
-        _ => panic!("Could not read the vault."),
+    };
+
+    if !args.is_present("unsafe-plaintext") {
+        eprintln!("The export is not encrypted, anyone reading it gets all the passwords.");
+        eprintln!("Pass --unsafe-plaintext to export anyway.");
+        std::process::exit(1);
+    }
+
+    let vault_path = existing_vault_path();
+    let (vault, master) = unlock_vault(&vault_path)?;
+
+    // re-typing the master password confirms the export is intended
+    let repeat = prompt_master("retype the master password to export: ")?;
+    if repeat != master {
+        return Err(PwdError::AuthenticationFailed);
+    }
+
+    let output = args.value_of("output").unwrap();
+    let exported = export::export(vault.schema(), format);
+    export::write_export(output, exported.as_bytes())?;
+
+    println!("Vault exported to '{}'.", output);
+
+    Ok(())
+}
+
+fn handle_import(args: &clap::ArgMatches) -> PwdResult<()> {
+    let format = match args.value_of("from").unwrap() {
+        "bitwarden" => ImportFormat::Bitwarden,
+        "keepass" => ImportFormat::KeePass,
+        "1password" => ImportFormat::OnePassword,
+        "chrome" => ImportFormat::Chrome,
+        _ => ImportFormat::Firefox,
+    };
+    let dry_run = args.is_present("dry-run");
+
+    // fail on a malformed file before asking the master password
+    let data = fs::read(args.value_of("file").unwrap())?;
+    let items = import::parse(format, &data)?;
+
+    let vault_path = existing_vault_path();
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(lock_vault(&vault_path)?)
+    };
+    let mut vault = open_vault(&vault_path)?;
+
+    let plan = import::plan(vault.schema(), items);
+
+    for label in &plan.duplicates {
+        println!("Already in the vault: {}", label);
+    }
+    for label in &plan.skipped {
+        println!("Skipped, no password: {}", label);
+    }
+
+    let count = plan.entries.len();
+    if dry_run {
+        for (group, entry) in &plan.entries {
+            println!("Would import: {}: {}", group, entry.name());
+        }
+        println!("{} entries would be imported.", count);
+        return Ok(());
+    }
+
+    for (group, entry) in plan.entries {
+        vault.insert_entry(&group, entry)?;
+    }
+    vault.sync(&vault_path)?;
+
+    println!("{} entries imported.", count);
+
+    Ok(())
+}
+
+fn handle_backup(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let vault = open_vault(&vault_path)?;
+
+    // the backup has its own passphrase and KDF params
+    let passphrase = prompt_new_master("backup passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(PwdError::InvalidPassword);
+    }
+    let kdf = parse_kdf_params(args, vault.kdf_params());
+    let bundle = Bundle::create(vault.schema(), &passphrase, kdf)?;
+
+    let output = args.value_of("output").unwrap();
+    export::write_export(output, &bundle.to_bytes()?)?;
+
+    println!(
+        "Backed up {} entries to '{}'.",
+        bundle.manifest.entries, output
+    );
+
+    Ok(())
+}
+
+fn handle_restore(args: &clap::ArgMatches) -> PwdResult<()> {
+    let bundle = Bundle::from_file(args.value_of("file").unwrap())?;
+    // the checksum doesn't need the passphrase
+    bundle.verify()?;
+
+    let manifest = &bundle.manifest;
+    if args.is_present("verify") {
+        println!(
+            "Backup OK: {} entries in {} groups, created {}.",
+            manifest.entries,
+            manifest.groups,
+            format_timestamp(manifest.created_at)
+        );
+        return Ok(());
+    }
+
+    let passphrase = prompt_master("backup passphrase: ")?;
+    let schema = bundle.open(&passphrase)?;
+
+    let vault_path = crate::vault_path();
+    create_vault_dir(&vault_path);
+    let _lock = lock_vault(&vault_path)?;
+
+    if !Path::new(&vault_path).exists() {
+        println!("Vault doesn't exists, creating a new one.");
+
+        let master = prompt_new_master("master_password: ")?;
+        let default_kdf = KdfParams::default_for(KdfAlgorithm::default());
+        let mut vault = Vault::with_params(&master, default_kdf)?;
+        vault.replace_schema(schema);
+        vault.sync(&vault_path)?;
+
+        println!("{} entries restored.", manifest.entries);
+
+        return Ok(());
+    }
+
+    let mut vault = open_vault(&vault_path)?;
+
+    if args.is_present("replace") {
+        // the previous entries are still in the vault backup file
+        vault.replace_schema(schema);
+        vault.sync(&vault_path)?;
+
+        println!("Vault replaced by the {} backup entries.", manifest.entries);
+    } else {
+        let added = vault.insert_missing(schema);
+        vault.sync(&vault_path)?;
+
+        println!(
+            "{} entries restored, {} already in the vault.",
+            added,
+            manifest.entries - added
+        );
+    }
+
+    Ok(())
+}
+
+fn handle_merge(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let other_path = args.value_of("other").unwrap();
+
+    let _lock = lock_vault(&vault_path)?;
+    let (mut vault, master) = unlock_vault(&vault_path)?;
+
+    // the copies usually share the master password
+    let other = match Vault::from_file(other_path, &master) {
+        Err(PwdError::AuthenticationFailed) => {
+            let other_master = prompt_master("master password of the other vault: ")?;
+            Vault::from_file(other_path, &other_master)?
+        }
+        result => result?,
+    };
+
+    let strategy = args.value_of("strategy").unwrap_or("ask");
+    let report = vault.merge(other.schema(), |conflict| match strategy {
+        "ours" => Resolution::Ours,
+        "theirs" => Resolution::Theirs,
+        "newer" if conflict.theirs.updated_at() > conflict.ours.updated_at() => Resolution::Theirs,
+        "newer" => Resolution::Ours,
+        _ => ask_resolution(conflict),
     });
 
-    let master = prompt_master("master password: ").unwrap();
-    let vault = Vault::from_file(&mut vault_file, &master).unwrap();
+    for label in &report.added {
+        println!("Added: {}", label);
+    }
+    for label in &report.readded {
+        println!("Added back, changed since its removal: {}", label);
+    }
+    for label in &report.kept_removed {
+        println!("Kept removed: {}", label);
+    }
+    for label in &report.removed {
+        println!("Removed: {}", label);
+    }
+    for label in &report.updated {
+        println!("Updated: {}", label);
+    }
+    for (label, resolution) in &report.conflicts {
+        let kept = match resolution {
+            Resolution::Ours => "ours",
+            Resolution::Theirs => "theirs",
+        };
+        println!("Conflict, kept {}: {}", kept, label);
+    }
 
-    if let Some(id) = args.value_of("id") {
-        let groups = &vault.schema().passwords;
+    vault.sync(&vault_path)?;
+
+    println!(
+        "{} added, {} updated, {} removed, {} conflicts.",
+        report.added.len() + report.readded.len(),
+        report.updated.len(),
+        report.removed.len(),
+        report.conflicts.len()
+    );
+
+    Ok(())
+}
+
+/// Ask which version of an entry changed in both vaults to keep
+fn ask_resolution(conflict: &MergeConflict) -> Resolution {
+    println!("Conflict on {}: {}", conflict.group, conflict.ours.name());
+    for (side, group, entry) in [
+        ("ours", conflict.group, conflict.ours),
+        ("theirs", conflict.their_group, conflict.theirs),
+    ] {
+        println!(
+            "  {:<7} {}: {}, updated {}",
+            side,
+            group,
+            entry.name(),
+            format_timestamp(entry.updated_at())
+        );
+    }
 
-        // search for the entry with the given ID
-        for (_, entries) in groups {
-            if let Some(entry) = entries.iter().find(|a| a.id() == id){
-                // entry found, print its password
-                let password: &String = &entry.password(); // deref cohersion
-                print!("{}", password);
+    let mut differences = conflict.ours.differences(conflict.theirs);
+    if conflict.ours.password() != conflict.theirs.password() {
+        differences.insert(0, "password");
+    }
+    if !differences.is_empty() {
+        println!("  differs: {}", differences.join(", "));
+    }
 
-                // exit
-                return;
+    loop {
+        print!("Keep [o]urs or [t]heirs? ");
+        io::stdout().flush().ok();
+
+        let mut answer = String::new();
+        match io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => {
+                eprintln!("No answer, the merge is cancelled.");
+                std::process::exit(1);
             }
+            Ok(_) => {}
+        }
+
+        match answer.trim() {
+            "o" | "ours" => return Resolution::Ours,
+            "t" | "theirs" => return Resolution::Theirs,
+            _ => {}
         }
+    }
+}
 
-        // exit with error
-        eprintln!("Could not find the given ID");
+fn handle_otp(args: &clap::ArgMatches) -> PwdResult<()> {
+    let format = output_format(args);
+    let vault_path = existing_vault_path();
+    // HOTP counters are saved after each code
+    let _lock = lock_vault(&vault_path)?;
+    let mut vault = open_vault(&vault_path)?;
+
+    let query = args.value_of("id").unwrap();
+    let id = match vault.resolve_id(query) {
+        Err(PwdError::EntryNotFound) => String::from(confident_match(&vault, query)?.1.id()),
+        result => result?,
+    };
+
+    let otp = vault.generate_otp(&id, crate::password::now())?;
+    if otp.counter.is_some() {
+        vault.sync(&vault_path)?;
+    }
+
+    match format {
+        OutputFormat::Json => println!("{}", OtpOutput::new(&otp).to_json()),
+        OutputFormat::Plain => match otp.remaining {
+            Some(remaining) => println!("{}\t{}", otp.code, remaining),
+            None => println!("{}", otp.code),
+        },
+        OutputFormat::Tree => match (otp.remaining, otp.counter) {
+            (Some(remaining), _) => println!("{} ({}s remaining)", otp.code, remaining),
+            (None, Some(counter)) => println!("{} (counter {})", otp.code, counter),
+            (None, None) => println!("{}", otp.code),
+        },
+    }
+
+    Ok(())
+}
+
+fn handle_agent(args: &clap::ArgMatches) -> PwdResult<()> {
+    let socket_path = crate::agent_socket_path();
+
+    if args.is_present("stop") {
+        agent::stop(&socket_path)?;
+        println!("Agent stopped.");
+        return Ok(());
+    }
+
+    let timeout = parse_number(args, "timeout")
+        .map(Duration::from_secs)
+        .unwrap_or(agent::DEFAULT_IDLE_TIMEOUT);
+    if timeout.is_zero() {
+        // the keys would be forgotten as soon as they are cached
+        eprintln!("The timeout must be at least 1 second.");
         std::process::exit(1);
+    }
 
-    } else {
-        let _filter_service = args.value_of("service");
-        let _filter_username = args.value_of("username");
+    if args.is_present("foreground") {
+        let agent = Agent::bind(&socket_path, timeout)?;
+        eprintln!("Agent listening on '{}'.", socket_path);
+        return agent.run();
+    }
 
-        let entries = &vault.schema().passwords;
+    if agent::is_running(&socket_path) {
+        return Err(PwdError::AgentAlreadyRunning);
+    }
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(["agent", "--foreground", "--timeout"])
+        .arg(timeout.as_secs().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    // run the agent in its own process group, so it isn't
+    // stopped along with the terminal
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let mut child = command.spawn()?;
+
+    let start = Instant::now();
+    while !agent::is_running(&socket_path) {
+        if child.try_wait()?.is_some() || start.elapsed() > AGENT_START_TIMEOUT {
+            eprintln!("The agent failed to start.");
+            std::process::exit(1);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    println!(
+        "Agent started (pid {}), listening on '{}'.",
+        child.id(),
+        socket_path
+    );
+
+    Ok(())
+}
+
+fn handle_unlock() -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let socket_path = crate::agent_socket_path();
+
+    // fail before asking the master password
+    if !agent::is_running(&socket_path) {
+        return Err(PwdError::AgentNotRunning);
+    }
+
+    let (vault, _) = unlock_vault(&vault_path)?;
+    agent::unlock(&socket_path, &vault_path, vault.key())?;
+
+    println!("Vault unlocked.");
+
+    Ok(())
+}
+
+fn handle_lock() -> PwdResult<()> {
+    agent::lock(crate::agent_socket_path())?;
+
+    println!("Agent locked.");
+
+    Ok(())
+}
+
+fn handle_shell(args: &clap::ArgMatches) -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    // the vault can't change under the session
+    let _lock = lock_vault(&vault_path)?;
+    let vault = open_vault(&vault_path)?;
+
+    println!("Vault unlocked, type `help` to list the commands.");
+    Shell::new(vault, &vault_path, args.is_present("autosave")).run()
+}
+
+fn handle_ui() -> PwdResult<()> {
+    let vault_path = existing_vault_path();
+    let _lock = lock_vault(&vault_path)?;
+    let vault = open_vault(&vault_path)?;
+
+    let mut terminal = ratatui::init();
+    let result = App::new(vault, &vault_path).run(&mut terminal);
+    ratatui::restore();
+
+    Ok(result?)
+}
+
+fn handle_history(args: &clap::ArgMatches) -> PwdResult<()> {
+    let format = output_format(args);
+    let vault_path = existing_vault_path();
+    let id = args.value_of("id").unwrap();
+
+    if let Some(number) = parse_number::<usize>(args, "restore") {
+        let _lock = lock_vault(&vault_path)?;
+        let mut vault = open_vault(&vault_path)?;
 
-        for (group, entries) in entries.iter() {
-            println!("{}:", group);
-            let last_entry = entries.len().saturating_sub(1);
-            for (i, entry) in entries.iter().enumerate() {
-                let tree_char = if i < last_entry { '├' } else { '└' };
-                println!("  {}── {}", tree_char, entry.name());
+        // the listing starts at 1
+        let index = number.checked_sub(1).ok_or(PwdError::HistoryNotFound)?;
+        let id = vault.resolve_id(id)?;
+        vault.restore_password(&id, index)?;
+        vault.sync(&vault_path)?;
+
+        println!("Password restored.");
+
+        return Ok(());
+    }
+
+    let vault = open_vault(&vault_path)?;
+    let (group, entry) = vault.entry(&vault.resolve_id(id)?).unwrap();
+    let output = HistoryOutput::new(group, entry, args.is_present("reveal"));
+
+    match format {
+        OutputFormat::Json => println!("{}", output.to_json()),
+        OutputFormat::Plain => {
+            // number, unix timestamp and password when revealed
+            for item in &output.history {
+                print!("{}\t{}", item.number, item.replaced_at);
+                if let Some(password) = item.password {
+                    print!("\t{}", password);
+                }
+                println!();
             }
         }
+        OutputFormat::Tree => print_history(&output),
+    }
+
+    Ok(())
+}
+
+fn print_history(output: &HistoryOutput) {
+    println!("created: {}", format_timestamp(output.entry.created_at));
+    println!("updated: {}", format_timestamp(output.entry.updated_at));
+
+    if output.history.is_empty() {
+        println!("No previous passwords.");
+    } else {
+        println!("previous passwords (replaced at):");
+        for item in &output.history {
+            let replaced_at = format_timestamp(item.replaced_at);
+            let password = item.password.unwrap_or("********");
+            println!("  {}. {}  {}", item.number, replaced_at, password);
+        }
+    }
 
-        println!("")
+    if !output.discarded.is_empty() {
+        println!("versions discarded by merges (updated at):");
+        for version in &output.discarded {
+            let updated_at = format_timestamp(version.updated_at);
+            print!("  {}  {}", updated_at, version.name);
+            if let Some(url) = version.url {
+                print!("  {}", url);
+            }
+            println!();
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io;
 
+    use super::*;
+
+    /// One error of every variant, along with its exit code
+    fn exit_code_table() -> Vec<(PwdError, i32)> {
+        vec![
+            (PwdError::AuthenticationFailed, EXIT_AUTHENTICATION_FAILED),
+            (PwdError::InvalidVaultFile, EXIT_INVALID_VAULT),
+            (PwdError::UnsupportedVaultVersion(9), EXIT_INVALID_VAULT),
+            (PwdError::InvalidKdfParams, EXIT_INVALID_KDF_PARAMS),
+            (PwdError::InvalidPassword, EXIT_INVALID_PASSWORD),
+            (
+                PwdError::InvalidPasswordPolicy(String::from("no characters left to use")),
+                EXIT_INVALID_PASSWORD,
+            ),
+            (PwdError::VaultLocked, EXIT_VAULT_LOCKED),
+            (PwdError::EntryNotFound, EXIT_NOT_FOUND),
+            (PwdError::GroupNotFound, EXIT_NOT_FOUND),
+            (PwdError::HistoryNotFound, EXIT_NOT_FOUND),
+            (PwdError::AmbiguousEntry, EXIT_AMBIGUOUS),
+            (
+                PwdError::InvalidImportFile(String::from("empty CSV file")),
+                EXIT_INVALID_IMPORT,
+            ),
+            (PwdError::InvalidBackupFile, EXIT_INVALID_VAULT),
+            (PwdError::InvalidOtpSecret, EXIT_INVALID_PASSWORD),
+            (PwdError::OtpNotFound, EXIT_NOT_FOUND),
+            (PwdError::AgentNotRunning, EXIT_AGENT),
+            (PwdError::AgentAlreadyRunning, EXIT_AGENT),
+            (
+                PwdError::IO(io::Error::from(io::ErrorKind::PermissionDenied)),
+                EXIT_IO,
+            ),
+        ]
+    }
+
+    #[test]
+    fn exit_codes() {
+        for (error, code) in exit_code_table() {
+            assert_eq!(exit_code(&error), code, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn distinct_exit_codes() {
+        let table = exit_code_table();
+
+        // a new variant breaks this match, so it gets added to the table
+        for (error, _) in &table {
+            match error {
+                PwdError::AuthenticationFailed
+                | PwdError::InvalidVaultFile
+                | PwdError::UnsupportedVaultVersion(_)
+                | PwdError::InvalidKdfParams
+                | PwdError::InvalidPassword
+                | PwdError::InvalidPasswordPolicy(_)
+                | PwdError::VaultLocked
+                | PwdError::EntryNotFound
+                | PwdError::GroupNotFound
+                | PwdError::HistoryNotFound
+                | PwdError::AmbiguousEntry
+                | PwdError::InvalidImportFile(_)
+                | PwdError::InvalidBackupFile
+                | PwdError::InvalidOtpSecret
+                | PwdError::OtpNotFound
+                | PwdError::AgentNotRunning
+                | PwdError::AgentAlreadyRunning
+                | PwdError::IO(_) => {}
+            }
+        }
+        assert_eq!(table.len(), 18);
+
+        let mut codes: Vec<i32> = table.iter().map(|(error, _)| exit_code(error)).collect();
+        // zero is success and one is used for usage errors
+        assert!(codes.iter().all(|&code| code > 1));
+
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(
+            codes,
+            [
+                EXIT_AUTHENTICATION_FAILED,
+                EXIT_INVALID_VAULT,
+                EXIT_INVALID_PASSWORD,
+                EXIT_IO,
+                EXIT_INVALID_KDF_PARAMS,
+                EXIT_VAULT_LOCKED,
+                EXIT_NOT_FOUND,
+                EXIT_AMBIGUOUS,
+                EXIT_INVALID_IMPORT,
+                EXIT_AGENT,
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp_format() {
+        assert_eq!(format_timestamp(0), "unknown");
+        assert_eq!(format_timestamp(1), "1970-01-01 00:00:01 UTC");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13:20 UTC");
+    }
+}
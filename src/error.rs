@@ -1,4 +1,6 @@
-use std::io;
+use std::{fmt, io};
+
+use crate::password::PasswordError;
 
 pub type PwdResult<T> = Result<T, PwdError>;
 
@@ -6,13 +8,67 @@ pub type PwdResult<T> = Result<T, PwdError>;
 pub enum PwdError {
     AuthenticationFailed,
     InvalidVaultFile,
+    UnsupportedVaultVersion(u16),
+    InvalidKdfParams,
     InvalidPassword,
+    InvalidPasswordPolicy(String),
+    VaultLocked,
+    EntryNotFound,
+    GroupNotFound,
+    HistoryNotFound,
+    AmbiguousEntry,
+    InvalidImportFile(String),
+    InvalidBackupFile,
+    InvalidOtpSecret,
+    OtpNotFound,
+    AgentNotRunning,
+    AgentAlreadyRunning,
 
     IO(io::Error)
 }
 
+impl fmt::Display for PwdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::AuthenticationFailed => {
+                write!(f, "Authentication failed (wrong master password?)")
+            }
+            Self::InvalidVaultFile => write!(f, "Invalid or corrupted vault file"),
+            Self::UnsupportedVaultVersion(version) => {
+                write!(f, "Unsupported vault format version: {}", version)
+            }
+            Self::InvalidKdfParams => write!(f, "Invalid key derivation params"),
+            Self::InvalidPassword => write!(f, "Invalid password"),
+            Self::InvalidPasswordPolicy(reason) => write!(f, "Invalid password policy: {}", reason),
+            Self::VaultLocked => write!(f, "The vault is locked by another pwdeck process"),
+            Self::EntryNotFound => write!(f, "Could not find the given ID"),
+            Self::GroupNotFound => write!(f, "Could not find the given group"),
+            Self::HistoryNotFound => write!(f, "Could not find the given password in the history"),
+            Self::AmbiguousEntry => write!(f, "Several entries match the given query"),
+            Self::InvalidImportFile(reason) => write!(f, "Invalid import file: {}", reason),
+            Self::InvalidBackupFile => write!(f, "Invalid or corrupted backup file"),
+            Self::InvalidOtpSecret => write!(f, "Invalid OTP secret or otpauth URI"),
+            Self::OtpNotFound => write!(f, "The entry has no OTP secret"),
+            Self::AgentNotRunning => write!(f, "The pwdeck agent is not running"),
+            Self::AgentAlreadyRunning => write!(f, "A pwdeck agent is already running"),
+            Self::IO(error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for PwdError {}
+
 impl From<io::Error> for PwdError {
     fn from(error: io::Error) -> Self {
         Self::IO(error)
     }
 }
+
+impl From<PasswordError> for PwdError {
+    fn from(error: PasswordError) -> Self {
+        match error {
+            PasswordError::InvalidPolicy(reason) => Self::InvalidPasswordPolicy(reason),
+            _ => Self::InvalidPassword,
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{password::Entry, security::SecString, vault::VaultSchema};
+
+/// The columns of the CSV export
+const CSV_HEADER: [&str; 11] = [
+    "id",
+    "group",
+    "name",
+    "password",
+    "url",
+    "notes",
+    "tags",
+    "fields",
+    "otp",
+    "created_at",
+    "updated_at",
+];
+
+/// Plaintext export formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Serialize)]
+/// The vault schema with its groups sorted, so exports can be diffed
+struct SortedSchema<'a> {
+    passwords: BTreeMap<&'a str, &'a [Entry]>,
+}
+
+/// Export the vault entries in plaintext.
+///
+/// The JSON export has the same layout as the vault schema, with every
+/// entry field (history included). The CSV export has one entry per line,
+/// without the history, and with the custom fields as `name=value` lines.
+pub fn export(schema: &VaultSchema, format: ExportFormat) -> SecString {
+    match format {
+        ExportFormat::Json => export_json(schema),
+        ExportFormat::Csv => export_csv(schema),
+    }
+}
+
+fn export_json(schema: &VaultSchema) -> SecString {
+    let sorted = SortedSchema {
+        passwords: schema
+            .passwords
+            .iter()
+            .map(|(group, entries)| (group.as_str(), entries.as_slice()))
+            .collect(),
+    };
+
+    // the schema only holds strings, numbers and lists
+    serde_json::to_string_pretty(&sorted).unwrap().into()
+}
+
+fn export_csv(schema: &VaultSchema) -> SecString {
+    let mut entries: Vec<(&str, &Entry)> = schema
+        .passwords
+        .iter()
+        .flat_map(|(group, entries)| entries.iter().map(move |entry| (group.as_str(), entry)))
+        .collect();
+    entries.sort_by_key(|(group, entry)| (*group, entry.name()));
+
+    let mut csv = csv_line(CSV_HEADER.iter().copied());
+    for (group, entry) in entries {
+        let fields: Vec<String> = entry
+            .fields()
+            .iter()
+            .map(|field| format!("{}={}", field.name, field.value.as_str()))
+            .collect();
+
+        let tags = entry.tags().join(",");
+        let fields = fields.join("\n");
+        let created_at = entry.created_at().to_string();
+        let updated_at = entry.updated_at().to_string();
+
+        csv.push_str(&csv_line(
+            [
+                entry.id(),
+                group,
+                entry.name(),
+                entry.password(),
+                entry.url().unwrap_or(""),
+                entry.notes().map_or("", |notes| notes.as_str()),
+                &tags,
+                &fields,
+                entry.otp().map_or("", |otp| otp.as_str()),
+                &created_at,
+                &updated_at,
+            ]
+            .iter()
+            .copied(),
+        ));
+    }
+
+    csv.into()
+}
+
+/// Format a CSV record (RFC 4180), quoting the values when needed
+fn csv_line<'a>(values: impl Iterator<Item = &'a str>) -> String {
+    let values: Vec<String> = values
+        .map(|value| {
+            if value.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                String::from(value)
+            }
+        })
+        .collect();
+
+    values.join(",") + "\r\n"
+}
+
+/// Write an export or a backup to a new file only readable by its owner,
+/// existing files are never overwritten
+pub fn write_export<P: AsRef<Path>>(path: P, export: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(export)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::password::{CustomField, EntryUpdate};
+
+    fn test_schema() -> VaultSchema {
+        let mut entry = Entry::new("foo@email.com", "pass,\"word\"");
+        let update = EntryUpdate {
+            notes: Some(SecString::from("line 1\nline 2")),
+            tags: Some(vec![String::from("work"), String::from("dev")]),
+            fields: vec![CustomField::new("pin", "1234", true)],
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+
+        let mut schema = VaultSchema::default();
+        schema.passwords.insert(String::from("Github"), vec![entry]);
+        schema
+    }
+
+    #[test]
+    fn json_export() {
+        let schema = test_schema();
+        let json = export(&schema, ExportFormat::Json);
+
+        // the export can be read back as a vault schema
+        let decoded: VaultSchema = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.passwords, schema.passwords);
+    }
+
+    #[test]
+    fn csv_export() {
+        let schema = test_schema();
+        let entry = &schema.passwords["Github"][0];
+        let csv = export(&schema, ExportFormat::Csv);
+
+        let expected = format!(
+            "id,group,name,password,url,notes,tags,fields,otp,created_at,updated_at\r\n\
+             {},Github,foo@email.com,\"pass,\"\"word\"\"\",,\"line 1\nline 2\",\"work,dev\",pin=1234,,{},{}\r\n",
+            entry.id(),
+            entry.created_at(),
+            entry.updated_at(),
+        );
+        assert_eq!(csv.as_str(), expected);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let line = csv_line(["plain", "a,b", "say \"hi\"", "a\nb", ""].iter().copied());
+        assert_eq!(line, "plain,\"a,b\",\"say \"\"hi\"\"\",\"a\nb\",\r\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn private_export_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const PATH: &str = "target/debug_export.json";
+        let _ = fs::remove_file(PATH);
+
+        let export = SecString::from("{}");
+        write_export(PATH, export.as_bytes()).unwrap();
+
+        let mode = fs::metadata(PATH).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // existing files are kept
+        assert!(write_export(PATH, export.as_bytes()).is_err());
+        fs::remove_file(PATH).unwrap();
+    }
+}
@@ -1,3 +1,28 @@
+// flock operations
+pub const LOCK_EX: i32 = 2;
+pub const LOCK_NB: i32 = 4;
+
+// getsockopt option to read the credentials of a unix socket peer
+#[cfg(target_os = "linux")]
+pub const SOL_SOCKET: i32 = 1;
+#[cfg(target_os = "linux")]
+pub const SO_PEERCRED: i32 = 17;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+pub struct UCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
 extern "C" {
     pub fn isatty(fd: i32) -> i32;
+    pub fn flock(fd: i32, operation: i32) -> i32;
+    pub fn geteuid() -> u32;
+    #[cfg(target_os = "linux")]
+    pub fn getsockopt(fd: i32, level: i32, name: i32, value: *mut UCred, len: *mut u32) -> i32;
+    #[cfg(not(target_os = "linux"))]
+    pub fn getpeereid(fd: i32, uid: *mut u32, gid: *mut u32) -> i32;
 }
@@ -4,9 +4,12 @@ use std::io::{BufRead, BufReader};
 use rand::distributions::{self, Distribution};
 use rand::rngs::OsRng;
 
-use super::PasswordGenerator;
+use super::{GeneratedPassword, PasswordGenerator};
 use crate::{password::PasswordError, security::SecString};
 
+/// The count of dices rolled for each word
+const DICES: u32 = 5;
+
 /// Diceware password generator
 pub struct Diceware {
     /// the diceware wordlist path
@@ -22,7 +25,7 @@ impl Diceware {
 }
 
 impl PasswordGenerator for Diceware {
-    fn generate(&self) -> Result<SecString, PasswordError> {
+    fn generate(&self) -> Result<GeneratedPassword, PasswordError> {
         let wordlist_file =
             File::open(&self.source_path).expect("Can't open the diceware wordlist");
         let lines: Vec<String> = BufReader::new(&wordlist_file)
@@ -30,13 +33,13 @@ impl PasswordGenerator for Diceware {
             .map(|l| l.unwrap())
             .collect();
 
-        let mut rng = OsRng::default();
+        let mut rng = OsRng;
         let mut password = String::new();
 
         for _ in 0..self.words {
             // roll the dices
             let dices = distributions::Uniform::new_inclusive(0, 5);
-            let dices: Vec<usize> = dices.sample_iter(&mut rng).take(5).collect();
+            let dices: Vec<usize> = dices.sample_iter(&mut rng).take(DICES as usize).collect();
 
             let line = dices[4] + dices[3] * 6 + dices[2] * 36 + dices[1] * 216 + dices[0] * 1296;
 
@@ -45,7 +48,11 @@ impl PasswordGenerator for Diceware {
         }
         password.pop();
 
-        Ok(SecString::from(password))
+        Ok(GeneratedPassword {
+            password: SecString::from(password),
+            // each word is one of the 6^5 lines
+            entropy: self.words as f64 * 6f64.powi(DICES as i32).log2(),
+        })
     }
 }
 
@@ -56,17 +63,19 @@ mod tests {
     #[test]
     fn five_words() {
         let diceware_words = 5;
-        let diceware_password = Diceware {
+        let generated = Diceware {
             source_path: String::from("res/diceware_wordlist.txt"),
             words: diceware_words,
         }
         .generate()
         .unwrap();
+        let diceware_password = generated.password;
 
         println!("{}", *diceware_password);
         assert_eq!(
             diceware_password.split(" ").collect::<Vec<_>>().len(),
             diceware_words
         );
+        assert!((generated.entropy - 5.0 * 7776f64.log2()).abs() < 1e-9);
     }
 }
@@ -5,14 +5,23 @@ mod random;
 
 use diceware::Diceware;
 use random::Random;
+pub use random::{CharClass, RandomPolicy, LOOKALIKES, SYMBOLS};
+
+/// A generated password, with its entropy
+pub struct GeneratedPassword {
+    pub password: SecString,
+    /// the log2 count of the passwords the generator
+    /// could have returned, all being equally likely
+    pub entropy: f64,
+}
 
 /// Generator trait
 pub trait PasswordGenerator {
-    fn generate(&self) -> Result<SecString, PasswordError>;
+    fn generate(&self) -> Result<GeneratedPassword, PasswordError>;
 }
 
 pub enum GenerationMethod {
-    Random(usize),
+    Random(RandomPolicy),
     Diceware(String, usize),
 }
 
@@ -26,7 +35,7 @@ impl Generator {
         Self { generator }
     }
 
-    pub fn password(self) -> Result<SecString, PasswordError> {
+    pub fn generate(self) -> Result<GeneratedPassword, PasswordError> {
         self.generator.generate()
     }
 }
@@ -34,7 +43,7 @@ impl Generator {
 impl From<GenerationMethod> for Generator {
     fn from(method: GenerationMethod) -> Self {
         let generator: Box<dyn PasswordGenerator> = match method {
-            GenerationMethod::Random(len) => Box::new(Random::new(len)),
+            GenerationMethod::Random(policy) => Box::new(Random::new(policy)),
             GenerationMethod::Diceware(wordlist, len) => Box::new(Diceware::new(wordlist, len)),
         };
 
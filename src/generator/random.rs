@@ -1,60 +1,365 @@
-use rand::{rngs::OsRng, Rng};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::OsRng,
+    seq::SliceRandom,
+};
 
-use super::PasswordGenerator;
+use super::{GeneratedPassword, PasswordGenerator};
 use crate::{password::PasswordError, security::SecString};
 
+/// The symbols used by default
+pub const SYMBOLS: &str = "!#$%&*+-_./:=?~`";
+
+/// The characters easily mistaken for one another
+pub const LOOKALIKES: &str = "0O1lI|";
+
+/// The longest password with minimum counts,
+/// their generation cost grows with the square of the length
+const MAX_CONSTRAINED_LENGTH: usize = 1024;
+
+/// The classes of the password characters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharClass {
+    Lowercase,
+    Uppercase,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    pub const ALL: [CharClass; 4] = [
+        CharClass::Lowercase,
+        CharClass::Uppercase,
+        CharClass::Digit,
+        CharClass::Symbol,
+    ];
+
+    /// The class of a character, anything but
+    /// ASCII letters and digits being a symbol
+    pub fn of(c: char) -> Self {
+        if c.is_ascii_lowercase() {
+            Self::Lowercase
+        } else if c.is_ascii_uppercase() {
+            Self::Uppercase
+        } else if c.is_ascii_digit() {
+            Self::Digit
+        } else {
+            Self::Symbol
+        }
+    }
+
+    /// The characters of the class used by default
+    fn chars(self) -> &'static str {
+        match self {
+            Self::Lowercase => "abcdefghijklmnopqrstuvwxyz",
+            Self::Uppercase => "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+            Self::Digit => "0123456789",
+            Self::Symbol => SYMBOLS,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Lowercase => "lowercase",
+            Self::Uppercase => "uppercase",
+            Self::Digit => "digit",
+            Self::Symbol => "symbol",
+        }
+    }
+}
+
+/// The rules of the random passwords
+#[derive(Debug, Clone, PartialEq)]
+pub struct RandomPolicy {
+    /// the password length
+    pub length: usize,
+    /// the classes of the default characters
+    pub classes: Vec<CharClass>,
+    /// the characters to use instead of the classes ones
+    pub alphabet: Option<String>,
+    /// characters added to the alphabet
+    pub include: String,
+    /// characters never used, even if included
+    pub exclude: String,
+    /// whether to exclude the `LOOKALIKES`
+    pub exclude_lookalikes: bool,
+
+    // the minimum count of characters of each class
+    pub min_lowercase: usize,
+    pub min_uppercase: usize,
+    pub min_digits: usize,
+    pub min_symbols: usize,
+}
+
+impl RandomPolicy {
+    /// A policy using all the classes, without constraints
+    pub fn new(length: usize) -> Self {
+        Self {
+            length,
+            classes: CharClass::ALL.to_vec(),
+            alphabet: None,
+            include: String::new(),
+            exclude: String::new(),
+            exclude_lookalikes: false,
+            min_lowercase: 0,
+            min_uppercase: 0,
+            min_digits: 0,
+            min_symbols: 0,
+        }
+    }
+
+    pub fn minimum(&self, class: CharClass) -> usize {
+        match class {
+            CharClass::Lowercase => self.min_lowercase,
+            CharClass::Uppercase => self.min_uppercase,
+            CharClass::Digit => self.min_digits,
+            CharClass::Symbol => self.min_symbols,
+        }
+    }
+
+    /// The characters the passwords are made of, without duplicates
+    pub fn alphabet(&self) -> Vec<char> {
+        let base: String = match &self.alphabet {
+            Some(alphabet) => alphabet.clone(),
+            None => self.classes.iter().map(|class| class.chars()).collect(),
+        };
+
+        let mut alphabet: Vec<char> = Vec::new();
+        for c in base.chars().chain(self.include.chars()) {
+            let excluded = self.exclude.contains(c)
+                || (self.exclude_lookalikes && LOOKALIKES.contains(c))
+                || c.is_control();
+
+            if !excluded && !alphabet.contains(&c) {
+                alphabet.push(c);
+            }
+        }
+
+        alphabet
+    }
+
+    /// Check the policy can be satisfied, returning the
+    /// alphabet characters of each class
+    fn classify(&self) -> Result<Vec<(CharClass, Vec<char>)>, PasswordError> {
+        let alphabet = self.alphabet();
+        if alphabet.is_empty() {
+            return Err(PasswordError::InvalidPolicy(String::from(
+                "no characters left to use",
+            )));
+        }
+
+        let minimums: usize = CharClass::ALL
+            .iter()
+            .map(|&class| self.minimum(class))
+            .sum();
+        if minimums > self.length {
+            return Err(PasswordError::InvalidPolicy(format!(
+                "the minimum counts add up to more than {} characters",
+                self.length
+            )));
+        }
+
+        let mut classes = Vec::new();
+        for &class in CharClass::ALL.iter() {
+            let chars: Vec<char> = alphabet
+                .iter()
+                .copied()
+                .filter(|&c| CharClass::of(c) == class)
+                .collect();
+
+            if chars.is_empty() && self.minimum(class) > 0 {
+                return Err(PasswordError::InvalidPolicy(format!(
+                    "no {} characters left to use",
+                    class.name()
+                )));
+            }
+            if !chars.is_empty() {
+                classes.push((class, chars));
+            }
+        }
+
+        Ok(classes)
+    }
+}
+
 /// Random password generator
 pub struct Random {
-    /// the password length
-    length: usize,
+    policy: RandomPolicy,
 }
 
 impl Random {
-    pub fn new(length: usize) -> Self {
-        Self { length }
+    pub fn new(policy: RandomPolicy) -> Self {
+        Self { policy }
+    }
+}
+
+impl Random {
+    /// Pick each character uniformly from the whole alphabet
+    fn generate_uniform(&self, classes: &[(CharClass, Vec<char>)]) -> GeneratedPassword {
+        let alphabet: Vec<char> = classes
+            .iter()
+            .flat_map(|(_, chars)| chars.iter().copied())
+            .collect();
+
+        let mut rng = OsRng;
+        let password: String = (0..self.policy.length)
+            .map(|_| *alphabet.choose(&mut rng).unwrap())
+            .collect();
+
+        GeneratedPassword {
+            password: SecString::from(password),
+            entropy: self.policy.length as f64 * (alphabet.len() as f64).log2(),
+        }
+    }
+
+    /// Pick the characters uniformly among the passwords
+    /// satisfying the minimum counts
+    fn generate_constrained(
+        &self,
+        classes: &[(CharClass, Vec<char>)],
+    ) -> Result<GeneratedPassword, PasswordError> {
+        if self.policy.length > MAX_CONSTRAINED_LENGTH {
+            return Err(PasswordError::InvalidPolicy(format!(
+                "the minimum counts are only supported up to {} characters",
+                MAX_CONSTRAINED_LENGTH
+            )));
+        }
+
+        let sizes: Vec<(usize, usize)> = classes
+            .iter()
+            .map(|(class, chars)| (chars.len(), self.policy.minimum(*class)))
+            .collect();
+        let table = CountTable::new(&sizes, self.policy.length);
+
+        let mut rng = OsRng;
+
+        // pick how many characters of each class, weighted by
+        // the count of passwords having them
+        let mut slots: Vec<usize> = Vec::with_capacity(self.policy.length);
+        let mut remaining = self.policy.length;
+        for (i, &(size, minimum)) in sizes.iter().enumerate() {
+            let counts: Vec<f64> = (0..=remaining)
+                .map(|k| {
+                    if k < minimum {
+                        f64::NEG_INFINITY
+                    } else {
+                        table.with_class(i, size, remaining, k)
+                    }
+                })
+                .collect();
+            let max = counts.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let weights = WeightedIndex::new(counts.iter().map(|count| (count - max).exp2()))
+                .map_err(|_| PasswordError::Unknown)?;
+
+            let k = weights.sample(&mut rng);
+            slots.extend(std::iter::repeat_n(i, k));
+            remaining -= k;
+        }
+
+        // then place them anywhere, and pick each character
+        // uniformly, so every valid password is equally likely
+        slots.shuffle(&mut rng);
+        let password: String = slots
+            .into_iter()
+            .map(|i| *classes[i].1.choose(&mut rng).unwrap())
+            .collect();
+
+        Ok(GeneratedPassword {
+            password: SecString::from(password),
+            entropy: table.log2_count(0, self.policy.length),
+        })
     }
 }
 
 impl PasswordGenerator for Random {
-    fn generate(&self) -> Result<SecString, PasswordError> {
-        let special_chars = [
-            '!', '#', '$', '%', '&', '*', '+', '-', '_', '.', '/', ':', '=', '?', '~', '`',
-        ];
-
-        let mut rng = OsRng::default();
-        let mut password = String::new();
-
-        for _ in 0..self.length {
-            let r: u8 = rng.gen_range(0..7);
-
-            match r {
-                // uppercase
-                0..=1 => password.push(rng.gen_range('a'..='z')),
-                // lowercase
-                2..=3 => password.push(rng.gen_range('A'..='Z')),
-                // number
-                4..=5 => password.push(char::from(rng.gen_range(48..=57))),
-                // special
-                6 => {
-                    let i = rng.gen_range(0..special_chars.len());
-                    password.push(special_chars[i]);
-                }
-                _ => unreachable!(),
+    fn generate(&self) -> Result<GeneratedPassword, PasswordError> {
+        let classes = self.policy.classify()?;
+
+        let constrained = CharClass::ALL
+            .iter()
+            .any(|&class| self.policy.minimum(class) > 0);
+        if constrained {
+            self.generate_constrained(&classes)
+        } else {
+            Ok(self.generate_uniform(&classes))
+        }
+    }
+}
+
+/// The log2 count of the passwords satisfying the minimum counts,
+/// for each class and each password length
+struct CountTable {
+    /// `counts[i][n]` counts the passwords of `n` characters
+    /// made of the classes from the `i`th one
+    counts: Vec<Vec<f64>>,
+    /// `log2(n!)`, for the binomial coefficients
+    factorials: Vec<f64>,
+}
+
+impl CountTable {
+    /// Build the table from the alphabet size and
+    /// the minimum count of each class
+    fn new(sizes: &[(usize, usize)], length: usize) -> Self {
+        let mut factorials = vec![0.0; length + 1];
+        for n in 1..=length {
+            factorials[n] = factorials[n - 1] + (n as f64).log2();
+        }
+
+        let mut table = Self {
+            counts: vec![vec![f64::NEG_INFINITY; length + 1]; sizes.len() + 1],
+            factorials,
+        };
+        // no class left: only the empty password
+        table.counts[sizes.len()][0] = 0.0;
+
+        for (i, &(size, minimum)) in sizes.iter().enumerate().rev() {
+            for n in 0..=length {
+                table.counts[i][n] = (minimum..=n)
+                    .map(|k| table.with_class(i, size, n, k))
+                    .fold(f64::NEG_INFINITY, log2_add);
             }
         }
 
-        Ok(SecString::from(password))
+        table
+    }
+
+    fn log2_count(&self, class: usize, length: usize) -> f64 {
+        self.counts[class][length]
     }
+
+    /// The log2 count of the passwords of `length` characters having exactly
+    /// `k` characters of the `class`th one, out of `size`
+    fn with_class(&self, class: usize, size: usize, length: usize, k: usize) -> f64 {
+        let binomial = self.factorials[length] - self.factorials[k] - self.factorials[length - k];
+        binomial + k as f64 * (size as f64).log2() + self.counts[class + 1][length - k]
+    }
+}
+
+/// `log2(2^a + 2^b)`, without overflowing
+fn log2_add(a: f64, b: f64) -> f64 {
+    let (max, min) = if a > b { (a, b) } else { (b, a) };
+    if min == f64::NEG_INFINITY {
+        return max;
+    }
+
+    max + (min - max).exp2().ln_1p() / std::f64::consts::LN_2
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
     fn long_random() {
         let length = 40;
-        let random_password = Random::new(length).generate().unwrap();
+        let random_password = Random::new(RandomPolicy::new(length))
+            .generate()
+            .unwrap()
+            .password;
 
         println!("{}", *random_password);
         assert_eq!(random_password.len(), length);
@@ -63,9 +368,171 @@ mod tests {
     #[test]
     fn short_random() {
         let length = 10;
-        let random_password = Random::new(length).generate().unwrap();
+        let random_password = Random::new(RandomPolicy::new(length))
+            .generate()
+            .unwrap()
+            .password;
 
         println!("{}", *random_password);
         assert_eq!(random_password.len(), length)
     }
+
+    #[test]
+    fn custom_alphabet() {
+        let policy = RandomPolicy {
+            alphabet: Some(String::from("abc")),
+            include: String::from("é"),
+            exclude: String::from("b"),
+            ..RandomPolicy::new(30)
+        };
+        assert_eq!(policy.alphabet(), ['a', 'c', 'é']);
+
+        let password = Random::new(policy).generate().unwrap().password;
+        assert!(password.chars().all(|c| "acé".contains(c)));
+    }
+
+    #[test]
+    fn unsatisfiable_policies() {
+        let no_digits = RandomPolicy {
+            classes: vec![CharClass::Lowercase],
+            min_digits: 1,
+            ..RandomPolicy::new(10)
+        };
+        let too_short = RandomPolicy {
+            min_lowercase: 3,
+            min_symbols: 3,
+            ..RandomPolicy::new(5)
+        };
+        let empty = RandomPolicy {
+            alphabet: Some(String::from("0O")),
+            exclude_lookalikes: true,
+            ..RandomPolicy::new(5)
+        };
+
+        for policy in [no_digits, too_short, empty].iter() {
+            assert!(matches!(
+                Random::new(policy.clone()).generate(),
+                Err(PasswordError::InvalidPolicy(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn uniform_entropy() {
+        let generated = Random::new(RandomPolicy::new(20)).generate().unwrap();
+        let alphabet_size = 26 + 26 + 10 + SYMBOLS.len();
+
+        let expected = 20.0 * (alphabet_size as f64).log2();
+        assert!((generated.entropy - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn huge_random() {
+        let length = 100_000;
+        let generated = Random::new(RandomPolicy::new(length)).generate().unwrap();
+
+        assert_eq!(generated.password.len(), length);
+    }
+
+    #[test]
+    fn huge_constrained_random() {
+        let policy = RandomPolicy {
+            min_digits: 1,
+            ..RandomPolicy::new(MAX_CONSTRAINED_LENGTH + 1)
+        };
+        assert!(matches!(
+            Random::new(policy).generate(),
+            Err(PasswordError::InvalidPolicy(_))
+        ));
+
+        let policy = RandomPolicy {
+            min_digits: 1,
+            ..RandomPolicy::new(MAX_CONSTRAINED_LENGTH)
+        };
+        let password = Random::new(policy).generate().unwrap().password;
+        assert!(password.chars().any(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn constrained_entropy() {
+        // "aaa" and "BBB" are the only passwords left out
+        let policy = RandomPolicy {
+            alphabet: Some(String::from("aB")),
+            min_lowercase: 1,
+            min_uppercase: 1,
+            ..RandomPolicy::new(3)
+        };
+        let random = Random::new(policy);
+        assert!((random.generate().unwrap().entropy - 6f64.log2()).abs() < 1e-9);
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..6000 {
+            let password = random.generate().unwrap().password;
+            *counts.entry(password.to_string()).or_default() += 1;
+        }
+
+        assert_eq!(counts.len(), 6);
+        for count in counts.values() {
+            assert!((800..1200).contains(count), "{:?}", counts);
+        }
+    }
+
+    fn policies() -> impl Strategy<Value = RandomPolicy> {
+        (
+            0..64usize,
+            proptest::sample::subsequence(CharClass::ALL.to_vec(), 0..=4),
+            "[a-zA-Z0-9!#$%]{0,8}",
+            "[a-zA-Z0-9!#$%]{0,8}",
+            any::<bool>(),
+            proptest::array::uniform4(0..5usize),
+        )
+            .prop_map(
+                |(length, classes, include, exclude, exclude_lookalikes, minimums)| RandomPolicy {
+                    classes,
+                    include,
+                    exclude,
+                    exclude_lookalikes,
+                    min_lowercase: minimums[0],
+                    min_uppercase: minimums[1],
+                    min_digits: minimums[2],
+                    min_symbols: minimums[3],
+                    ..RandomPolicy::new(length)
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn policy_constraints(policy in policies()) {
+            let alphabet = policy.alphabet();
+            let satisfiable = !alphabet.is_empty()
+                && CharClass::ALL.iter().map(|&class| policy.minimum(class)).sum::<usize>()
+                    <= policy.length
+                && CharClass::ALL.iter().all(|&class| {
+                    policy.minimum(class) == 0
+                        || alphabet.iter().any(|&c| CharClass::of(c) == class)
+                });
+
+            let password = match Random::new(policy.clone()).generate() {
+                Ok(generated) => generated.password,
+                Err(error) => {
+                    prop_assert!(!satisfiable);
+                    prop_assert!(matches!(error, PasswordError::InvalidPolicy(_)));
+                    return Ok(());
+                }
+            };
+            prop_assert!(satisfiable);
+
+            prop_assert_eq!(password.chars().count(), policy.length);
+            for c in password.chars() {
+                prop_assert!(alphabet.contains(&c));
+                prop_assert!(!policy.exclude.contains(c));
+                prop_assert!(!(policy.exclude_lookalikes && LOOKALIKES.contains(c)));
+            }
+            for &class in CharClass::ALL.iter() {
+                let count = password.chars().filter(|&c| CharClass::of(c) == class).count();
+                prop_assert!(count >= policy.minimum(class));
+            }
+        }
+    }
 }
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::{invalid, non_empty, text, ImportItem, Importer};
+use crate::{error::PwdResult, password::CustomField};
+
+// the Bitwarden custom field types
+const FIELD_HIDDEN: u8 = 1;
+
+/// Bitwarden unencrypted JSON export
+pub struct Bitwarden;
+
+#[derive(Deserialize)]
+struct Export {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    folders: Vec<Folder>,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct Folder {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Item {
+    name: String,
+    folder_id: Option<String>,
+    notes: Option<String>,
+    #[serde(default)]
+    fields: Vec<Field>,
+    // only set for logins, secure notes and cards are skipped
+    login: Option<Login>,
+}
+
+#[derive(Deserialize)]
+struct Field {
+    name: Option<String>,
+    value: Option<String>,
+    #[serde(rename = "type")]
+    kind: u8,
+}
+
+#[derive(Deserialize, Default)]
+struct Login {
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    uris: Vec<Uri>,
+    totp: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Uri {
+    uri: Option<String>,
+}
+
+impl Importer for Bitwarden {
+    fn parse(&self, data: &[u8]) -> PwdResult<Vec<ImportItem>> {
+        let export: Export = serde_json::from_str(text(data)?).map_err(invalid)?;
+        if export.encrypted {
+            return Err(invalid("encrypted Bitwarden exports are not supported"));
+        }
+
+        let folders: HashMap<&str, &str> = export
+            .folders
+            .iter()
+            .map(|folder| (folder.id.as_str(), folder.name.as_str()))
+            .collect();
+
+        let items = export
+            .items
+            .into_iter()
+            .map(|item| {
+                // the folders are kept as tags
+                let tags = item
+                    .folder_id
+                    .as_deref()
+                    .and_then(|id| folders.get(id))
+                    .map(|folder| vec![String::from(*folder)])
+                    .unwrap_or_default();
+
+                let fields = item
+                    .fields
+                    .into_iter()
+                    .filter_map(|field| {
+                        let secret = field.kind == FIELD_HIDDEN;
+                        Some(CustomField::new(&field.name?, &field.value?, secret))
+                    })
+                    .collect();
+
+                let login = item.login.unwrap_or_default();
+
+                ImportItem {
+                    group: item.name,
+                    name: login.username.unwrap_or_default(),
+                    password: login.password.unwrap_or_default(),
+                    url: login.uris.into_iter().find_map(|uri| uri.uri),
+                    notes: item.notes.and_then(non_empty),
+                    tags,
+                    fields,
+                    otp: login.totp.and_then(non_empty),
+                }
+            })
+            .collect();
+
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"{
+        "encrypted": false,
+        "folders": [{ "id": "f1", "name": "Work" }],
+        "items": [
+            {
+                "id": "i1",
+                "folderId": "f1",
+                "type": 1,
+                "name": "GitHub",
+                "notes": "recovery codes in the safe",
+                "favorite": false,
+                "fields": [
+                    { "name": "account", "value": "42", "type": 0 },
+                    { "name": "pin", "value": "1234", "type": 1 },
+                    { "name": "linked", "value": null, "type": 3, "linkedId": 100 }
+                ],
+                "login": {
+                    "uris": [{ "match": null, "uri": "https://github.com" }],
+                    "username": "me",
+                    "password": "123",
+                    "totp": "JBSWY3DPEHPK3PXP"
+                }
+            },
+            {
+                "id": "i2",
+                "folderId": null,
+                "type": 2,
+                "name": "Wifi",
+                "notes": "the password is on the router",
+                "secureNote": { "type": 0 }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn login() {
+        let items = Bitwarden.parse(EXPORT.as_bytes()).unwrap();
+        let item = &items[0];
+
+        assert_eq!(item.group, "GitHub");
+        assert_eq!(item.name, "me");
+        assert_eq!(item.password, "123");
+        assert_eq!(item.url.as_deref(), Some("https://github.com"));
+        assert_eq!(item.notes.as_deref(), Some("recovery codes in the safe"));
+        assert_eq!(item.tags, ["Work"]);
+
+        let fields: Vec<(&str, bool)> = item
+            .fields
+            .iter()
+            .map(|field| (field.name.as_str(), field.secret))
+            .collect();
+        assert_eq!(fields, [("account", false), ("pin", true)]);
+        assert_eq!(item.otp.as_deref(), Some("JBSWY3DPEHPK3PXP"));
+    }
+
+    #[test]
+    fn secure_note() {
+        let items = Bitwarden.parse(EXPORT.as_bytes()).unwrap();
+
+        // imported without password, so it is skipped by the import plan
+        assert_eq!(items[1].group, "Wifi");
+        assert!(items[1].password.is_empty());
+    }
+
+    #[test]
+    fn encrypted_export() {
+        let export = r#"{ "encrypted": true, "encKeyValidation_DO_NOT_EDIT": "2.x" }"#;
+        assert!(Bitwarden.parse(export.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use super::invalid;
+use crate::error::PwdResult;
+
+/// Parse CSV records (RFC 4180), with quoted values spanning
+/// several lines and `""` escaping a quote
+fn parse_records(text: &str) -> PwdResult<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut value = String::new();
+    let mut quoted = false;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    value.push('"');
+                }
+                '"' => quoted = false,
+                _ => value.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' => quoted = true,
+            ',' => record.push(std::mem::take(&mut value)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                record.push(std::mem::take(&mut value));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => value.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(invalid("unterminated quoted CSV value"));
+    }
+    // the last line may not end with a newline
+    if !value.is_empty() || !record.is_empty() {
+        record.push(value);
+        records.push(record);
+    }
+
+    // skip the blank lines
+    records.retain(|record| record != &[""]);
+
+    Ok(records)
+}
+
+/// Parse CSV records into maps from the lowercase column names to the values
+pub(crate) fn parse_csv(text: &str) -> PwdResult<Vec<HashMap<String, String>>> {
+    let mut records = parse_records(text)?.into_iter();
+
+    let header: Vec<String> = records
+        .next()
+        .ok_or_else(|| invalid("empty CSV file"))?
+        .iter()
+        .map(|column| column.trim().to_lowercase())
+        .collect();
+
+    records
+        .map(|record| {
+            if record.len() != header.len() {
+                return Err(invalid("CSV line with a wrong number of values"));
+            }
+            Ok(header.iter().cloned().zip(record).collect())
+        })
+        .collect()
+}
+
+/// Get the first value of the given columns set in the record
+pub(crate) fn column(record: &HashMap<String, String>, names: &[&str]) -> String {
+    names
+        .iter()
+        .filter_map(|name| record.get(*name))
+        .find(|value| !value.is_empty())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Check that the CSV has the given column
+pub(crate) fn require_column(records: &[HashMap<String, String>], name: &str) -> PwdResult<()> {
+    match records.first() {
+        Some(record) if !record.contains_key(name) => {
+            Err(invalid(format!("missing the CSV column '{}'", name)))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let text = "a,b,c\r\n1,\"2,3\",\"say \"\"hi\"\"\"\n\n\"multi\nline\",,x";
+        let records = parse_records(text).unwrap();
+
+        assert_eq!(
+            records,
+            [
+                vec!["a", "b", "c"],
+                vec!["1", "2,3", "say \"hi\""],
+                vec!["multi\nline", "", "x"],
+            ]
+        );
+    }
+
+    #[test]
+    fn header_columns() {
+        let records = parse_csv("Name, URL\ngithub,https://github.com\n").unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["name"], "github");
+        assert_eq!(
+            column(&records[0], &["website", "url"]),
+            "https://github.com"
+        );
+        assert_eq!(column(&records[0], &["notes"]), "");
+        assert!(require_column(&records, "password").is_err());
+    }
+
+    #[test]
+    fn malformed_csv() {
+        assert!(parse_csv("").is_err());
+        assert!(parse_csv("a,b\n1\n").is_err());
+        assert!(parse_csv("a,b\n\"1,2\n").is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use roxmltree::{Document, Node};
+
+use super::{invalid, non_empty, text, ImportItem, Importer};
+use crate::{error::PwdResult, password::CustomField};
+
+/// KeePass 2 XML export
+pub struct KeePass;
+
+// the standard entry strings, the others become custom fields
+const TITLE: &str = "Title";
+const USERNAME: &str = "UserName";
+const PASSWORD: &str = "Password";
+const URL: &str = "URL";
+const NOTES: &str = "Notes";
+// the otpauth URI, as stored by KeePassXC
+const OTP: &str = "otp";
+
+impl Importer for KeePass {
+    fn parse(&self, data: &[u8]) -> PwdResult<Vec<ImportItem>> {
+        let document = Document::parse(text(data)?).map_err(invalid)?;
+
+        let file = document.root_element();
+        if !file.has_tag_name("KeePassFile") {
+            return Err(invalid("not a KeePass XML export"));
+        }
+
+        // deleted entries are kept in the recycle bin group
+        let recycle_bin = child(file, "Meta")
+            .and_then(|meta| child_text(meta, "RecycleBinUUID"))
+            .unwrap_or_default();
+
+        let root_group = child(file, "Root")
+            .and_then(|root| child(root, "Group"))
+            .ok_or_else(|| invalid("missing the KeePass root group"))?;
+
+        let mut items = Vec::new();
+        read_group(root_group, &[], recycle_bin, &mut items);
+
+        Ok(items)
+    }
+}
+
+/// Read the entries of a group and of its subgroups, `path` is the
+/// list of the parent group names below the root group
+fn read_group(group: Node, path: &[&str], recycle_bin: &str, items: &mut Vec<ImportItem>) {
+    for entry in children(group, "Entry") {
+        items.push(read_entry(entry, path));
+    }
+
+    for subgroup in children(group, "Group") {
+        let uuid = child_text(subgroup, "UUID").unwrap_or_default();
+        if !recycle_bin.is_empty() && uuid == recycle_bin {
+            continue;
+        }
+
+        let name = child_text(subgroup, "Name").unwrap_or_default();
+        let mut subpath = path.to_vec();
+        subpath.push(name);
+        read_group(subgroup, &subpath, recycle_bin, items);
+    }
+}
+
+fn read_entry(entry: Node, path: &[&str]) -> ImportItem {
+    // the entry history is in a <History> child, and is not imported
+    let mut item = ImportItem::default();
+
+    for string in children(entry, "String") {
+        let key = child_text(string, "Key").unwrap_or_default();
+        let value_node = child(string, "Value");
+        let value = value_node.and_then(|v| v.text()).unwrap_or_default();
+
+        match key {
+            TITLE => item.group = String::from(value),
+            USERNAME => item.name = String::from(value),
+            PASSWORD => item.password = String::from(value),
+            URL => item.url = non_empty(value),
+            NOTES => item.notes = non_empty(value),
+            OTP => item.otp = non_empty(value),
+            _ if !value.is_empty() => {
+                let protected = value_node
+                    .and_then(|v| v.attribute("ProtectInMemory"))
+                    .is_some_and(|protect| protect.eq_ignore_ascii_case("true"));
+                item.fields.push(CustomField::new(key, value, protected));
+            }
+            _ => {}
+        }
+    }
+
+    // the groups are kept as a tag, e.g. "Internet/Social"
+    if !path.is_empty() {
+        item.tags.push(path.join("/"));
+    }
+    if let Some(tags) = child_text(entry, "Tags") {
+        item.tags.extend(
+            tags.split(&[';', ','][..])
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from),
+        );
+    }
+
+    item
+}
+
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.has_tag_name(name))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &'static str) -> Option<Node<'a, 'input>> {
+    children(node, name).next()
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &'static str) -> Option<&'a str> {
+    child(node, name).and_then(|child| child.text())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="utf-8" standalone="yes"?>
+<KeePassFile>
+    <Meta>
+        <Generator>KeePass</Generator>
+        <RecycleBinUUID>bin=</RecycleBinUUID>
+    </Meta>
+    <Root>
+        <Group>
+            <UUID>root=</UUID>
+            <Name>Database</Name>
+            <Entry>
+                <String><Key>Title</Key><Value>GitHub</Value></String>
+                <String><Key>UserName</Key><Value>me</Value></String>
+                <String><Key>Password</Key><Value ProtectInMemory="True">123</Value></String>
+                <String><Key>URL</Key><Value>https://github.com</Value></String>
+                <String><Key>Notes</Key><Value>line 1&#10;line 2</Value></String>
+                <String><Key>PIN</Key><Value ProtectInMemory="True">1234</Value></String>
+                <String><Key>Account</Key><Value>42</Value></String>
+                <String><Key>otp</Key><Value ProtectInMemory="True">otpauth://totp/me?secret=JBSWY3DPEHPK3PXP</Value></String>
+                <Tags>work;dev</Tags>
+                <History>
+                    <Entry>
+                        <String><Key>Title</Key><Value>GitHub</Value></String>
+                        <String><Key>Password</Key><Value>old</Value></String>
+                    </Entry>
+                </History>
+            </Entry>
+            <Group>
+                <UUID>internet=</UUID>
+                <Name>Internet</Name>
+                <Group>
+                    <UUID>social=</UUID>
+                    <Name>Social</Name>
+                    <Entry>
+                        <String><Key>Title</Key><Value>Reddit</Value></String>
+                        <String><Key>UserName</Key><Value>user</Value></String>
+                        <String><Key>Password</Key><Value>abc</Value></String>
+                    </Entry>
+                </Group>
+            </Group>
+            <Group>
+                <UUID>bin=</UUID>
+                <Name>Recycle Bin</Name>
+                <Entry>
+                    <String><Key>Title</Key><Value>Deleted</Value></String>
+                    <String><Key>Password</Key><Value>gone</Value></String>
+                </Entry>
+            </Group>
+        </Group>
+    </Root>
+</KeePassFile>"#;
+
+    #[test]
+    fn entries() {
+        let items = KeePass.parse(EXPORT.as_bytes()).unwrap();
+        // the history and the recycle bin are not imported
+        assert_eq!(items.len(), 2);
+
+        let item = &items[0];
+        assert_eq!(item.group, "GitHub");
+        assert_eq!(item.name, "me");
+        assert_eq!(item.password, "123");
+        assert_eq!(item.url.as_deref(), Some("https://github.com"));
+        assert_eq!(item.notes.as_deref(), Some("line 1\nline 2"));
+        assert_eq!(item.tags, ["work", "dev"]);
+        assert_eq!(
+            item.fields,
+            [
+                CustomField::new("PIN", "1234", true),
+                CustomField::new("Account", "42", false),
+            ]
+        );
+
+        assert_eq!(
+            item.otp.as_deref(),
+            Some("otpauth://totp/me?secret=JBSWY3DPEHPK3PXP")
+        );
+
+        assert_eq!(items[1].group, "Reddit");
+        assert_eq!(items[1].tags, ["Internet/Social"]);
+    }
+
+    #[test]
+    fn not_keepass() {
+        assert!(KeePass.parse(b"<html></html>").is_err());
+        assert!(KeePass.parse(b"not xml").is_err());
+    }
+}
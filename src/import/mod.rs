@@ -0,0 +1,268 @@
+use crate::{
+    error::{PwdError, PwdResult},
+    otp::Otp,
+    password::{CustomField, Entry, EntryUpdate},
+    security::SecString,
+    vault::VaultSchema,
+};
+
+mod bitwarden;
+mod browser;
+mod csv;
+mod keepass;
+mod onepassword;
+
+use bitwarden::Bitwarden;
+use browser::{Chrome, Firefox};
+use keepass::KeePass;
+use onepassword::OnePassword;
+
+/// Importer trait, parsing the export of another password manager
+pub trait Importer {
+    fn parse(&self, data: &[u8]) -> PwdResult<Vec<ImportItem>>;
+}
+
+/// The password managers pwdeck can import from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportFormat {
+    /// Bitwarden unencrypted JSON export
+    Bitwarden,
+    /// KeePass 2 XML export
+    KeePass,
+    /// 1Password 1PUX archive or CSV export
+    OnePassword,
+    /// Chrome passwords CSV export
+    Chrome,
+    /// Firefox logins CSV export
+    Firefox,
+}
+
+impl From<ImportFormat> for Box<dyn Importer> {
+    fn from(format: ImportFormat) -> Self {
+        match format {
+            ImportFormat::Bitwarden => Box::new(Bitwarden),
+            ImportFormat::KeePass => Box::new(KeePass),
+            ImportFormat::OnePassword => Box::new(OnePassword),
+            ImportFormat::Chrome => Box::new(Chrome),
+            ImportFormat::Firefox => Box::new(Firefox),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+/// An entry read from another password manager, mapped onto the
+/// vault layout: the group is the service and the name the username
+pub struct ImportItem {
+    pub group: String,
+    pub name: String,
+    pub password: String,
+    pub url: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+    pub fields: Vec<CustomField>,
+    /// an `otpauth://` URI or a base32 TOTP secret
+    pub otp: Option<String>,
+}
+
+impl ImportItem {
+    /// Build the vault entry, if the item has a password
+    fn into_entry(self) -> Option<(String, Entry)> {
+        if self.password.is_empty() {
+            return None;
+        }
+
+        // unsupported OTP secrets are kept as a secret field
+        let mut fields = self.fields;
+        let otp = match self.otp.map(|otp| Otp::parse(&otp).map_err(|_| otp)) {
+            Some(Ok(otp)) => Some(otp.to_uri()),
+            Some(Err(otp)) => {
+                fields.push(CustomField::new("otp", &otp, true));
+                None
+            }
+            None => None,
+        };
+
+        let mut entry = Entry::new(&self.name, &self.password);
+        let update = EntryUpdate {
+            url: self.url,
+            notes: self.notes.map(SecString::from),
+            tags: Some(self.tags),
+            fields,
+            otp,
+            ..EntryUpdate::default()
+        };
+        // the password was checked above
+        entry.update(update).ok()?;
+
+        Some((self.group, entry))
+    }
+
+    /// The item description in the import reports
+    fn label(&self) -> String {
+        match self.name.as_str() {
+            "" => self.group.clone(),
+            name => format!("{}: {}", self.group, name),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// What an import adds to the vault
+pub struct ImportPlan {
+    /// the new entries, along with their groups
+    pub entries: Vec<(String, Entry)>,
+    /// the items already in the vault, or twice in the import
+    pub duplicates: Vec<String>,
+    /// the items without password (secure notes, cards, ...)
+    pub skipped: Vec<String>,
+}
+
+/// Parse an export of another password manager
+pub fn parse(format: ImportFormat, data: &[u8]) -> PwdResult<Vec<ImportItem>> {
+    let importer: Box<dyn Importer> = format.into();
+    importer.parse(data)
+}
+
+/// Plan the import of the items into the vault.
+///
+/// An item is a duplicate when an entry of the same group (ignoring the
+/// case) already has the same name and password.
+pub fn plan(schema: &VaultSchema, items: Vec<ImportItem>) -> ImportPlan {
+    let mut plan = ImportPlan::default();
+
+    for item in items {
+        let label = item.label();
+
+        let is_duplicate = |group: &str, entry: &Entry| {
+            group.to_lowercase() == item.group.to_lowercase()
+                && entry.name() == item.name
+                && entry.password().as_str() == item.password
+        };
+        let in_vault = schema
+            .passwords
+            .iter()
+            .any(|(group, entries)| entries.iter().any(|entry| is_duplicate(group, entry)));
+        let in_import = plan
+            .entries
+            .iter()
+            .any(|(group, entry)| is_duplicate(group, entry));
+
+        if in_vault || in_import {
+            plan.duplicates.push(label);
+            continue;
+        }
+
+        match item.into_entry() {
+            Some(entry) => plan.entries.push(entry),
+            None => plan.skipped.push(label),
+        }
+    }
+
+    plan
+}
+
+/// The error for a malformed import file
+pub(crate) fn invalid(reason: impl ToString) -> PwdError {
+    PwdError::InvalidImportFile(reason.to_string())
+}
+
+/// Read the import file as UTF-8, without its byte order mark
+pub(crate) fn text(data: &[u8]) -> PwdResult<&str> {
+    let text = std::str::from_utf8(data).map_err(invalid)?;
+    Ok(text.strip_prefix('\u{feff}').unwrap_or(text))
+}
+
+/// Turn an empty string into `None`
+pub(crate) fn non_empty(value: impl Into<String>) -> Option<String> {
+    Some(value.into()).filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(group: &str, name: &str, password: &str) -> ImportItem {
+        ImportItem {
+            group: String::from(group),
+            name: String::from(name),
+            password: String::from(password),
+            ..ImportItem::default()
+        }
+    }
+
+    #[test]
+    fn plan_import() {
+        let mut schema = VaultSchema::default();
+        schema
+            .passwords
+            .insert(String::from("Github"), vec![Entry::new("me", "123")]);
+
+        let items = vec![
+            // already in the vault, the group case is ignored
+            item("github", "me", "123"),
+            // same username, but another password
+            item("Github", "me", "456"),
+            item("Reddit", "user", "abc"),
+            // twice in the import
+            item("Reddit", "user", "abc"),
+            // no password
+            item("Notes", "wifi", ""),
+        ];
+
+        let plan = plan(&schema, items);
+        let added: Vec<(&str, &str)> = plan
+            .entries
+            .iter()
+            .map(|(group, entry)| (group.as_str(), entry.password().as_str()))
+            .collect();
+
+        assert_eq!(added, [("Github", "456"), ("Reddit", "abc")]);
+        assert_eq!(plan.duplicates, ["github: me", "Reddit: user"]);
+        assert_eq!(plan.skipped, ["Notes: wifi"]);
+        assert_eq!(item("Router", "", "").label(), "Router");
+    }
+
+    #[test]
+    fn imported_entry_fields() {
+        let item = ImportItem {
+            url: Some(String::from("https://example.com")),
+            notes: Some(String::from("notes")),
+            tags: vec![String::from("work")],
+            fields: vec![CustomField::new("pin", "1234", true)],
+            ..item("Example", "me", "123")
+        };
+
+        let (group, entry) = item.into_entry().unwrap();
+        assert_eq!(group, "Example");
+        assert_eq!(entry.url(), Some("https://example.com"));
+        assert_eq!(entry.notes().unwrap().as_str(), "notes");
+        assert_eq!(entry.tags(), ["work"]);
+        assert!(entry.fields()[0].secret);
+    }
+
+    #[test]
+    fn imported_otp() {
+        let imported = ImportItem {
+            otp: Some(String::from("JBSWY3DPEHPK3PXP")),
+            ..item("Example", "me", "123")
+        };
+        let (_, entry) = imported.into_entry().unwrap();
+        assert!(entry.otp().unwrap().starts_with("otpauth://totp/"));
+        assert!(entry.fields().is_empty());
+
+        // kept as a field when it can't be parsed
+        let imported = ImportItem {
+            otp: Some(String::from("steam://ABC")),
+            ..item("Example", "me", "123")
+        };
+        let (_, entry) = imported.into_entry().unwrap();
+        assert_eq!(entry.otp(), None);
+        assert_eq!(entry.fields()[0].value.as_str(), "steam://ABC");
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        assert_eq!(text(b"\xef\xbb\xbfname").unwrap(), "name");
+        assert!(matches!(text(b"\xff"), Err(PwdError::InvalidImportFile(_))));
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::csv::{column, parse_csv, require_column};
+use super::{invalid, non_empty, text, ImportItem, Importer};
+use crate::{error::PwdResult, password::CustomField};
+
+// a 1PUX export is a zip archive, with the items in this file
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const EXPORT_DATA: &str = "export.data";
+
+// the designations of the login fields
+const USERNAME: &str = "username";
+const PASSWORD: &str = "password";
+
+/// 1Password 1PUX archive or CSV export
+pub struct OnePassword;
+
+#[derive(Deserialize)]
+struct Export {
+    accounts: Vec<Account>,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    #[serde(default)]
+    vaults: Vec<Vault>,
+}
+
+#[derive(Deserialize)]
+struct Vault {
+    attrs: VaultAttrs,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Deserialize)]
+struct VaultAttrs {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Item {
+    // "active", "archived" or "trashed"
+    state: Option<String>,
+    overview: Overview,
+    #[serde(default)]
+    details: Details,
+}
+
+#[derive(Deserialize)]
+struct Overview {
+    #[serde(default)]
+    title: String,
+    url: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct Details {
+    #[serde(default)]
+    login_fields: Vec<LoginField>,
+    notes_plain: Option<String>,
+    // the password of the password items
+    password: Option<String>,
+    #[serde(default)]
+    sections: Vec<Section>,
+}
+
+#[derive(Deserialize)]
+struct LoginField {
+    #[serde(default)]
+    value: String,
+    designation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Section {
+    #[serde(default)]
+    fields: Vec<SectionField>,
+}
+
+#[derive(Deserialize)]
+struct SectionField {
+    #[serde(default)]
+    title: String,
+    // a single kind of value, e.g. `{ "concealed": "1234" }`
+    value: HashMap<String, Value>,
+}
+
+impl Importer for OnePassword {
+    fn parse(&self, data: &[u8]) -> PwdResult<Vec<ImportItem>> {
+        if data.starts_with(ZIP_MAGIC) {
+            parse_1pux(data)
+        } else {
+            parse_1password_csv(data)
+        }
+    }
+}
+
+fn parse_1pux(data: &[u8]) -> PwdResult<Vec<ImportItem>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(invalid)?;
+    let mut export_data = String::new();
+    archive
+        .by_name(EXPORT_DATA)
+        .map_err(invalid)?
+        .read_to_string(&mut export_data)
+        .map_err(invalid)?;
+
+    let export: Export = serde_json::from_str(&export_data).map_err(invalid)?;
+
+    let items = export
+        .accounts
+        .into_iter()
+        .flat_map(|account| account.vaults)
+        .flat_map(|vault| {
+            let vault_name = vault.attrs.name;
+            vault
+                .items
+                .into_iter()
+                .filter(|item| item.state.as_deref().unwrap_or("active") == "active")
+                .map(move |item| read_item(item, &vault_name))
+        })
+        .collect();
+
+    Ok(items)
+}
+
+fn read_item(item: Item, vault_name: &str) -> ImportItem {
+    let details = item.details;
+
+    let login_field = |designation: &str| {
+        details
+            .login_fields
+            .iter()
+            .find(|field| field.designation.as_deref() == Some(designation))
+            .map(|field| field.value.clone())
+    };
+    let name = login_field(USERNAME).unwrap_or_default();
+    let password = login_field(PASSWORD)
+        .or_else(|| details.password.clone())
+        .unwrap_or_default();
+
+    let section_fields = details.sections.iter().flat_map(|section| &section.fields);
+    let otp = section_fields
+        .clone()
+        .find_map(|field| field.value.get("totp")?.as_str().and_then(non_empty));
+    let fields = section_fields.filter_map(read_field).collect();
+
+    // the vault is kept as a tag
+    let mut tags = item.overview.tags;
+    tags.push(String::from(vault_name));
+
+    ImportItem {
+        group: item.overview.title,
+        name,
+        password,
+        url: item.overview.url.and_then(non_empty),
+        notes: details.notes_plain.and_then(non_empty),
+        tags,
+        fields,
+        otp,
+    }
+}
+
+/// Read a section field, only the text values are imported
+/// (the one-time password secrets are read separately)
+fn read_field(field: &SectionField) -> Option<CustomField> {
+    let (kind, value) = field.value.iter().next()?;
+    let value = value.as_str().filter(|value| !value.is_empty())?;
+
+    match kind.as_str() {
+        "concealed" => Some(CustomField::new(&field.title, value, true)),
+        "string" | "url" | "email" | "phone" => Some(CustomField::new(&field.title, value, false)),
+        _ => None,
+    }
+}
+
+/// Parse the 1Password CSV export (title, url, username, password, ...)
+fn parse_1password_csv(data: &[u8]) -> PwdResult<Vec<ImportItem>> {
+    let records = parse_csv(text(data)?)?;
+    require_column(&records, "password")?;
+
+    let items = records
+        .iter()
+        .filter(|record| !column(record, &["archived"]).eq_ignore_ascii_case("true"))
+        .map(|record| {
+            let tags = column(record, &["tags"])
+                .split(&[';', ','][..])
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect();
+
+            ImportItem {
+                group: column(record, &["title"]),
+                name: column(record, &["username"]),
+                password: column(record, &["password"]),
+                url: non_empty(column(record, &["url", "website"])),
+                notes: non_empty(column(record, &["notes", "notesplain"])),
+                tags,
+                otp: non_empty(column(record, &["otpauth", "one-time password"])),
+                ..ImportItem::default()
+            }
+        })
+        .collect();
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const EXPORT_DATA_JSON: &str = r#"{
+        "accounts": [{
+            "attrs": { "accountName": "Me" },
+            "vaults": [{
+                "attrs": { "uuid": "v1", "name": "Personal" },
+                "items": [
+                    {
+                        "uuid": "i1",
+                        "state": "active",
+                        "categoryUuid": "001",
+                        "overview": {
+                            "title": "GitHub",
+                            "url": "https://github.com",
+                            "tags": ["work"]
+                        },
+                        "details": {
+                            "loginFields": [
+                                { "value": "me", "name": "login", "designation": "username" },
+                                { "value": "123", "name": "password", "designation": "password" }
+                            ],
+                            "notesPlain": "recovery codes in the safe",
+                            "sections": [{
+                                "title": "",
+                                "fields": [
+                                    { "title": "pin", "id": "f1", "value": { "concealed": "1234" } },
+                                    { "title": "account", "id": "f2", "value": { "string": "42" } },
+                                    { "title": "code", "id": "f3", "value": { "totp": "JBSWY3DPEHPK3PXP" } },
+                                    { "title": "expires", "id": "f4", "value": { "date": 1600000000 } }
+                                ]
+                            }]
+                        }
+                    },
+                    {
+                        "uuid": "i2",
+                        "state": "active",
+                        "categoryUuid": "005",
+                        "overview": { "title": "Router" },
+                        "details": { "password": "abc" }
+                    },
+                    {
+                        "uuid": "i3",
+                        "state": "archived",
+                        "overview": { "title": "Old" },
+                        "details": { "password": "old" }
+                    }
+                ]
+            }]
+        }]
+    }"#;
+
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn onepux() {
+        let data = archive(&[
+            ("export.attributes", r#"{ "version": 3 }"#),
+            (EXPORT_DATA, EXPORT_DATA_JSON),
+        ]);
+        let items = OnePassword.parse(&data).unwrap();
+        // the archived item is not imported
+        assert_eq!(items.len(), 2);
+
+        let item = &items[0];
+        assert_eq!(item.group, "GitHub");
+        assert_eq!(item.name, "me");
+        assert_eq!(item.password, "123");
+        assert_eq!(item.url.as_deref(), Some("https://github.com"));
+        assert_eq!(item.notes.as_deref(), Some("recovery codes in the safe"));
+        assert_eq!(item.tags, ["work", "Personal"]);
+        assert_eq!(
+            item.fields,
+            [
+                CustomField::new("pin", "1234", true),
+                CustomField::new("account", "42", false),
+            ]
+        );
+        assert_eq!(item.otp.as_deref(), Some("JBSWY3DPEHPK3PXP"));
+
+        assert_eq!(items[1].group, "Router");
+        assert_eq!(items[1].password, "abc");
+    }
+
+    #[test]
+    fn onepux_without_export_data() {
+        let data = archive(&[("export.attributes", "{}")]);
+        assert!(OnePassword.parse(&data).is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let csv = "Title,Url,Username,Password,OTPAuth,Favorite,Archived,Tags,Notes\n\
+                   GitHub,https://github.com,me,123,otpauth://totp/me,false,false,work;dev,\n\
+                   Old,,me,old,,false,true,,\n";
+        let items = OnePassword.parse(csv.as_bytes()).unwrap();
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].group, "GitHub");
+        assert_eq!(items[0].password, "123");
+        assert_eq!(items[0].tags, ["work", "dev"]);
+        assert_eq!(items[0].otp.as_deref(), Some("otpauth://totp/me"));
+    }
+}
@@ -1,15 +1,28 @@
 use std::{env, path::Path};
 
+pub mod agent;
+pub mod backup;
 pub mod cli;
 pub mod error;
+pub mod export;
 mod ffi;
 pub mod generator;
+pub mod import;
+pub mod lock;
+pub mod otp;
+pub mod output;
 pub mod password;
+pub mod query;
 pub mod security;
+pub mod shell;
+pub mod ui;
 pub mod vault;
 
 // TODO: proper vault path
-pub const DEFAULT_VAULT_PATH: &'static str = ".local/share/pwdeck/vault.pwd";
+pub const DEFAULT_VAULT_PATH: &str = ".local/share/pwdeck/vault.pwd";
+
+/// The agent socket, when `XDG_RUNTIME_DIR` isn't set
+pub const DEFAULT_AGENT_SOCKET: &str = ".local/share/pwdeck/agent.sock";
 
 pub fn vault_path() -> String {
     if let Ok(path) = env::var("PWDECK_VAULT") {
@@ -23,3 +36,22 @@ pub fn vault_path() -> String {
             .into()
     }
 }
+
+pub fn agent_socket_path() -> String {
+    if let Ok(path) = env::var("PWDECK_AGENT_SOCK") {
+        path
+    } else if let Ok(runtime_dir) = env::var("XDG_RUNTIME_DIR") {
+        Path::new(&runtime_dir)
+            .join("pwdeck-agent.sock")
+            .to_str()
+            .unwrap()
+            .into()
+    } else {
+        let user_home = env::var("HOME").unwrap();
+        Path::new(&user_home)
+            .join(DEFAULT_AGENT_SOCKET)
+            .to_str()
+            .unwrap()
+            .into()
+    }
+}
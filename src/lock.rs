@@ -0,0 +1,150 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    error::{PwdError, PwdResult},
+    vault::sibling_path,
+};
+
+// how often a held lock is retried
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Exclusive advisory lock on a vault.
+///
+/// The lock is taken on a `<vault>.lock` file next to the vault, since
+/// the vault file itself is replaced on every sync. It is held until
+/// the `VaultLock` is dropped.
+pub struct VaultLock {
+    // the lock is released when the file is closed
+    _file: File,
+}
+
+impl VaultLock {
+    /// Try to lock the vault, failing if another process holds the lock
+    pub fn try_acquire<P: AsRef<Path>>(vault_path: P) -> PwdResult<Self> {
+        let file = open_lock_file(&lock_path(vault_path))?;
+        lock_file(&file)?;
+
+        Ok(Self { _file: file })
+    }
+
+    /// Lock the vault, waiting up to `timeout` for another process to release it
+    pub fn acquire<P: AsRef<Path>>(vault_path: P, timeout: Duration) -> PwdResult<Self> {
+        let vault_path = vault_path.as_ref();
+        let start = Instant::now();
+
+        loop {
+            match Self::try_acquire(vault_path) {
+                Err(PwdError::VaultLocked) if start.elapsed() < timeout => {
+                    thread::sleep(RETRY_INTERVAL)
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The path of the lock file of a vault
+pub fn lock_path<P: AsRef<Path>>(vault_path: P) -> PathBuf {
+    sibling_path(vault_path.as_ref(), "lock")
+}
+
+/// Take an exclusive lock on the file, without blocking
+#[cfg(unix)]
+fn lock_file(file: &File) -> PwdResult<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = crate::ffi::LOCK_EX | crate::ffi::LOCK_NB;
+    // SAFETY: the file descriptor is valid while `file` lives
+    if unsafe { crate::ffi::flock(file.as_raw_fd(), operation) } != 0 {
+        let error = io::Error::last_os_error();
+        return match error.kind() {
+            io::ErrorKind::WouldBlock => Err(PwdError::VaultLocked),
+            _ => Err(error.into()),
+        };
+    }
+
+    Ok(())
+}
+
+/// The vault can't be locked without flock, so it isn't opened
+/// rather than risking concurrent writes
+#[cfg(not(unix))]
+fn lock_file(_file: &File) -> PwdResult<()> {
+    let message = "Locking the vault is only supported on unix";
+    Err(io::Error::new(io::ErrorKind::Unsupported, message).into())
+}
+
+/// Open (or create) the lock file, only readable by its owner
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(false);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusive_lock() {
+        const PATH: &str = "target/debug_exclusive.deck";
+
+        let lock = VaultLock::try_acquire(PATH).unwrap();
+        assert!(matches!(
+            VaultLock::try_acquire(PATH),
+            Err(PwdError::VaultLocked)
+        ));
+
+        // the lock is released on drop
+        drop(lock);
+        assert!(VaultLock::try_acquire(PATH).is_ok());
+    }
+
+    #[test]
+    fn lock_timeout() {
+        const PATH: &str = "target/debug_timeout.deck";
+
+        let _lock = VaultLock::try_acquire(PATH).unwrap();
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(300);
+        assert!(matches!(
+            VaultLock::acquire(PATH, timeout),
+            Err(PwdError::VaultLocked)
+        ));
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn wait_for_release() {
+        const PATH: &str = "target/debug_release.deck";
+
+        let lock = VaultLock::try_acquire(PATH).unwrap();
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+
+        assert!(VaultLock::acquire(PATH, Duration::from_secs(5)).is_ok());
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn lock_file_name() {
+        assert_eq!(
+            lock_path("some/dir/vault.pwd"),
+            PathBuf::from("some/dir/vault.pwd.lock")
+        );
+    }
+}
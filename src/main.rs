@@ -6,5 +6,5 @@ extern crate serde;
 use pwdeck::cli::CLI;
 
 fn main() {
-    let _ = CLI::from_args().run();
+    CLI::from_args().run();
 }
@@ -0,0 +1,468 @@
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use crate::{
+    error::{PwdError, PwdResult},
+    security::{SecString, SecVec},
+};
+
+const OTPAUTH_SCHEME: &str = "otpauth://";
+
+// the defaults of the otpauth URIs
+const DEFAULT_DIGITS: u32 = 6;
+const DEFAULT_PERIOD: u64 = 30;
+
+/// The accepted number of digits, the 31 bits
+/// kept from the HMAC hold up to 10 digits
+const MIN_DIGITS: u32 = 6;
+const MAX_DIGITS: u32 = 10;
+
+/// The HMAC hash function of a one-time password
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl OtpAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "SHA1",
+            Self::Sha256 => "SHA256",
+            Self::Sha512 => "SHA512",
+        }
+    }
+}
+
+/// Time based (RFC 6238) or counter based (RFC 4226) one-time passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpKind {
+    /// the period is in seconds
+    Totp { period: u64 },
+    /// the counter of the next code
+    Hotp { counter: u64 },
+}
+
+/// A one-time password generator, as described by an `otpauth://` URI
+pub struct Otp {
+    secret: SecVec<u8>,
+    algorithm: OtpAlgorithm,
+    digits: u32,
+    kind: OtpKind,
+
+    // kept as they were in the URI, they're only written back
+    label: String,
+    issuer: Option<String>,
+}
+
+/// A generated one-time password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtpCode {
+    pub code: String,
+    /// the seconds the TOTP code remains valid
+    pub remaining: Option<u64>,
+    /// the counter the HOTP code was generated with
+    pub counter: Option<u64>,
+}
+
+impl Otp {
+    /// Parse an `otpauth://` URI, or a bare base32 secret
+    /// using the default TOTP params
+    pub fn parse(text: &str) -> PwdResult<Self> {
+        let text = text.trim();
+
+        match strip_prefix_ignore_case(text, OTPAUTH_SCHEME) {
+            Some(uri) => Self::parse_uri(uri),
+            None => Ok(Self {
+                secret: decode_base32(text)?,
+                algorithm: OtpAlgorithm::Sha1,
+                digits: DEFAULT_DIGITS,
+                kind: OtpKind::Totp {
+                    period: DEFAULT_PERIOD,
+                },
+                label: String::new(),
+                issuer: None,
+            }),
+        }
+    }
+
+    /// Parse an URI without its scheme, e.g. `totp/label?secret=...`
+    fn parse_uri(uri: &str) -> PwdResult<Self> {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let (kind, label) = path.split_once('/').unwrap_or((path, ""));
+
+        let mut secret = None;
+        let mut algorithm = OtpAlgorithm::Sha1;
+        let mut digits = DEFAULT_DIGITS;
+        let mut period = DEFAULT_PERIOD;
+        let mut counter = None;
+        let mut issuer = None;
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+
+            match key.to_lowercase().as_str() {
+                "secret" => secret = Some(decode_base32(&percent_decode(value))?),
+                "algorithm" => {
+                    algorithm = match value.to_uppercase().as_str() {
+                        "SHA1" => OtpAlgorithm::Sha1,
+                        "SHA256" => OtpAlgorithm::Sha256,
+                        "SHA512" => OtpAlgorithm::Sha512,
+                        _ => return Err(PwdError::InvalidOtpSecret),
+                    }
+                }
+                "digits" => digits = parse_param(value)?,
+                "period" => period = parse_param(value)?,
+                "counter" => counter = Some(parse_param(value)?),
+                "issuer" => issuer = Some(String::from(value)),
+                // e.g. image
+                _ => {}
+            }
+        }
+
+        let kind = match kind.to_lowercase().as_str() {
+            "totp" if period > 0 => OtpKind::Totp { period },
+            "hotp" => OtpKind::Hotp {
+                counter: counter.unwrap_or(0),
+            },
+            _ => return Err(PwdError::InvalidOtpSecret),
+        };
+
+        if !(MIN_DIGITS..=MAX_DIGITS).contains(&digits) {
+            return Err(PwdError::InvalidOtpSecret);
+        }
+
+        Ok(Self {
+            secret: secret.ok_or(PwdError::InvalidOtpSecret)?,
+            algorithm,
+            digits,
+            kind,
+            label: String::from(label),
+            issuer,
+        })
+    }
+
+    /// The `otpauth://` URI of the generator, as stored in the vault
+    pub fn to_uri(&self) -> SecString {
+        let (kind, moving_factor) = match self.kind {
+            OtpKind::Totp { period } => ("totp", format!("period={}", period)),
+            OtpKind::Hotp { counter } => ("hotp", format!("counter={}", counter)),
+        };
+
+        let mut uri = format!(
+            "{}{}/{}?secret={}&algorithm={}&digits={}&{}",
+            OTPAUTH_SCHEME,
+            kind,
+            self.label,
+            encode_base32(&self.secret),
+            self.algorithm.name(),
+            self.digits,
+            moving_factor
+        );
+        if let Some(issuer) = &self.issuer {
+            uri.push_str("&issuer=");
+            uri.push_str(issuer);
+        }
+
+        uri.into()
+    }
+
+    pub fn algorithm(&self) -> OtpAlgorithm {
+        self.algorithm
+    }
+
+    pub fn digits(&self) -> u32 {
+        self.digits
+    }
+
+    pub fn kind(&self) -> OtpKind {
+        self.kind
+    }
+
+    /// Generate the code for the given unix time. HOTP codes don't
+    /// depend on the time, and the counter is incremented, failing
+    /// once it can't be.
+    pub fn generate(&mut self, time: u64) -> PwdResult<OtpCode> {
+        match self.kind {
+            OtpKind::Totp { period } => Ok(OtpCode {
+                code: self.hotp(time / period),
+                remaining: Some(period - time % period),
+                counter: None,
+            }),
+            OtpKind::Hotp { counter } => {
+                self.kind = OtpKind::Hotp {
+                    counter: counter.checked_add(1).ok_or(PwdError::InvalidOtpSecret)?,
+                };
+
+                Ok(OtpCode {
+                    code: self.hotp(counter),
+                    remaining: None,
+                    counter: Some(counter),
+                })
+            }
+        }
+    }
+
+    /// The HOTP value for the counter (RFC 4226)
+    fn hotp(&self, counter: u64) -> String {
+        let message = counter.to_be_bytes();
+        let hash = match self.algorithm {
+            OtpAlgorithm::Sha1 => hmac::<Hmac<Sha1>>(&self.secret, &message),
+            OtpAlgorithm::Sha256 => hmac::<Hmac<Sha256>>(&self.secret, &message),
+            OtpAlgorithm::Sha512 => hmac::<Hmac<Sha512>>(&self.secret, &message),
+        };
+
+        // dynamic truncation, 31 bits at the offset given by the last byte
+        let offset = (hash[hash.len() - 1] & 0xf) as usize;
+        let bytes = [
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ];
+        let value = u64::from(u32::from_be_bytes(bytes));
+
+        let code = value % 10u64.pow(self.digits);
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+}
+
+fn hmac<M: Mac + KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn parse_param<T: std::str::FromStr>(value: &str) -> PwdResult<T> {
+    value.parse().map_err(|_| PwdError::InvalidOtpSecret)
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    match text.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&text[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// Decode the `%XX` escapes of an URI component
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = value
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Decode a base32 secret (RFC 4648), ignoring the case,
+/// the spaces and the padding
+fn decode_base32(text: &str) -> PwdResult<SecVec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '-') {
+        if c == '=' {
+            break;
+        }
+
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&letter| letter == c.to_ascii_uppercase() as u8)
+            .ok_or(PwdError::InvalidOtpSecret)?;
+
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    if decoded.is_empty() {
+        return Err(PwdError::InvalidOtpSecret);
+    }
+
+    Ok(decoded.into())
+}
+
+/// Encode the secret in base32, without padding
+fn encode_base32(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the RFC 6238 seeds, for each algorithm
+    const SEED_SHA1: &[u8] = b"12345678901234567890";
+    const SEED_SHA256: &[u8] = b"12345678901234567890123456789012";
+    const SEED_SHA512: &[u8] = b"1234567890123456789012345678901234567890123456789012345678901234";
+
+    fn totp_uri(seed: &[u8], algorithm: &str) -> String {
+        format!(
+            "otpauth://totp/test?secret={}&algorithm={}&digits=8&period=30",
+            encode_base32(seed),
+            algorithm
+        )
+    }
+
+    #[test]
+    fn rfc6238_vectors() {
+        // time, SHA1, SHA256 and SHA512 codes
+        let vectors = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+
+        let mut sha1 = Otp::parse(&totp_uri(SEED_SHA1, "SHA1")).unwrap();
+        let mut sha256 = Otp::parse(&totp_uri(SEED_SHA256, "SHA256")).unwrap();
+        let mut sha512 = Otp::parse(&totp_uri(SEED_SHA512, "SHA512")).unwrap();
+
+        for (time, code_sha1, code_sha256, code_sha512) in vectors.iter() {
+            assert_eq!(sha1.generate(*time).unwrap().code, *code_sha1);
+            assert_eq!(sha256.generate(*time).unwrap().code, *code_sha256);
+            assert_eq!(sha512.generate(*time).unwrap().code, *code_sha512);
+        }
+    }
+
+    #[test]
+    fn rfc4226_vectors() {
+        let codes = [
+            "755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583",
+            "399871", "520489",
+        ];
+
+        let uri = format!("otpauth://hotp/test?secret={}", encode_base32(SEED_SHA1));
+        let mut otp = Otp::parse(&uri).unwrap();
+
+        for (counter, code) in codes.iter().enumerate() {
+            let generated = otp.generate(0).unwrap();
+            assert_eq!(generated.code, *code);
+            assert_eq!(generated.counter, Some(counter as u64));
+        }
+        assert_eq!(otp.kind(), OtpKind::Hotp { counter: 10 });
+    }
+
+    #[test]
+    fn exhausted_hotp_counter() {
+        let uri = format!(
+            "otpauth://hotp/test?secret={}&counter={}",
+            encode_base32(SEED_SHA1),
+            u64::MAX - 1
+        );
+        let mut otp = Otp::parse(&uri).unwrap();
+
+        assert_eq!(otp.generate(0).unwrap().counter, Some(u64::MAX - 1));
+        assert!(matches!(otp.generate(0), Err(PwdError::InvalidOtpSecret)));
+        assert_eq!(otp.kind(), OtpKind::Hotp { counter: u64::MAX });
+    }
+
+    #[test]
+    fn remaining_seconds() {
+        let mut otp = Otp::parse("JBSWY3DPEHPK3PXP").unwrap();
+
+        assert_eq!(otp.generate(60).unwrap().remaining, Some(30));
+        assert_eq!(otp.generate(89).unwrap().remaining, Some(1));
+        assert_eq!(
+            otp.generate(89).unwrap().code.len(),
+            DEFAULT_DIGITS as usize
+        );
+    }
+
+    #[test]
+    fn parse_uri() {
+        let uri = "otpauth://totp/ACME%20Co:john@example.com?secret=jbsw%20y3dp ehpk3pxp\
+                   &issuer=ACME%20Co&Algorithm=sha256&digits=7&period=60&image=x";
+        let otp = Otp::parse(uri).unwrap();
+
+        assert_eq!(otp.algorithm(), OtpAlgorithm::Sha256);
+        assert_eq!(otp.digits(), 7);
+        assert_eq!(otp.kind(), OtpKind::Totp { period: 60 });
+        assert_eq!(
+            otp.to_uri().as_str(),
+            "otpauth://totp/ACME%20Co:john@example.com?secret=JBSWY3DPEHPK3PXP\
+             &algorithm=SHA256&digits=7&period=60&issuer=ACME%20Co"
+        );
+
+        // the URI written back is parsed the same
+        let reparsed = Otp::parse(&otp.to_uri()).unwrap();
+        assert_eq!(reparsed.to_uri(), otp.to_uri());
+    }
+
+    #[test]
+    fn invalid_secrets() {
+        let invalid = [
+            "",
+            "not base32!",
+            "otpauth://totp/test",
+            "otpauth://totp/test?secret=JBSWY3DP&algorithm=MD5",
+            "otpauth://totp/test?secret=JBSWY3DP&digits=4",
+            "otpauth://totp/test?secret=JBSWY3DP&period=0",
+            "otpauth://motp/test?secret=JBSWY3DP",
+        ];
+
+        for text in invalid.iter() {
+            assert!(
+                matches!(Otp::parse(text), Err(PwdError::InvalidOtpSecret)),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn base32() {
+        assert_eq!(encode_base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(
+            decode_base32("MZXW6YTBOI======").unwrap().as_slice(),
+            b"foobar"
+        );
+        assert_eq!(decode_base32("mzxw 6ytb oi").unwrap().as_slice(), b"foobar");
+    }
+}
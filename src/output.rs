@@ -0,0 +1,334 @@
+use serde::Serialize;
+
+use crate::{otp::OtpCode, password::Entry};
+
+/// The version of the JSON output schema, documented in the README.
+/// Fields may be added, but existing ones are never renamed or removed
+/// without bumping it.
+pub const OUTPUT_VERSION: u32 = 1;
+
+// the output model of the read commands (get, find and history),
+// rendered as JSON with `--format json`
+
+#[derive(Serialize, Debug)]
+/// A custom field of an entry
+pub struct FieldOutput<'a> {
+    pub name: &'a str,
+    /// `None` for hidden secret fields
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<&'a str>,
+    pub secret: bool,
+}
+
+#[derive(Serialize, Debug)]
+/// A vault entry, with its secrets only when they are requested
+pub struct EntryOutput<'a> {
+    pub id: &'a str,
+    pub group: &'a str,
+    pub name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<&'a str>,
+    pub tags: &'a [String],
+    pub fields: Vec<FieldOutput<'a>>,
+    pub has_otp: bool,
+    /// the `otpauth://` URI, only with the secrets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otp: Option<&'a str>,
+    /// unix timestamps, zero when unknown
+    pub created_at: u64,
+    pub updated_at: u64,
+    /// the fuzzy match score, for search results
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+
+    #[serde(skip)]
+    entry: &'a Entry,
+}
+
+impl<'a> EntryOutput<'a> {
+    /// The entry without its password and secret fields
+    pub fn new(group: &'a str, entry: &'a Entry) -> Self {
+        let fields = entry
+            .fields()
+            .iter()
+            .map(|field| FieldOutput {
+                name: &field.name,
+                value: if field.secret {
+                    None
+                } else {
+                    Some(field.value.as_str())
+                },
+                secret: field.secret,
+            })
+            .collect();
+
+        Self {
+            id: entry.id(),
+            group,
+            name: entry.name(),
+            password: None,
+            url: entry.url(),
+            notes: entry.notes().map(|notes| notes.as_str()),
+            tags: entry.tags(),
+            fields,
+            has_otp: entry.otp().is_some(),
+            otp: None,
+            created_at: entry.created_at(),
+            updated_at: entry.updated_at(),
+            score: None,
+
+            entry,
+        }
+    }
+
+    /// Include the password
+    pub fn with_password(mut self) -> Self {
+        self.password = Some(self.entry.password().as_str());
+        self
+    }
+
+    /// Include the password, the values of the secret fields and the OTP secret
+    pub fn with_secrets(mut self) -> Self {
+        for (output, field) in self.fields.iter_mut().zip(self.entry.fields()) {
+            output.value = Some(field.value.as_str());
+        }
+        self.otp = self.entry.otp().map(|otp| otp.as_str());
+        self.with_password()
+    }
+
+    /// Add the fuzzy match score
+    pub fn with_score(mut self, score: f64) -> Self {
+        self.score = Some(score);
+        self
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A list of entries, as printed by `get` and `find`
+pub struct EntriesOutput<'a> {
+    pub version: u32,
+    pub entries: Vec<EntryOutput<'a>>,
+}
+
+impl<'a> EntriesOutput<'a> {
+    pub fn new(entries: Vec<EntryOutput<'a>>) -> Self {
+        Self {
+            version: OUTPUT_VERSION,
+            entries,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        to_json(self)
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A previous password of an entry
+pub struct HistoryItemOutput<'a> {
+    /// the number used by `history --restore`, starting at 1
+    pub number: usize,
+    /// unix timestamp of when the password was replaced
+    pub replaced_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug)]
+/// The password history of an entry, as printed by `history`
+pub struct HistoryOutput<'a> {
+    pub version: u32,
+    pub entry: EntryOutput<'a>,
+    pub history: Vec<HistoryItemOutput<'a>>,
+    /// the versions that lost a merge conflict, most recent first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub discarded: Vec<EntryOutput<'a>>,
+}
+
+impl<'a> HistoryOutput<'a> {
+    /// The history of the entry, with the previous passwords only if `reveal` is set
+    pub fn new(group: &'a str, entry: &'a Entry, reveal: bool) -> Self {
+        let history = entry
+            .history()
+            .iter()
+            .enumerate()
+            .map(|(i, (replaced_at, password))| HistoryItemOutput {
+                number: i + 1,
+                replaced_at: *replaced_at,
+                password: if reveal {
+                    Some(password.as_str())
+                } else {
+                    None
+                },
+            })
+            .collect();
+
+        let discarded = entry
+            .discarded()
+            .iter()
+            .map(|version| {
+                let output = EntryOutput::new(group, version);
+                if reveal {
+                    output.with_secrets()
+                } else {
+                    output
+                }
+            })
+            .collect();
+
+        Self {
+            version: OUTPUT_VERSION,
+            entry: EntryOutput::new(group, entry),
+            history,
+            discarded,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        to_json(self)
+    }
+}
+
+#[derive(Serialize, Debug)]
+/// A one-time password, as printed by `otp`
+pub struct OtpOutput<'a> {
+    pub version: u32,
+    pub code: &'a str,
+    /// the seconds the TOTP code remains valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+    /// the counter of the HOTP code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counter: Option<u64>,
+}
+
+impl<'a> OtpOutput<'a> {
+    pub fn new(otp: &'a OtpCode) -> Self {
+        Self {
+            version: OUTPUT_VERSION,
+            code: &otp.code,
+            remaining: otp.remaining,
+            counter: otp.counter,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        to_json(self)
+    }
+}
+
+fn to_json<T: Serialize>(output: &T) -> String {
+    // the output model only holds strings, numbers and lists
+    serde_json::to_string_pretty(output).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::password::{CustomField, EntryUpdate};
+    use crate::security::SecString;
+
+    fn test_entry() -> Entry {
+        let mut entry = Entry::new("user", "first");
+        let update = EntryUpdate {
+            password: Some(SecString::from("second")),
+            url: Some(String::from("https://example.com")),
+            fields: vec![
+                CustomField::new("pin", "1234", true),
+                CustomField::new("account", "42", false),
+            ],
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+
+        entry
+    }
+
+    #[test]
+    fn entries_schema() {
+        let entry = test_entry();
+        let output = EntriesOutput::new(vec![EntryOutput::new("Test", &entry)]);
+        let json: Value = serde_json::from_str(&output.to_json()).unwrap();
+
+        assert_eq!(json["version"], OUTPUT_VERSION);
+        let entry_json = &json["entries"][0];
+        assert_eq!(entry_json["id"], entry.id());
+        assert_eq!(entry_json["group"], "Test");
+        assert_eq!(entry_json["name"], "user");
+        assert_eq!(entry_json["url"], "https://example.com");
+        assert_eq!(entry_json["created_at"], entry.created_at());
+
+        // the secrets are hidden by default
+        assert!(entry_json.get("password").is_none());
+        assert!(entry_json["fields"][0].get("value").is_none());
+        assert_eq!(entry_json["fields"][0]["secret"], true);
+        assert_eq!(entry_json["fields"][1]["value"], "42");
+    }
+
+    #[test]
+    fn revealed_secrets() {
+        let entry = test_entry();
+
+        let output = EntryOutput::new("Test", &entry).with_password();
+        assert_eq!(output.password, Some("second"));
+        assert_eq!(output.fields[0].value, None);
+
+        let output = EntryOutput::new("Test", &entry).with_secrets();
+        assert_eq!(output.fields[0].value, Some("1234"));
+    }
+
+    #[test]
+    fn otp_secret() {
+        let mut entry = test_entry();
+        assert!(!EntryOutput::new("Test", &entry).has_otp);
+
+        let uri = "otpauth://totp/test?secret=JBSWY3DPEHPK3PXP";
+        let update = EntryUpdate {
+            otp: Some(SecString::from(uri)),
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+
+        let output = EntryOutput::new("Test", &entry);
+        assert!(output.has_otp);
+        assert_eq!(output.otp, None);
+        assert_eq!(output.with_secrets().otp, Some(uri));
+    }
+
+    #[test]
+    fn otp_schema() {
+        let otp = OtpCode {
+            code: String::from("123456"),
+            remaining: Some(12),
+            counter: None,
+        };
+        let json: Value = serde_json::from_str(&OtpOutput::new(&otp).to_json()).unwrap();
+
+        assert_eq!(json["version"], OUTPUT_VERSION);
+        assert_eq!(json["code"], "123456");
+        assert_eq!(json["remaining"], 12);
+        assert!(json.get("counter").is_none());
+    }
+
+    #[test]
+    fn history_schema() {
+        let entry = test_entry();
+
+        let output = HistoryOutput::new("Test", &entry, false);
+        let json: Value = serde_json::from_str(&output.to_json()).unwrap();
+        assert_eq!(json["entry"]["id"], entry.id());
+        assert_eq!(json["history"][0]["number"], 1);
+        assert!(json["history"][0].get("password").is_none());
+
+        assert!(json.get("discarded").is_none());
+
+        let output = HistoryOutput::new("Test", &entry, true);
+        assert_eq!(output.history[0].password, Some("first"));
+    }
+}
@@ -1,3 +1,6 @@
+use std::cmp::Reverse;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
 pub use crate::generator::Generator;
@@ -8,6 +11,22 @@ use crate::security::SecString;
 pub enum PasswordError {
     Unknown,
     EmptyPassword,
+    /// the generator rules can't be satisfied
+    InvalidPolicy(String),
+}
+
+/// How many previous passwords are kept in the entry history
+pub const HISTORY_LIMIT: usize = 10;
+
+/// How many previous revisions are kept to prove the ancestry of an entry
+const ANCESTRY_LIMIT: usize = 64;
+
+/// The current time, in seconds since the unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
 }
 
 // TODO: maybe move to vault module as a vault entry
@@ -17,6 +36,61 @@ pub struct Entry {
     id: String,
     name: String,
     password: SecString,
+
+    // the fields below were added later, vaults
+    // written before them use the defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notes: Option<SecString>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<CustomField>,
+    /// the TOTP/HOTP secret, as an `otpauth://` URI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    otp: Option<SecString>,
+
+    // unix timestamps, zero when unknown
+    #[serde(default)]
+    created_at: u64,
+    #[serde(default)]
+    updated_at: u64,
+    /// the previous passwords, most recent first, along with the time they were replaced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<(u64, SecString)>,
+
+    /// a random ID of the version of the entry, changed on each
+    /// modification, empty for the entries written before it
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    revision: String,
+    /// the revisions this version descends from, most recent first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ancestors: Vec<String>,
+    /// the versions that lost a merge conflict, most recent first,
+    /// without their own history
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    discarded: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// A custom key/value field of an entry
+pub struct CustomField {
+    pub name: String,
+    pub value: SecString,
+    /// secret fields (PINs, security answers, ...) are hidden by default
+    #[serde(default)]
+    pub secret: bool,
+}
+
+impl CustomField {
+    pub fn new(name: &str, value: &str, secret: bool) -> Self {
+        Self {
+            name: String::from(name),
+            value: SecString::from(value),
+            secret,
+        }
+    }
 }
 
 #[allow(dead_code)] // suppress warnings for now
@@ -26,11 +100,26 @@ impl Entry {
 
         let name = String::from(name);
         let password = SecString::from(password);
+        let created_at = now();
 
         Self {
             id,
             name,
             password,
+
+            url: None,
+            notes: None,
+            tags: Vec::new(),
+            fields: Vec::new(),
+            otp: None,
+
+            created_at,
+            updated_at: created_at,
+            history: Vec::new(),
+
+            revision: nanoid::nanoid!(),
+            ancestors: Vec::new(),
+            discarded: Vec::new(),
         }
     }
     /// Get the entry id
@@ -45,4 +134,427 @@ impl Entry {
     pub(crate) fn password(&self) -> &SecString {
         &self.password
     }
+    /// Get the entry login URL
+    pub(crate) fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+    /// Get the entry notes
+    pub(crate) fn notes(&self) -> Option<&SecString> {
+        self.notes.as_ref()
+    }
+    /// Get the entry tags
+    pub(crate) fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    /// Get the entry custom fields
+    pub(crate) fn fields(&self) -> &[CustomField] {
+        &self.fields
+    }
+    /// Get the entry OTP secret, as an `otpauth://` URI
+    pub(crate) fn otp(&self) -> Option<&SecString> {
+        self.otp.as_ref()
+    }
+    /// Get the entry creation time
+    pub(crate) fn created_at(&self) -> u64 {
+        self.created_at
+    }
+    /// Get the entry last modification time
+    pub(crate) fn updated_at(&self) -> u64 {
+        self.updated_at
+    }
+    /// Get the previous passwords, most recent first
+    pub(crate) fn history(&self) -> &[(u64, SecString)] {
+        &self.history
+    }
+    /// Get the versions that lost a merge conflict, most recent first
+    pub(crate) fn discarded(&self) -> &[Entry] {
+        &self.discarded
+    }
+    /// Mark the entry as modified, starting a new revision
+    pub(crate) fn touch(&mut self) {
+        self.updated_at = now();
+
+        let previous = std::mem::replace(&mut self.revision, nanoid::nanoid!());
+        if !previous.is_empty() {
+            self.ancestors.insert(0, previous);
+            self.ancestors.truncate(ANCESTRY_LIMIT);
+        }
+    }
+    /// Apply the changes to the entry
+    pub(crate) fn update(&mut self, update: EntryUpdate) -> Result<(), PasswordError> {
+        if let Some(password) = &update.password {
+            if password.is_empty() {
+                return Err(PasswordError::EmptyPassword);
+            }
+        }
+
+        let before = self.clone();
+
+        if let Some(name) = update.name {
+            self.name = name;
+        }
+        if let Some(password) = update.password {
+            if password != self.password {
+                // keep the replaced password in the history
+                let previous = std::mem::replace(&mut self.password, password);
+                self.history.insert(0, (now(), previous));
+                self.history.truncate(HISTORY_LIMIT);
+            }
+        }
+        // an empty value clears the field
+        if let Some(url) = update.url {
+            self.url = Some(url).filter(|url| !url.is_empty());
+        }
+        if let Some(notes) = update.notes {
+            self.notes = Some(notes).filter(|notes| !notes.is_empty());
+        }
+        if let Some(tags) = update.tags {
+            self.tags = tags;
+        }
+        if let Some(otp) = update.otp {
+            self.otp = Some(otp).filter(|otp| !otp.is_empty());
+        }
+
+        let remove_fields = update.remove_fields;
+        self.fields
+            .retain(|field| !remove_fields.contains(&field.name));
+        for field in update.fields {
+            // replace the fields with the same name
+            match self.fields.iter_mut().find(|f| f.name == field.name) {
+                Some(existing) => *existing = field,
+                None => self.fields.push(field),
+            }
+        }
+
+        // an update changing nothing keeps the entry as it was
+        if *self != before {
+            self.touch();
+        }
+
+        Ok(())
+    }
+    /// Make the password at `index` in the history the current one,
+    /// the replaced password is moved to the history
+    pub(crate) fn restore_password(&mut self, index: usize) -> Option<()> {
+        if index >= self.history.len() {
+            return None;
+        }
+
+        let (_, password) = self.history.remove(index);
+        let update = EntryUpdate {
+            password: Some(password),
+            ..EntryUpdate::default()
+        };
+        // the history never holds empty passwords
+        self.update(update).ok()
+    }
+
+    /// Whether this entry is a later version of `older`: the revision of
+    /// `older` is its current or a previous one. Entries written before the
+    /// revisions fall back to the password history: this entry is at least
+    /// as recent, the password of `older` is its current or a previous one,
+    /// and all the other values are the same.
+    pub(crate) fn supersedes(&self, older: &Entry) -> bool {
+        if !older.revision.is_empty() {
+            return self.revision == older.revision || self.ancestors.contains(&older.revision);
+        }
+
+        self.updated_at >= older.updated_at
+            && self.same_details(older)
+            && (self.password == older.password
+                || self
+                    .history
+                    .iter()
+                    .any(|(_, password)| *password == older.password))
+    }
+
+    /// Whether the values other than the password are the same
+    pub(crate) fn same_details(&self, other: &Entry) -> bool {
+        self.differences(other).is_empty()
+    }
+
+    /// The names of the values, other than the password, that differ
+    pub(crate) fn differences(&self, other: &Entry) -> Vec<&'static str> {
+        let differences = [
+            ("name", self.name != other.name),
+            ("url", self.url != other.url),
+            ("notes", self.notes != other.notes),
+            ("tags", self.tags != other.tags),
+            ("fields", self.fields != other.fields),
+            ("otp", self.otp != other.otp),
+        ];
+
+        differences
+            .iter()
+            .filter(|(_, differ)| *differ)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Keep another version of the entry that lost a merge conflict,
+    /// when its values other than the password differ. Its password
+    /// is kept by `merge_history`.
+    pub(crate) fn keep_discarded(&mut self, loser: &Entry) {
+        if self.same_details(loser) {
+            return;
+        }
+
+        let snapshot = Entry {
+            history: Vec::new(),
+            ancestors: Vec::new(),
+            discarded: Vec::new(),
+            ..loser.clone()
+        };
+        self.discarded.insert(0, snapshot);
+        self.discarded.truncate(HISTORY_LIMIT);
+    }
+
+    /// Add the password, the history and the revisions of another
+    /// version of the entry to this one, e.g. when merging vaults
+    pub(crate) fn merge_history(&mut self, other: &Entry) {
+        let replaced = std::iter::once((other.updated_at, other.password.clone()));
+
+        for (time, password) in replaced.chain(other.history.iter().cloned()) {
+            let known = password == self.password
+                || self
+                    .history
+                    .iter()
+                    .any(|(_, previous)| *previous == password);
+            if !known {
+                self.history.push((time, password));
+            }
+        }
+
+        // most recent first
+        self.history.sort_by_key(|(time, _)| Reverse(*time));
+        self.history.truncate(HISTORY_LIMIT);
+
+        // this version now descends from the other one
+        let revisions = std::iter::once(&other.revision).chain(other.ancestors.iter());
+        for revision in revisions {
+            let known = revision.is_empty()
+                || *revision == self.revision
+                || self.ancestors.contains(revision);
+            if !known {
+                self.ancestors.push(revision.clone());
+            }
+        }
+        self.ancestors.truncate(ANCESTRY_LIMIT);
+
+        for discarded in &other.discarded {
+            if !self.discarded.contains(discarded) {
+                self.discarded.push(discarded.clone());
+            }
+        }
+        self.discarded
+            .sort_by_key(|entry| Reverse(entry.updated_at));
+        self.discarded.truncate(HISTORY_LIMIT);
+    }
+}
+
+/// Changes to apply to an entry, the fields set to `None` are kept
+#[derive(Debug, Default, Clone)]
+pub struct EntryUpdate {
+    pub name: Option<String>,
+    pub password: Option<SecString>,
+    /// an empty URL clears it
+    pub url: Option<String>,
+    /// empty notes clear them
+    pub notes: Option<SecString>,
+    /// replaces all the tags
+    pub tags: Option<Vec<String>>,
+    /// custom fields to add, or to replace if one has the same name
+    pub fields: Vec<CustomField>,
+    /// names of the custom fields to remove
+    pub remove_fields: Vec<String>,
+    /// an `otpauth://` URI, an empty one clears the OTP secret
+    pub otp: Option<SecString>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_entry_format() {
+        let json = r#"{"id":"V1StGXR8_Z5jdHi6B-myT","name":"user","password":"123"}"#;
+        let entry: Entry = serde_json::from_str(json).unwrap();
+
+        assert_eq!(entry.name(), "user");
+        assert_eq!(entry.url(), None);
+        assert!(entry.notes().is_none());
+        assert!(entry.tags().is_empty());
+        assert!(entry.fields().is_empty());
+        assert_eq!(entry.created_at(), 0);
+        assert!(entry.history().is_empty());
+    }
+
+    #[test]
+    fn rich_entry_roundtrip() {
+        let mut entry = Entry::new("user", "123");
+        let update = EntryUpdate {
+            url: Some(String::from("https://example.com/login")),
+            notes: Some(SecString::from("recovery codes in the safe")),
+            tags: Some(vec![String::from("work")]),
+            fields: vec![
+                CustomField::new("pin", "1234", true),
+                CustomField::new("account", "42", false),
+            ],
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+
+        let json = serde_json::to_string(&entry).unwrap();
+        let decoded: Entry = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, entry);
+        assert!(decoded.fields()[0].secret);
+    }
+
+    #[test]
+    fn update_fields() {
+        let mut entry = Entry::new("user", "123");
+        let update = EntryUpdate {
+            url: Some(String::from("https://example.com")),
+            fields: vec![
+                CustomField::new("pin", "1234", true),
+                CustomField::new("account", "42", false),
+            ],
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+
+        let update = EntryUpdate {
+            url: Some(String::new()),
+            fields: vec![CustomField::new("pin", "4321", true)],
+            remove_fields: vec![String::from("account")],
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+
+        assert_eq!(entry.url(), None);
+        assert_eq!(entry.fields().len(), 1);
+        assert_eq!(entry.fields()[0].value.as_str(), "4321");
+    }
+
+    fn change_password(entry: &mut Entry, password: &str) {
+        let update = EntryUpdate {
+            password: Some(SecString::from(password)),
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+    }
+
+    #[test]
+    fn empty_update() {
+        let mut entry = Entry::new("user", "123");
+        let updated_at = entry.updated_at;
+        let revision = entry.revision.clone();
+
+        // the same values, nothing changes
+        let update = EntryUpdate {
+            name: Some(String::from("user")),
+            password: Some(SecString::from("123")),
+            url: Some(String::new()),
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+        entry.update(EntryUpdate::default()).unwrap();
+
+        assert_eq!(entry.updated_at, updated_at);
+        assert_eq!(entry.revision, revision);
+        assert!(entry.history().is_empty());
+    }
+
+    #[test]
+    fn password_history() {
+        let mut entry = Entry::new("user", "first");
+        assert!(entry.created_at() > 0);
+        assert_eq!(entry.created_at(), entry.updated_at());
+
+        change_password(&mut entry, "second");
+        // the same password isn't added to the history
+        change_password(&mut entry, "second");
+        change_password(&mut entry, "third");
+
+        let history: Vec<&str> = entry.history().iter().map(|(_, p)| p.as_str()).collect();
+        assert_eq!(history, ["second", "first"]);
+    }
+
+    #[test]
+    fn bounded_history() {
+        let mut entry = Entry::new("user", "0");
+        for i in 1..=HISTORY_LIMIT + 5 {
+            change_password(&mut entry, &i.to_string());
+        }
+
+        assert_eq!(entry.history().len(), HISTORY_LIMIT);
+        // the oldest passwords are dropped
+        assert_eq!(entry.history()[HISTORY_LIMIT - 1].1.as_str(), "5");
+    }
+
+    #[test]
+    fn restore_password() {
+        let mut entry = Entry::new("user", "first");
+        change_password(&mut entry, "second");
+
+        entry.restore_password(0).unwrap();
+        assert_eq!(entry.password().as_str(), "first");
+        assert_eq!(entry.history().len(), 1);
+        assert_eq!(entry.history()[0].1.as_str(), "second");
+
+        assert!(entry.restore_password(1).is_none());
+    }
+
+    #[test]
+    fn superseding_entry() {
+        let older = Entry::new("user", "first");
+        let mut newer = older.clone();
+        change_password(&mut newer, "second");
+
+        assert!(newer.supersedes(&older));
+        assert!(!older.supersedes(&newer));
+
+        // changed on both sides
+        let mut other = older.clone();
+        change_password(&mut other, "other");
+        assert!(!newer.supersedes(&other));
+        assert!(!other.supersedes(&newer));
+    }
+
+    #[test]
+    fn merged_history() {
+        let mut entry = Entry::new("user", "first");
+        let mut other = entry.clone();
+        change_password(&mut entry, "second");
+        change_password(&mut other, "other");
+
+        entry.merge_history(&other);
+        let mut history: Vec<&str> = entry.history().iter().map(|(_, p)| p.as_str()).collect();
+        history.sort_unstable();
+        // "first" is in both histories, and only kept once
+        assert_eq!(history, ["first", "other"]);
+
+        // and the entry now descends from the other one
+        assert!(entry.supersedes(&other));
+        assert!(!other.supersedes(&entry));
+    }
+
+    #[test]
+    fn supersedes_without_revisions() {
+        // written before the revisions
+        let json = r#"{"id":"V1StGXR8_Z5jdHi6B-myT","name":"user","password":"123"}"#;
+        let older: Entry = serde_json::from_str(json).unwrap();
+
+        let mut newer = older.clone();
+        change_password(&mut newer, "456");
+        assert!(newer.supersedes(&older));
+
+        // the history only proves the ancestry of the password
+        let update = EntryUpdate {
+            url: Some(String::from("https://example.com")),
+            ..EntryUpdate::default()
+        };
+        newer.update(update).unwrap();
+        assert!(!newer.supersedes(&older));
+    }
 }
@@ -0,0 +1,410 @@
+use std::cmp::Ordering;
+
+use crate::password::Entry;
+
+/// The fuzzy score from which a match is considered confident
+pub const CONFIDENT_SCORE: f64 = 0.6;
+
+/// The minimum similarity between the query and a mistyped word,
+/// from 0 (nothing in common) to 1 (same word)
+const MIN_SIMILARITY: f64 = 0.6;
+
+// fuzzy scores for the different kinds of matches
+const EXACT_SCORE: f64 = 1.0;
+const EXACT_WORD_SCORE: f64 = 0.95;
+const PREFIX_SCORE: f64 = 0.9;
+const WORD_PREFIX_SCORE: f64 = 0.85;
+const SUBSTRING_SCORE: f64 = 0.8;
+const TYPO_SCORE: f64 = 0.75;
+
+// URLs and tags describe the entry less directly than its group and name
+const SECONDARY_FIELD_WEIGHT: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How a pattern is compared with a value
+pub enum MatchKind {
+    /// the whole value must be equal to the pattern
+    Exact,
+    /// the value must contain the pattern
+    Substring,
+    /// the whole value must match the pattern, where `*` matches
+    /// any sequence of characters and `?` matches a single character
+    Glob,
+}
+
+#[derive(Debug, Clone)]
+/// A pattern matching a group or an entry name
+pub struct Pattern {
+    text: String,
+    kind: MatchKind,
+    case_sensitive: bool,
+}
+
+impl Pattern {
+    pub fn new(text: &str, kind: MatchKind) -> Self {
+        Self {
+            text: String::from(text),
+            kind,
+            case_sensitive: true,
+        }
+    }
+
+    pub fn exact(text: &str) -> Self {
+        Self::new(text, MatchKind::Exact)
+    }
+
+    pub fn substring(text: &str) -> Self {
+        Self::new(text, MatchKind::Substring)
+    }
+
+    pub fn glob(text: &str) -> Self {
+        Self::new(text, MatchKind::Glob)
+    }
+
+    /// Ignore the case when matching
+    pub fn case_insensitive(mut self) -> Self {
+        self.case_sensitive = false;
+        self
+    }
+
+    /// Check if the value matches the pattern
+    pub fn matches(&self, value: &str) -> bool {
+        if self.case_sensitive {
+            self.matches_with_case(&self.text, value)
+        } else {
+            self.matches_with_case(&self.text.to_lowercase(), &value.to_lowercase())
+        }
+    }
+
+    fn matches_with_case(&self, pattern: &str, value: &str) -> bool {
+        match self.kind {
+            MatchKind::Exact => value == pattern,
+            MatchKind::Substring => value.contains(pattern),
+            MatchKind::Glob => {
+                let pattern: Vec<char> = pattern.chars().collect();
+                let value: Vec<char> = value.chars().collect();
+                glob_match(&pattern, &value)
+            }
+        }
+    }
+}
+
+/// Match a glob pattern, backtracking to the last `*` on mismatches
+fn glob_match(pattern: &[char], value: &[char]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    // the position of the last `*` and the value position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match star {
+                // let the last `*` match one more character
+                Some((star_p, star_v)) => {
+                    star = Some((star_p, star_v + 1));
+                    p = star_p + 1;
+                    v = star_v + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    // only trailing `*` can match the empty rest
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Debug, Clone, Default)]
+/// Filters the vault entries, the patterns not set match everything
+pub struct Query {
+    pub group: Option<Pattern>,
+    pub name: Option<Pattern>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match the entries in the groups matching the pattern
+    pub fn group(mut self, pattern: Pattern) -> Self {
+        self.group = Some(pattern);
+        self
+    }
+
+    /// Only match the entries whose name matches the pattern
+    pub fn name(mut self, pattern: Pattern) -> Self {
+        self.name = Some(pattern);
+        self
+    }
+
+    /// Check if the entry in the given group matches the query
+    pub fn matches(&self, group: &str, entry: &Entry) -> bool {
+        let group_matches = self.group.as_ref().is_none_or(|p| p.matches(group));
+        let name_matches = self.name.as_ref().is_none_or(|p| p.matches(entry.name()));
+
+        group_matches && name_matches
+    }
+}
+
+#[derive(Debug, Clone)]
+/// An entry matching a fuzzy query
+pub struct FuzzyMatch<'a> {
+    pub group: &'a str,
+    pub entry: &'a Entry,
+    /// from 0 (no match) to 1 (exact match)
+    pub score: f64,
+}
+
+impl FuzzyMatch<'_> {
+    /// Check if the match is good enough to be picked without confirmation
+    pub fn is_confident(&self) -> bool {
+        self.score >= CONFIDENT_SCORE
+    }
+}
+
+/// Sort the matches from the best to the worst
+pub(crate) fn rank(matches: &mut [FuzzyMatch]) {
+    matches.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.group.cmp(b.group))
+            .then_with(|| a.entry.name().cmp(b.entry.name()))
+    });
+}
+
+/// Score how well the entry matches the query,
+/// on its group, name, URL and tags
+pub fn fuzzy_entry_score(query: &str, group: &str, entry: &Entry) -> f64 {
+    let secondary = entry
+        .url()
+        .into_iter()
+        .chain(entry.tags().iter().map(String::as_str))
+        .map(|value| fuzzy_score(query, value) * SECONDARY_FIELD_WEIGHT);
+
+    [fuzzy_score(query, group), fuzzy_score(query, entry.name())]
+        .iter()
+        .copied()
+        .chain(secondary)
+        .fold(0.0, f64::max)
+}
+
+/// Score how well the value matches the query, ignoring the case.
+///
+/// Exact matches score 1, prefixes and substrings a bit less, and
+/// mistyped words (e.g. "gihtub" for "github") score less the more
+/// edits they need. Values that don't match at all score 0.
+pub fn fuzzy_score(query: &str, value: &str) -> f64 {
+    let query = query.to_lowercase();
+    let value = value.to_lowercase();
+
+    if query.is_empty() {
+        return 0.0;
+    }
+
+    // the words of the value, e.g. the host parts of an URL
+    let words: Vec<&str> = value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    if value == query {
+        EXACT_SCORE
+    } else if words.contains(&query.as_str()) {
+        EXACT_WORD_SCORE
+    } else if value.starts_with(&query) {
+        PREFIX_SCORE
+    } else if words.iter().any(|word| word.starts_with(&query)) {
+        WORD_PREFIX_SCORE
+    } else if value.contains(&query) {
+        SUBSTRING_SCORE
+    } else {
+        let similarity = words
+            .iter()
+            .copied()
+            .chain(std::iter::once(value.as_str()))
+            .map(|word| similarity(&query, word))
+            .fold(0.0, f64::max);
+
+        if similarity >= MIN_SIMILARITY {
+            TYPO_SCORE * similarity
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The similarity of two words, from the edit distance between them
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    1.0 - edit_distance(&a, &b) as f64 / longest as f64
+}
+
+/// The optimal string alignment distance: the number of insertions,
+/// deletions, substitutions and transpositions of adjacent characters
+/// needed to turn `a` into `b`
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    // distances[i][j] is the distance between a[..i] and b[..j]
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        let pattern = Pattern::exact("GitHub");
+
+        assert!(pattern.matches("GitHub"));
+        assert!(!pattern.matches("github"));
+        assert!(!pattern.matches("GitHub Enterprise"));
+    }
+
+    #[test]
+    fn substring() {
+        let pattern = Pattern::substring("Hub");
+
+        assert!(pattern.matches("GitHub"));
+        assert!(pattern.matches("Hub"));
+        assert!(!pattern.matches("hub"));
+    }
+
+    #[test]
+    fn glob() {
+        let pattern = Pattern::glob("*@*.com");
+        assert!(pattern.matches("foo@email.com"));
+        assert!(!pattern.matches("foo@email.org"));
+        assert!(!pattern.matches("foo.com"));
+
+        let pattern = Pattern::glob("user?");
+        assert!(pattern.matches("user1"));
+        assert!(!pattern.matches("user"));
+        assert!(!pattern.matches("user12"));
+
+        assert!(Pattern::glob("*").matches(""));
+        assert!(Pattern::glob("a*b*c").matches("abbbcbc"));
+        assert!(!Pattern::glob("a*b*c").matches("abbbcb"));
+        // non-ascii characters are matched as a whole
+        assert!(Pattern::glob("caf?").matches("café"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let exact = Pattern::exact("GitHub").case_insensitive();
+        let substring = Pattern::substring("HUB").case_insensitive();
+        let glob = Pattern::glob("g*B").case_insensitive();
+
+        assert!(exact.matches("github"));
+        assert!(substring.matches("GitHub"));
+        assert!(glob.matches("GitHub"));
+    }
+
+    #[test]
+    fn query() {
+        let entry = Entry::new("foo@email.com", "123");
+
+        assert!(Query::new().matches("Github", &entry));
+
+        let query = Query::new()
+            .group(Pattern::exact("Github"))
+            .name(Pattern::substring("foo"));
+        assert!(query.matches("Github", &entry));
+        assert!(!query.matches("Gitlab", &entry));
+
+        let query = Query::new().name(Pattern::substring("bar"));
+        assert!(!query.matches("Github", &entry));
+    }
+
+    #[test]
+    fn edit_distances() {
+        let distance = |a: &str, b: &str| {
+            let a: Vec<char> = a.chars().collect();
+            let b: Vec<char> = b.chars().collect();
+            edit_distance(&a, &b)
+        };
+
+        assert_eq!(distance("github", "github"), 0);
+        assert_eq!(distance("gihtub", "github"), 1);
+        assert_eq!(distance("githb", "github"), 1);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn fuzzy_scores() {
+        assert_eq!(fuzzy_score("GitHub", "github"), EXACT_SCORE);
+        assert_eq!(
+            fuzzy_score("github", "https://github.com"),
+            EXACT_WORD_SCORE
+        );
+        assert_eq!(fuzzy_score("git", "github"), PREFIX_SCORE);
+        assert_eq!(fuzzy_score("hub", "github"), SUBSTRING_SCORE);
+
+        let typo = fuzzy_score("gihtub", "github");
+        assert!(typo > 0.0 && typo < SUBSTRING_SCORE);
+        assert!(typo >= CONFIDENT_SCORE);
+
+        assert_eq!(fuzzy_score("gitlab", "reddit"), 0.0);
+        assert_eq!(fuzzy_score("", "github"), 0.0);
+    }
+
+    #[test]
+    fn fuzzy_entry_fields() {
+        use crate::password::EntryUpdate;
+
+        let mut entry = Entry::new("foo@email.com", "123");
+        let update = EntryUpdate {
+            url: Some(String::from("https://accounts.example.com")),
+            tags: Some(vec![String::from("banking")]),
+            ..EntryUpdate::default()
+        };
+        entry.update(update).unwrap();
+
+        assert_eq!(fuzzy_entry_score("work", "Work", &entry), EXACT_SCORE);
+        assert!(fuzzy_entry_score("foo", "Work", &entry) > 0.0);
+        assert!(fuzzy_entry_score("example", "Work", &entry) > 0.0);
+        assert!(fuzzy_entry_score("bankng", "Work", &entry) > 0.0);
+        assert_eq!(fuzzy_entry_score("nothing", "Work", &entry), 0.0);
+    }
+}
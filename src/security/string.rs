@@ -8,6 +8,37 @@ use serde::ser::{Serialize, Serializer};
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct SecString(String);
 
+impl SecString {
+    /// Append a character, moving the data to a larger
+    /// buffer by hand so the previous one is overwritten
+    pub fn push(&mut self, c: char) {
+        if self.0.len() + c.len_utf8() > self.0.capacity() {
+            let mut grown = String::with_capacity((self.0.capacity() * 2).max(32));
+            grown.push_str(&self.0);
+            // the previous buffer is wiped when dropped
+            drop(std::mem::replace(self, Self(grown)));
+        }
+
+        self.0.push(c);
+    }
+
+    /// Remove the last character, overwriting its bytes
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.0.pop()?;
+
+        // SAFETY: the removed bytes are still in the buffer, between
+        // the new length and the capacity
+        unsafe {
+            let end = self.0.as_mut_ptr().add(self.0.len());
+            for i in 0..c.len_utf8() {
+                ptr::write_volatile(end.add(i), 0);
+            }
+        }
+
+        Some(c)
+    }
+}
+
 impl Deref for SecString {
     type Target = String;
 
@@ -30,12 +61,15 @@ impl<'a> From<&'a str> for SecString {
 
 impl Drop for SecString {
     fn drop(&mut self) {
-        if self.0.len() > 0 {
+        let data = self.0.as_mut_ptr();
+        // SAFETY: the whole capacity is allocated, and zero bytes
+        // are left behind by `clear` as a valid (empty) string
+        for i in 0..self.0.capacity() {
             unsafe {
-                ptr::write_volatile(self.0.as_mut_ptr(), 0);
+                ptr::write_volatile(data.add(i), 0);
             }
-            self.0.clear();
         }
+        self.0.clear();
     }
 }
 
@@ -66,8 +100,6 @@ impl<'de> Deserialize<'de> for SecString {
             }
         }
 
-        deserializer
-            .deserialize_str(SecVisitor)
-            .map(|v| SecString::from(v))
+        deserializer.deserialize_str(SecVisitor)
     }
 }
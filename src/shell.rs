@@ -0,0 +1,603 @@
+use std::io::{self, Write};
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use crate::{
+    cli::match_line,
+    error::{PwdError, PwdResult},
+    generator::{GenerationMethod, Generator, RandomPolicy},
+    password::Entry,
+    query::FuzzyMatch,
+    vault::{short_id, Vault},
+};
+
+/// The default size of the passwords generated by `gen`
+const DEFAULT_GEN_SIZE: usize = 25;
+
+/// The shell commands, with their usage and description
+const COMMANDS: [(&str, &str, &str); 10] = [
+    (
+        "ls",
+        "[group]",
+        "list the groups, or the entries of a group",
+    ),
+    ("get", "<entry>", "print the password of an entry"),
+    (
+        "add",
+        "<group> <name>",
+        "add an entry, prompting its password",
+    ),
+    ("rm", "<entry>", "remove an entry"),
+    ("gen", "[size]", "generate a random password"),
+    ("find", "<text>", "search the entries, allowing typos"),
+    ("save", "", "write the changes to the vault"),
+    ("autosave", "[on|off]", "save the vault after every change"),
+    ("help", "", "show this help"),
+    ("exit", "", "leave the shell"),
+];
+
+/// What the shell does after a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Continue,
+    Exit,
+}
+
+/// An interactive session on an unlocked vault.
+///
+/// The vault key is derived once for the whole session, and the changes
+/// are kept in memory until `save`, or saved after every command with
+/// autosave. The entries are given by ID (or a unique prefix of it),
+/// by `group/name`, or by a search matching a single entry.
+pub struct Shell {
+    vault: Vault,
+    vault_path: String,
+    autosave: bool,
+    // whether the vault has unsaved changes
+    changed: bool,
+    // whether `exit` already warned about the unsaved changes
+    exit_warned: bool,
+    // prompts the password of the added entries
+    read_password: fn(&str) -> io::Result<String>,
+}
+
+impl Shell {
+    pub fn new(vault: Vault, vault_path: &str, autosave: bool) -> Self {
+        Self {
+            vault,
+            vault_path: String::from(vault_path),
+            autosave,
+            changed: false,
+            exit_warned: false,
+            read_password: |prompt| rpassword::read_password_from_tty(Some(prompt)),
+        }
+    }
+
+    /// Read and run the commands until `exit` or the end of the input
+    pub fn run(&mut self) -> PwdResult<()> {
+        let mut editor: Editor<ShellHelper, DefaultHistory> =
+            Editor::new().map_err(readline_error)?;
+
+        loop {
+            // the names change along with the vault
+            editor.set_helper(Some(ShellHelper::new(&self.vault)));
+
+            let prompt = if self.changed {
+                "pwdeck*> "
+            } else {
+                "pwdeck> "
+            };
+            let line = match editor.readline(prompt) {
+                Ok(line) => line,
+                // clear the line
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => String::from("exit"),
+                Err(error) => return Err(readline_error(error)),
+            };
+            // the passwords are never part of the command lines
+            let _ = editor.add_history_entry(line.as_str());
+
+            match self.execute(&line, &mut io::stdout()) {
+                Ok(Flow::Exit) => return Ok(()),
+                Ok(Flow::Continue) => {}
+                Err(error) => eprintln!("{}.", error),
+            }
+        }
+    }
+
+    /// Run a command line, writing its output to `out`
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> PwdResult<Flow> {
+        let words = split_line(line);
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(Flow::Continue),
+        };
+        let exit_warned = std::mem::replace(&mut self.exit_warned, false);
+
+        match (command, args) {
+            ("ls", []) => self.list_groups(out)?,
+            ("ls", [group]) => self.list_entries(group, out)?,
+            ("get", [reference]) => {
+                let id = self.resolve(reference, out)?;
+                let (_, entry) = self.vault.entry(&id).unwrap();
+                writeln!(out, "{}", entry.password().as_str())?;
+            }
+            ("add", [group, name]) => {
+                let password = (self.read_password)("password: ")?;
+                let entry = Entry::new(name, &password);
+                let id = String::from(entry.id());
+
+                self.vault.insert_entry(group, entry)?;
+                writeln!(out, "Added {}.", short_id(&id, self.vault.short_id_len()))?;
+                self.changed(out)?;
+            }
+            ("rm", [reference]) => {
+                let id = self.resolve(reference, out)?;
+                let entry = self.vault.remove_entry(&id)?;
+
+                writeln!(out, "Removed {}.", entry.name())?;
+                self.changed(out)?;
+            }
+            ("gen", []) => self.generate(DEFAULT_GEN_SIZE, out)?,
+            ("gen", [size]) => match size.parse() {
+                Ok(size) => self.generate(size, out)?,
+                Err(_) => writeln!(out, "Invalid size: {}", size)?,
+            },
+            ("find", [_, ..]) => self.find(&args.join(" "), out)?,
+            ("save", []) => self.save(out)?,
+            ("autosave", []) => {
+                let state = if self.autosave { "on" } else { "off" };
+                writeln!(out, "autosave is {}", state)?;
+            }
+            ("autosave", ["on"]) => {
+                self.autosave = true;
+                if self.changed {
+                    self.save(out)?;
+                }
+            }
+            ("autosave", ["off"]) => self.autosave = false,
+            ("help", []) => {
+                for (name, args, description) in COMMANDS.iter() {
+                    let usage = format!("{} {}", name, args);
+                    writeln!(out, "{:<20}{}", usage, description)?;
+                }
+                writeln!(
+                    out,
+                    "\nThe entries are given by ID, by group/name or by a search."
+                )?;
+            }
+            ("exit", []) | ("quit", []) => {
+                if !self.changed || exit_warned {
+                    return Ok(Flow::Exit);
+                }
+
+                writeln!(
+                    out,
+                    "The vault has unsaved changes, `save` them or exit again to discard them."
+                )?;
+                self.exit_warned = true;
+            }
+            _ => match COMMANDS.iter().find(|(name, _, _)| *name == command) {
+                Some((name, args, _)) => writeln!(out, "usage: {} {}", name, args)?,
+                None => writeln!(out, "Unknown command: {}, see `help`.", command)?,
+            },
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    fn list_groups(&self, out: &mut dyn Write) -> PwdResult<()> {
+        let mut groups: Vec<(&String, usize)> = self
+            .vault
+            .schema()
+            .passwords
+            .iter()
+            .map(|(group, entries)| (group, entries.len()))
+            .collect();
+        groups.sort();
+
+        if groups.is_empty() {
+            writeln!(out, "The vault is empty.")?;
+        }
+        for (group, entries) in groups {
+            writeln!(out, "{} ({})", group, entries)?;
+        }
+
+        Ok(())
+    }
+
+    fn list_entries(&self, group: &str, out: &mut dyn Write) -> PwdResult<()> {
+        let mut entries: Vec<&Entry> = self
+            .vault
+            .schema()
+            .passwords
+            .get(group)
+            .ok_or(PwdError::GroupNotFound)?
+            .iter()
+            .collect();
+        entries.sort_by_key(|entry| entry.name());
+
+        let id_len = self.vault.short_id_len();
+        for entry in entries {
+            writeln!(out, "  {}  {}", short_id(entry.id(), id_len), entry.name())?;
+        }
+
+        Ok(())
+    }
+
+    fn find(&self, text: &str, out: &mut dyn Write) -> PwdResult<()> {
+        let matches = self.vault.schema().fuzzy_search(text);
+        if matches.is_empty() {
+            writeln!(out, "No entries found.")?;
+        }
+
+        let id_len = self.vault.short_id_len();
+        for found in &matches {
+            writeln!(out, "{}", match_line(found, id_len))?;
+        }
+
+        Ok(())
+    }
+
+    fn generate(&self, size: usize, out: &mut dyn Write) -> PwdResult<()> {
+        let method = GenerationMethod::Random(RandomPolicy::new(size));
+        let generated = Generator::from(method).generate()?;
+        writeln!(out, "{}", generated.password.as_str())?;
+
+        Ok(())
+    }
+
+    /// Find the ID of the entry with the given ID (or a unique prefix of it),
+    /// `group/name`, or of the single entry confidently matching the search
+    fn resolve(&self, reference: &str, out: &mut dyn Write) -> PwdResult<String> {
+        match self.vault.resolve_id(reference) {
+            Err(PwdError::EntryNotFound) => {}
+            result => return result,
+        }
+
+        let named: Vec<&Entry> = self
+            .vault
+            .schema()
+            .passwords
+            .iter()
+            .flat_map(|(group, entries)| entries.iter().map(move |entry| (group, entry)))
+            .filter(|(group, entry)| entry_path(group, entry) == reference)
+            .map(|(_, entry)| entry)
+            .collect();
+        match named.as_slice() {
+            [entry] => return Ok(String::from(entry.id())),
+            [] => {}
+            _ => return Err(PwdError::AmbiguousEntry),
+        }
+
+        let matches: Vec<FuzzyMatch> = self
+            .vault
+            .schema()
+            .fuzzy_search(reference)
+            .into_iter()
+            .filter(FuzzyMatch::is_confident)
+            .collect();
+        match matches.as_slice() {
+            [] => Err(PwdError::EntryNotFound),
+            [found] => Ok(String::from(found.entry.id())),
+            _ => {
+                writeln!(out, "Matching entries:")?;
+                let id_len = self.vault.short_id_len();
+                for found in &matches {
+                    writeln!(out, "{}", match_line(found, id_len))?;
+                }
+                Err(PwdError::AmbiguousEntry)
+            }
+        }
+    }
+
+    /// Save the change right away with autosave, or mark the vault as changed
+    fn changed(&mut self, out: &mut dyn Write) -> PwdResult<()> {
+        self.changed = true;
+
+        if self.autosave {
+            self.save(out)?;
+        }
+
+        Ok(())
+    }
+
+    fn save(&mut self, out: &mut dyn Write) -> PwdResult<()> {
+        self.vault.sync(&self.vault_path)?;
+        self.changed = false;
+
+        writeln!(out, "Vault saved.")?;
+
+        Ok(())
+    }
+}
+
+/// The `group/name` path of an entry
+fn entry_path(group: &str, entry: &Entry) -> String {
+    format!("{}/{}", group, entry.name())
+}
+
+fn readline_error(error: ReadlineError) -> PwdError {
+    match error {
+        ReadlineError::Io(error) => error.into(),
+        error => io::Error::other(error.to_string()).into(),
+    }
+}
+
+/// Split a command line in words, quotes keep the spaces of a word
+fn split_line(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => word.get_or_insert_with(String::new).push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            None if c.is_whitespace() => words.extend(word.take()),
+            None => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    words
+}
+
+/// Find the start of the word being typed at the end of the line
+fn word_start(line: &str) -> usize {
+    let mut start = 0;
+    let mut quote = None;
+
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c.is_whitespace() => start = i + c.len_utf8(),
+            None => {}
+        }
+    }
+
+    start
+}
+
+/// Complete the word at the end of the line: the commands, then the
+/// groups for `ls` and `add`, and the `group/name` entries for `get` and `rm`
+fn complete(line: &str, groups: &[String], entries: &[String]) -> (usize, Vec<String>) {
+    let start = word_start(line);
+    let previous = split_line(&line[..start]);
+    let prefix = split_line(&line[start..]).pop().unwrap_or_default();
+
+    let commands: Vec<String>;
+    let candidates: &[String] = match previous.as_slice() {
+        [] => {
+            commands = COMMANDS
+                .iter()
+                .map(|(name, _, _)| String::from(*name))
+                .collect();
+            &commands
+        }
+        [command] if command == "ls" || command == "add" => groups,
+        [command] if command == "get" || command == "rm" => entries,
+        _ => &[],
+    };
+
+    let completions = candidates
+        .iter()
+        .filter(|candidate| candidate.starts_with(&prefix))
+        .map(|candidate| {
+            if candidate.contains(char::is_whitespace) {
+                format!("\"{}\"", candidate)
+            } else {
+                candidate.clone()
+            }
+        })
+        .collect();
+
+    (start, completions)
+}
+
+/// Completes the commands, the groups and the entries of the vault
+struct ShellHelper {
+    groups: Vec<String>,
+    entries: Vec<String>,
+}
+
+impl ShellHelper {
+    fn new(vault: &Vault) -> Self {
+        let passwords = &vault.schema().passwords;
+
+        let mut groups: Vec<String> = passwords.keys().cloned().collect();
+        groups.sort();
+
+        let mut entries: Vec<String> = passwords
+            .iter()
+            .flat_map(|(group, entries)| entries.iter().map(move |entry| entry_path(group, entry)))
+            .collect();
+        entries.sort();
+        entries.dedup();
+
+        Self { groups, entries }
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, completions) = complete(&line[..pos], &self.groups, &self.entries);
+
+        let pairs = completions
+            .into_iter()
+            .map(|completion| Pair {
+                display: completion.clone(),
+                replacement: completion,
+            })
+            .collect();
+
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::TEST_KDF;
+
+    fn test_shell(path: &str) -> Shell {
+        let mut vault = Vault::with_params("master", TEST_KDF).unwrap();
+        vault
+            .insert_entry("github", Entry::new("me", "123"))
+            .unwrap();
+        vault
+            .insert_entry("my bank", Entry::new("john", "456"))
+            .unwrap();
+
+        let mut shell = Shell::new(vault, path, false);
+        shell.read_password = |_| Ok(String::from("secret"));
+        shell
+    }
+
+    fn run(shell: &mut Shell, line: &str) -> PwdResult<String> {
+        let mut out = Vec::new();
+        shell.execute(line, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn entry_commands() {
+        let mut shell = test_shell("target/debug_shell.deck");
+
+        assert_eq!(run(&mut shell, "ls").unwrap(), "github (1)\nmy bank (1)\n");
+        assert_eq!(run(&mut shell, "get github/me").unwrap(), "123\n");
+        assert_eq!(run(&mut shell, "get \"my bank/john\"").unwrap(), "456\n");
+        assert_eq!(run(&mut shell, "get gihtub").unwrap(), "123\n");
+
+        run(&mut shell, "add github work").unwrap();
+        assert_eq!(run(&mut shell, "get github/work").unwrap(), "secret\n");
+        assert!(shell.changed);
+
+        assert_eq!(run(&mut shell, "rm github/me").unwrap(), "Removed me.\n");
+        assert!(matches!(
+            run(&mut shell, "get github/me"),
+            Err(PwdError::EntryNotFound)
+        ));
+        assert!(matches!(
+            run(&mut shell, "ls gitlab"),
+            Err(PwdError::GroupNotFound)
+        ));
+    }
+
+    #[test]
+    fn explicit_save() {
+        const PATH: &str = "target/debug_shell_save.deck";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut shell = test_shell(PATH);
+        run(&mut shell, "add reddit user").unwrap();
+        // nothing is written before `save`
+        assert!(!std::path::Path::new(PATH).exists());
+
+        // exiting with unsaved changes needs a confirmation
+        assert_eq!(
+            shell.execute("exit", &mut io::sink()).unwrap(),
+            Flow::Continue
+        );
+        run(&mut shell, "save").unwrap();
+        assert!(!shell.changed);
+        assert_eq!(shell.execute("exit", &mut io::sink()).unwrap(), Flow::Exit);
+
+        let vault = Vault::from_file(PATH, "master").unwrap();
+        assert_eq!(vault.schema().entry_count(), 3);
+    }
+
+    #[test]
+    fn autosave() {
+        const PATH: &str = "target/debug_shell_autosave.deck";
+        let _ = std::fs::remove_file(PATH);
+
+        let mut shell = test_shell(PATH);
+        run(&mut shell, "autosave on").unwrap();
+        run(&mut shell, "rm github/me").unwrap();
+        assert!(!shell.changed);
+
+        let vault = Vault::from_file(PATH, "master").unwrap();
+        assert_eq!(vault.schema().entry_count(), 1);
+    }
+
+    #[test]
+    fn command_usage() {
+        let mut shell = test_shell("target/debug_shell_usage.deck");
+
+        assert_eq!(
+            run(&mut shell, "add github").unwrap(),
+            "usage: add <group> <name>\n"
+        );
+        assert_eq!(
+            run(&mut shell, "frobnicate").unwrap(),
+            "Unknown command: frobnicate, see `help`.\n"
+        );
+        assert_eq!(run(&mut shell, "gen 12").unwrap().trim_end().len(), 12);
+    }
+
+    #[test]
+    fn line_splitting() {
+        assert_eq!(split_line("  get  github/me "), ["get", "github/me"]);
+        assert_eq!(
+            split_line("add \"my bank\" 'john doe'"),
+            ["add", "my bank", "john doe"]
+        );
+        assert_eq!(split_line("add \"\" x"), ["add", "", "x"]);
+    }
+
+    #[test]
+    fn completion() {
+        let groups = vec![String::from("github"), String::from("my bank")];
+        let entries = vec![String::from("github/me"), String::from("my bank/john")];
+
+        assert_eq!(
+            complete("a", &groups, &entries),
+            (0, vec![String::from("add"), String::from("autosave")])
+        );
+        assert_eq!(
+            complete("ls g", &groups, &entries),
+            (3, vec![String::from("github")])
+        );
+        assert_eq!(
+            complete("get m", &groups, &entries),
+            (4, vec![String::from("\"my bank/john\"")])
+        );
+        assert_eq!(
+            complete("rm \"my b", &groups, &entries),
+            (3, vec![String::from("\"my bank/john\"")])
+        );
+        assert_eq!(complete("gen 1", &groups, &entries), (4, vec![]));
+    }
+}
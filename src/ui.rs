@@ -0,0 +1,727 @@
+use std::collections::HashSet;
+use std::{fmt, io};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::{
+    error::{PwdError, PwdResult},
+    generator::{GenerationMethod, Generator, RandomPolicy},
+    password::{Entry, EntryUpdate},
+    security::SecString,
+    vault::Vault,
+};
+
+/// The size of the passwords generated in the edit form
+const GENERATED_PASSWORD_SIZE: usize = 25;
+
+/// How the hidden secrets are shown
+const HIDDEN: &str = "********";
+
+// the edit form fields
+const NAME_FIELD: usize = 1;
+const PASSWORD_FIELD: usize = 2;
+const URL_FIELD: usize = 3;
+const FIELD_LABELS: [&str; 4] = ["group", "username", "password", "url"];
+
+/// The focused pane of the browser
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pane {
+    Groups,
+    Entries,
+}
+
+/// What the keys do
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Browse,
+    /// typing in the search box
+    Search,
+    /// editing an entry, or a new one
+    Edit(EditForm),
+    /// asking to confirm the deletion of the selected entry
+    ConfirmDelete,
+}
+
+/// The fields of the edited entry
+#[derive(PartialEq)]
+struct EditForm {
+    /// `None` for a new entry
+    id: Option<String>,
+    /// secure strings, since one of them is the password
+    values: [SecString; 4],
+    field: usize,
+}
+
+impl fmt::Debug for EditForm {
+    /// Leave the values out, not to print the password
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EditForm")
+            .field("id", &self.id)
+            .field("field", &self.field)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A full-screen browser of the vault.
+///
+/// The groups and the entries of the selected group are listed side by
+/// side, along with the details of the selected entry. The lists are
+/// filtered as the search is typed, allowing typos like `pwdeck find`.
+/// Every change is saved right away.
+pub struct App {
+    vault: Vault,
+    vault_path: String,
+
+    mode: Mode,
+    focus: Pane,
+    search: String,
+    // whether the passwords and the secret fields are shown
+    revealed: bool,
+    // the last action result or error
+    status: String,
+
+    // the groups and entry IDs matching the search, sorted
+    groups: Vec<String>,
+    entries: Vec<String>,
+    group_state: ListState,
+    entry_state: ListState,
+}
+
+impl App {
+    pub fn new(vault: Vault, vault_path: &str) -> Self {
+        let mut app = Self {
+            vault,
+            vault_path: String::from(vault_path),
+
+            mode: Mode::Browse,
+            focus: Pane::Groups,
+            search: String::new(),
+            revealed: false,
+            status: String::new(),
+
+            groups: Vec::new(),
+            entries: Vec::new(),
+            group_state: ListState::default().with_selected(Some(0)),
+            entry_state: ListState::default().with_selected(Some(0)),
+        };
+        app.refresh();
+        app
+    }
+
+    /// Draw the UI and handle the keys until the user quits
+    pub fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if let Event::Key(key) = event::read()? {
+                // ignore the key releases, reported on some platforms
+                if key.kind == KeyEventKind::Press && self.handle_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Handle a key press, returning whether to quit
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        match self.mode {
+            Mode::Browse => return self.browse_key(key),
+            Mode::Search => self.search_key(key),
+            Mode::Edit(_) => self.edit_key(key),
+            Mode::ConfirmDelete => self.confirm_delete_key(key),
+        }
+
+        false
+    }
+
+    fn browse_key(&mut self, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Tab
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::Char('h')
+            | KeyCode::Char('l') => {
+                self.focus = match self.focus {
+                    Pane::Groups => Pane::Entries,
+                    Pane::Entries => Pane::Groups,
+                }
+            }
+            KeyCode::Enter if self.focus == Pane::Groups => self.focus = Pane::Entries,
+            KeyCode::Char('/') => self.mode = Mode::Search,
+            KeyCode::Char('r') | KeyCode::Char(' ') => self.revealed = !self.revealed,
+            KeyCode::Char('n') => self.start_edit(None),
+            KeyCode::Char('e') | KeyCode::Enter => {
+                if let Some(id) = self
+                    .selected_entry()
+                    .map(|(_, entry)| String::from(entry.id()))
+                {
+                    self.start_edit(Some(id));
+                }
+            }
+            KeyCode::Char('d') | KeyCode::Delete => {
+                if let Some((group, entry)) = self.selected_entry() {
+                    self.status = format!("Delete {}: {}? (y/n)", group, entry.name());
+                    self.mode = Mode::ConfirmDelete;
+                }
+            }
+            _ => {}
+        }
+
+        false
+    }
+
+    fn search_key(&mut self, key: KeyEvent) {
+        match key.code {
+            // keep the filter
+            KeyCode::Enter => self.mode = Mode::Browse,
+            KeyCode::Esc => {
+                self.search.clear();
+                self.mode = Mode::Browse;
+            }
+            KeyCode::Backspace => {
+                self.search.pop();
+            }
+            KeyCode::Char(c) => self.search.push(c),
+            _ => return,
+        }
+
+        // the first match is selected as the search is typed
+        self.group_state.select(Some(0));
+        self.entry_state.select(Some(0));
+        self.refresh();
+    }
+
+    fn edit_key(&mut self, key: KeyEvent) {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let form = match &mut self.mode {
+            Mode::Edit(form) => form,
+            _ => return,
+        };
+
+        match key.code {
+            KeyCode::Esc => {
+                self.status = String::from("Edit cancelled.");
+                self.mode = Mode::Browse;
+            }
+            KeyCode::Enter => self.save_form(),
+            KeyCode::Up => form.field = form.field.checked_sub(1).unwrap_or(URL_FIELD),
+            KeyCode::Down | KeyCode::Tab => form.field = (form.field + 1) % FIELD_LABELS.len(),
+            KeyCode::Char('g') if control => {
+                let method = GenerationMethod::Random(RandomPolicy::new(GENERATED_PASSWORD_SIZE));
+                match Generator::from(method).generate() {
+                    Ok(generated) => {
+                        form.values[PASSWORD_FIELD] = generated.password;
+                        self.status = String::from("Password generated.");
+                    }
+                    Err(error) => self.status = PwdError::from(error).to_string(),
+                }
+            }
+            KeyCode::Char('r') if control => self.revealed = !self.revealed,
+            KeyCode::Backspace => {
+                form.values[form.field].pop();
+            }
+            KeyCode::Char(c) if !control => form.values[form.field].push(c),
+            _ => {}
+        }
+    }
+
+    fn confirm_delete_key(&mut self, key: KeyEvent) {
+        self.mode = Mode::Browse;
+
+        let id = match self.selected_entry() {
+            Some((_, entry)) if key.code == KeyCode::Char('y') => String::from(entry.id()),
+            _ => {
+                self.status = String::from("Nothing deleted.");
+                return;
+            }
+        };
+
+        self.status = match self.vault.remove_entry(&id).and_then(|_| self.save()) {
+            Ok(()) => String::from("Entry deleted."),
+            Err(error) => error.to_string(),
+        };
+        self.refresh();
+    }
+
+    /// Open the edit form for the entry, or for a new one
+    /// in the selected group
+    fn start_edit(&mut self, id: Option<String>) {
+        let values = match id.as_deref().and_then(|id| self.vault.entry(id)) {
+            Some((group, entry)) => [
+                SecString::from(group),
+                SecString::from(entry.name()),
+                entry.password().clone(),
+                SecString::from(entry.url().unwrap_or("")),
+            ],
+            None => [
+                SecString::from(self.selected_group().unwrap_or("")),
+                SecString::from(""),
+                SecString::from(""),
+                SecString::from(""),
+            ],
+        };
+
+        // the group is usually kept, start on the username
+        self.mode = Mode::Edit(EditForm {
+            id,
+            values,
+            field: NAME_FIELD,
+        });
+        self.status = String::from("Enter: save, Esc: cancel, Ctrl-G: generate the password");
+    }
+
+    /// Apply the edit form to the vault
+    fn save_form(&mut self) {
+        let form = match std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Edit(form) => form,
+            mode => {
+                self.mode = mode;
+                return;
+            }
+        };
+
+        let [group, name, password, url] = &form.values;
+        if group.is_empty() || name.is_empty() {
+            self.status = String::from("The group and the username are required.");
+            self.mode = Mode::Edit(form);
+            return;
+        }
+
+        let update = EntryUpdate {
+            name: Some(String::from(name.as_str())),
+            password: Some(password.clone()),
+            url: Some(String::from(url.as_str())),
+            ..EntryUpdate::default()
+        };
+        let result = match &form.id {
+            Some(id) => self
+                .vault
+                .update_entry(id, update)
+                .and_then(|_| self.vault.move_entry(id, group)),
+            None => {
+                let mut entry = Entry::new(name, password);
+                entry
+                    .update(update)
+                    .and_then(|_| self.vault.insert_entry(group, entry))
+                    .map_err(PwdError::from)
+            }
+        };
+
+        match result.and_then(|_| self.save()) {
+            Ok(()) => self.status = String::from("Entry saved."),
+            Err(error) => {
+                self.status = error.to_string();
+                self.mode = Mode::Edit(form);
+            }
+        }
+        self.refresh();
+    }
+
+    fn save(&self) -> PwdResult<()> {
+        self.vault.sync(&self.vault_path)
+    }
+
+    fn move_selection(&mut self, offset: isize) {
+        let (state, len) = match self.focus {
+            Pane::Groups => (&mut self.group_state, self.groups.len()),
+            Pane::Entries => (&mut self.entry_state, self.entries.len()),
+        };
+        if len == 0 {
+            return;
+        }
+
+        let selected = state.selected().unwrap_or(0) as isize + offset;
+        state.select(Some(selected.clamp(0, len as isize - 1) as usize));
+
+        if self.focus == Pane::Groups {
+            self.entry_state.select(Some(0));
+        }
+        self.refresh();
+    }
+
+    /// Update the lists after a change of the vault, the search or the selection
+    fn refresh(&mut self) {
+        let schema = self.vault.schema();
+
+        let matching: Option<HashSet<&str>> = if self.search.is_empty() {
+            None
+        } else {
+            let matches = schema.fuzzy_search(&self.search);
+            Some(matches.iter().map(|found| found.entry.id()).collect())
+        };
+        let is_match = |entry: &Entry| match &matching {
+            Some(ids) => ids.contains(entry.id()),
+            None => true,
+        };
+
+        let mut groups: Vec<String> = schema
+            .passwords
+            .iter()
+            .filter(|(_, entries)| entries.iter().any(is_match))
+            .map(|(group, _)| group.clone())
+            .collect();
+        groups.sort();
+        self.groups = groups;
+        clamp_selection(&mut self.group_state, self.groups.len());
+
+        let mut entries: Vec<&Entry> = self
+            .selected_group()
+            .and_then(|group| schema.passwords.get(group))
+            .map(|entries| entries.iter().filter(|entry| is_match(entry)).collect())
+            .unwrap_or_default();
+        entries.sort_by_key(|entry| entry.name());
+        self.entries = entries
+            .into_iter()
+            .map(|entry| String::from(entry.id()))
+            .collect();
+        clamp_selection(&mut self.entry_state, self.entries.len());
+    }
+
+    fn selected_group(&self) -> Option<&str> {
+        let index = self.group_state.selected()?;
+        self.groups.get(index).map(String::as_str)
+    }
+
+    fn selected_entry(&self) -> Option<(&str, &Entry)> {
+        let index = self.entry_state.selected()?;
+        self.vault.entry(self.entries.get(index)?)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [search_area, main_area, status_area, help_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [groups_area, entries_area, details_area] = Layout::horizontal([
+            Constraint::Percentage(25),
+            Constraint::Percentage(30),
+            Constraint::Percentage(45),
+        ])
+        .areas(main_area);
+
+        let search_style = if self.mode == Mode::Search {
+            Style::new().fg(Color::Yellow)
+        } else {
+            Style::new()
+        };
+        let search = Paragraph::new(self.search.as_str()).block(
+            Block::bordered()
+                .title("Search (/)")
+                .border_style(search_style),
+        );
+        frame.render_widget(search, search_area);
+
+        let groups: Vec<ListItem> = self
+            .groups
+            .iter()
+            .map(|group| ListItem::new(group.as_str()))
+            .collect();
+        let groups = List::new(groups)
+            .block(pane_block("Groups", self.focus == Pane::Groups))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(groups, groups_area, &mut self.group_state);
+
+        let entries: Vec<ListItem> = self
+            .entries
+            .iter()
+            .filter_map(|id| self.vault.entry(id))
+            .map(|(_, entry)| ListItem::new(entry.name().to_string()))
+            .collect();
+        let entries = List::new(entries)
+            .block(pane_block("Entries", self.focus == Pane::Entries))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(entries, entries_area, &mut self.entry_state);
+
+        self.draw_details(frame, details_area);
+
+        frame.render_widget(
+            Paragraph::new(self.status.as_str()).style(Style::new().fg(Color::Yellow)),
+            status_area,
+        );
+        let help = match self.mode {
+            Mode::Edit(_) => {
+                "Tab/Up/Down: field  Ctrl-G: generate  Ctrl-R: reveal  Enter: save  Esc: cancel"
+            }
+            Mode::Search => "Type to search  Enter: keep the filter  Esc: clear",
+            _ => "q: quit  /: search  Tab: pane  r: reveal  e: edit  n: new  d: delete",
+        };
+        frame.render_widget(
+            Paragraph::new(help).style(Style::new().add_modifier(Modifier::DIM)),
+            help_area,
+        );
+    }
+
+    /// Draw the selected entry, or the edit form
+    fn draw_details(&self, frame: &mut Frame, area: Rect) {
+        let (title, lines) = match &self.mode {
+            Mode::Edit(form) => {
+                let lines = FIELD_LABELS
+                    .iter()
+                    .zip(form.values.iter())
+                    .enumerate()
+                    .map(|(i, (label, value))| {
+                        let value = if i == PASSWORD_FIELD && !self.revealed {
+                            "*".repeat(value.chars().count())
+                        } else {
+                            String::from(value.as_str())
+                        };
+                        let style = if i == form.field {
+                            Style::new().fg(Color::Yellow)
+                        } else {
+                            Style::new()
+                        };
+                        let cursor = if i == form.field { "_" } else { "" };
+
+                        Line::from(vec![
+                            Span::styled(format!("{:<10}", label), style),
+                            Span::raw(value),
+                            Span::raw(cursor),
+                        ])
+                    })
+                    .collect();
+                let title = if form.id.is_some() {
+                    "Edit entry"
+                } else {
+                    "New entry"
+                };
+
+                (title, lines)
+            }
+            _ => match self.selected_entry() {
+                Some((group, entry)) => ("Entry", self.entry_lines(group, entry)),
+                None => ("Entry", vec![Line::from("No entry selected.")]),
+            },
+        };
+
+        let border_style = match self.mode {
+            Mode::Edit(_) => Style::new().fg(Color::Yellow),
+            _ => Style::new(),
+        };
+        let details =
+            Paragraph::new(lines).block(Block::bordered().title(title).border_style(border_style));
+        frame.render_widget(details, area);
+    }
+
+    fn entry_lines(&self, group: &str, entry: &Entry) -> Vec<Line<'static>> {
+        let secret = |value: &str| {
+            if self.revealed {
+                String::from(value)
+            } else {
+                String::from(HIDDEN)
+            }
+        };
+
+        let mut lines = vec![
+            detail_line("group", group),
+            detail_line("username", entry.name()),
+            detail_line("password", &secret(entry.password())),
+        ];
+        if let Some(url) = entry.url() {
+            lines.push(detail_line("url", url));
+        }
+        if !entry.tags().is_empty() {
+            lines.push(detail_line("tags", &entry.tags().join(", ")));
+        }
+        for field in entry.fields() {
+            let value = if field.secret {
+                secret(&field.value)
+            } else {
+                field.value.to_string()
+            };
+            lines.push(detail_line(&field.name, &value));
+        }
+        if let Some(notes) = entry.notes() {
+            lines.push(detail_line("notes", ""));
+            lines.extend(notes.lines().map(|line| Line::from(format!("  {}", line))));
+        }
+
+        lines
+    }
+}
+
+fn detail_line(label: &str, value: &str) -> Line<'static> {
+    Line::from(vec![
+        Span::styled(
+            format!("{:<10}", label),
+            Style::new().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(String::from(value)),
+    ])
+}
+
+fn pane_block(title: &str, focused: bool) -> Block<'_> {
+    let style = if focused {
+        Style::new().fg(Color::Cyan)
+    } else {
+        Style::new()
+    };
+
+    Block::bordered().title(title).border_style(style)
+}
+
+/// Keep the selection in the list bounds
+fn clamp_selection(state: &mut ListState, len: usize) {
+    let selected = state.selected().unwrap_or(0);
+    state.select(Some(selected.min(len.saturating_sub(1))));
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{backend::TestBackend, Terminal};
+
+    use super::*;
+    use crate::vault::TEST_KDF;
+
+    fn test_app(path: &str) -> App {
+        let mut vault = Vault::with_params("master", TEST_KDF).unwrap();
+        vault
+            .insert_entry("github", Entry::new("me", "hunter2"))
+            .unwrap();
+        vault
+            .insert_entry("github", Entry::new("work", "123"))
+            .unwrap();
+        vault
+            .insert_entry("reddit", Entry::new("user", "abc"))
+            .unwrap();
+
+        App::new(vault, path)
+    }
+
+    fn press(app: &mut App, code: KeyCode) -> bool {
+        app.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_text(app: &mut App, text: &str) {
+        for c in text.chars() {
+            press(app, KeyCode::Char(c));
+        }
+    }
+
+    fn screen(app: &mut App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        buffer.content().iter().map(|cell| cell.symbol()).collect()
+    }
+
+    #[test]
+    fn browse_panes() {
+        let mut app = test_app("target/debug_ui_browse.deck");
+        assert_eq!(app.groups, ["github", "reddit"]);
+        assert_eq!(app.entries.len(), 2);
+
+        press(&mut app, KeyCode::Down);
+        assert_eq!(app.selected_group(), Some("reddit"));
+        assert_eq!(app.selected_entry().unwrap().1.name(), "user");
+
+        press(&mut app, KeyCode::Up);
+        press(&mut app, KeyCode::Tab);
+        press(&mut app, KeyCode::Down);
+        assert_eq!(app.selected_entry().unwrap().1.name(), "work");
+
+        assert!(press(&mut app, KeyCode::Char('q')));
+    }
+
+    #[test]
+    fn incremental_search() {
+        let mut app = test_app("target/debug_ui_search.deck");
+
+        press(&mut app, KeyCode::Char('/'));
+        type_text(&mut app, "redit");
+        assert_eq!(app.groups, ["reddit"]);
+        assert_eq!(app.selected_entry().unwrap().1.name(), "user");
+
+        // the filter is cleared with Esc
+        press(&mut app, KeyCode::Esc);
+        assert_eq!(app.groups.len(), 2);
+    }
+
+    #[test]
+    fn reveal_password() {
+        let mut app = test_app("target/debug_ui_reveal.deck");
+
+        assert!(!screen(&mut app).contains("hunter2"));
+        press(&mut app, KeyCode::Char('r'));
+        assert!(screen(&mut app).contains("hunter2"));
+    }
+
+    #[test]
+    fn edit_entry() {
+        const PATH: &str = "target/debug_ui_edit.deck";
+        let mut app = test_app(PATH);
+
+        press(&mut app, KeyCode::Char('e'));
+        type_text(&mut app, "@work.com");
+        press(&mut app, KeyCode::Tab);
+        app.handle_key(KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL));
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.mode, Mode::Browse);
+
+        let vault = Vault::from_file(PATH, "master").unwrap();
+        let entry = &vault.schema().passwords["github"]
+            .iter()
+            .find(|entry| entry.name() == "me@work.com")
+            .unwrap();
+        assert_eq!(entry.password().len(), GENERATED_PASSWORD_SIZE);
+        // the replaced password is kept in the history
+        assert_eq!(entry.history()[0].1.as_str(), "hunter2");
+    }
+
+    #[test]
+    fn edit_form_debug() {
+        let mut app = test_app("target/debug_ui_debug.deck");
+
+        press(&mut app, KeyCode::Char('e'));
+        let debug = format!("{:?}", app.mode);
+        assert!(debug.contains("EditForm"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn new_entry() {
+        const PATH: &str = "target/debug_ui_new.deck";
+        let mut app = test_app(PATH);
+
+        press(&mut app, KeyCode::Char('n'));
+        // a password is required
+        type_text(&mut app, "other");
+        press(&mut app, KeyCode::Enter);
+        assert!(matches!(app.mode, Mode::Edit(_)));
+
+        press(&mut app, KeyCode::Tab);
+        type_text(&mut app, "pass");
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(app.mode, Mode::Browse);
+        assert_eq!(app.entries.len(), 3);
+    }
+
+    #[test]
+    fn delete_confirmation() {
+        const PATH: &str = "target/debug_ui_delete.deck";
+        let mut app = test_app(PATH);
+
+        press(&mut app, KeyCode::Char('d'));
+        press(&mut app, KeyCode::Char('n'));
+        assert_eq!(app.entries.len(), 2);
+
+        press(&mut app, KeyCode::Char('d'));
+        press(&mut app, KeyCode::Char('y'));
+        assert_eq!(app.entries.len(), 1);
+
+        let vault = Vault::from_file(PATH, "master").unwrap();
+        assert_eq!(vault.schema().entry_count(), 2);
+    }
+}
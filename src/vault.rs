@@ -1,16 +1,26 @@
+use std::convert::TryFrom;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::{collections::HashMap, fs::File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+};
 
-use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::aead::{Aead, NewAead, Payload};
 use aes_gcm::Aes256Gcm;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use rand::rngs::OsRng;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 
 use crate::{
     error::{PwdError, PwdResult},
-    password::{Entry, PasswordError},
+    otp::{Otp, OtpCode, OtpKind},
+    password::{now, Entry, EntryUpdate, PasswordError},
+    query::{self, FuzzyMatch, Query},
     security::{SecString, SecVec},
 };
 
@@ -18,14 +28,69 @@ const SALT_SIZE: usize = 32;
 const KEY_SIZE: usize = 32;
 const NONCE_SIZE: usize = 12;
 
+/// Identifies a pwdeck vault file
+const MAGIC: &[u8; 4] = b"PWDK";
+/// The vault file format written by this version,
+/// with the KDF id before its params
+const FORMAT_VERSION: u16 = 3;
+/// Files written before the header had a magic and a version
+const LEGACY_FORMAT_VERSION: u16 = 0;
+/// The first versioned header, with the scrypt params
+/// only and not authenticated with the schema
+const UNAUTHENTICATED_FORMAT_VERSION: u16 = 1;
+/// The header authenticated as associated data,
+/// still with the scrypt params only
+const SCRYPT_FORMAT_VERSION: u16 = 2;
+
+// KDF ids stored in the vault header
+const KDF_SCRYPT: u8 = 0;
+const KDF_ARGON2ID: u8 = 1;
+
 const SCRYPT_LOGN: u8 = 12;
 const SCRYPT_R: u32 = 8;
 const SCRYPT_P: u32 = 1;
 
+// memory cost in KiB
+const ARGON2_M_COST: u32 = 19 * 1024;
+const ARGON2_T_COST: u32 = 2;
+const ARGON2_P_COST: u32 = 1;
+
+// upper bounds for the KDF params read from a vault file,
+// so a tampered header can't make the key derivation exhaust
+// the memory or run forever
+const SCRYPT_MAX_LOGN: u8 = 20;
+const SCRYPT_MAX_R: u32 = 32;
+const SCRYPT_MAX_P: u32 = 16;
+
+const ARGON2_MAX_M_COST: u32 = 4 * 1024 * 1024;
+const ARGON2_MAX_T_COST: u32 = 256;
+const ARGON2_MAX_P_COST: u32 = 16;
+
+// like git short hashes, entry IDs are shown with at least 7 characters
+// and can be abbreviated down to 4 characters when they are unambiguous
+const SHORT_ID_LEN: usize = 7;
+const MIN_ID_PREFIX_LEN: usize = 4;
+
+/// Cheap KDF params for the tests, the KDF itself is tested with the vault
+#[cfg(test)]
+pub(crate) const TEST_KDF: KdfParams = KdfParams::Argon2id(Argon2Params {
+    m_cost: 64,
+    t_cost: 1,
+    p_cost: 1,
+});
+
+// the calibration stops doubling the argon2 memory at 1 GiB,
+// and raises the iterations instead
+const ARGON2_CALIBRATION_MAX_M_COST: u32 = 1024 * 1024;
+
 #[derive(Serialize, Deserialize, Debug)]
 /// The vault JSON schema
 pub struct VaultSchema {
     pub(crate) passwords: HashMap<String, Vec<Entry>>,
+    /// the IDs of the removed entries, with when they were
+    /// removed, so merges don't bring them back
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub(crate) removed: HashMap<String, u64>,
 }
 
 impl Default for VaultSchema {
@@ -33,127 +98,374 @@ impl Default for VaultSchema {
     fn default() -> Self {
         Self {
             passwords: HashMap::new(),
+            removed: HashMap::new(),
         }
     }
 }
 
+impl VaultSchema {
+    /// The number of entries in all the groups
+    pub fn entry_count(&self) -> usize {
+        self.passwords.values().map(Vec::len).sum()
+    }
+
+    /// Find the entries matching the query, allowing typos,
+    /// sorted from the best to the worst match
+    pub fn fuzzy_search(&self, text: &str) -> Vec<FuzzyMatch<'_>> {
+        let mut matches: Vec<FuzzyMatch> = self
+            .passwords
+            .iter()
+            .flat_map(|(group, entries)| {
+                entries.iter().map(move |entry| FuzzyMatch {
+                    group,
+                    entry,
+                    score: query::fuzzy_entry_score(text, group, entry),
+                })
+            })
+            .filter(|m| m.score > 0.0)
+            .collect();
+
+        query::rank(&mut matches);
+        matches
+    }
+}
+
+/// The path of the backup kept by `Vault::sync`
+pub fn backup_path<P: AsRef<Path>>(vault_path: P) -> PathBuf {
+    sibling_path(vault_path.as_ref(), "bak")
+}
+
+/// Append an extension to the file name, e.g. `vault.pwd.bak`
+pub(crate) fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+
+    path.with_file_name(file_name)
+}
+
+/// Create (or replace) a file only readable by its owner
+fn create_private_file(path: &Path) -> io::Result<File> {
+    // the mode only applies to new files, so a leftover
+    // one with looser permissions is removed first
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)
+}
+
+/// Flush a rename in the directory of the given path to the disk
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Shorten an entry ID to `len` characters. IDs read from the
+/// file are not validated, so shorter ones are kept whole
+pub fn short_id(id: &str, len: usize) -> &str {
+    id.get(..len).unwrap_or(id)
+}
+
+/// The number of leading bytes two strings have in common
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count()
+}
+
+/// Generate a random salt
+fn random_salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0; SALT_SIZE];
+    let mut rng = OsRng;
+    rng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Key derivation functions supported by the vault
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KdfAlgorithm {
+    #[default]
+    Scrypt,
+    Argon2id,
+}
+
+/// How an entry changed in both vaults is merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// keep the entry of this vault
+    Ours,
+    /// take the entry of the other vault
+    Theirs,
+}
+
+/// An entry changed in both vaults since they diverged
+pub struct MergeConflict<'a> {
+    pub group: &'a str,
+    pub ours: &'a Entry,
+    pub their_group: &'a str,
+    pub theirs: &'a Entry,
+}
+
+/// What a merge changed, the entries are described as "group: name"
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    /// the entries only in the other vault
+    pub added: Vec<String>,
+    /// the entries replaced by their later version in the other vault
+    pub updated: Vec<String>,
+    /// the entries changed in both vaults, with how they were merged
+    pub conflicts: Vec<(String, Resolution)>,
+    /// the entries removed in the other vault
+    pub removed: Vec<String>,
+    /// the entries removed in this vault, and left out of the merge
+    pub kept_removed: Vec<String>,
+    /// the entries removed in this vault, but changed
+    /// since in the other one, so added back
+    pub readded: Vec<String>,
+}
+
 /// The Password vault
 pub struct Vault {
     schema: VaultSchema,
 
-    // not sure if the master password should be stored
-    master_password: SecString,
+    // not sure if the master password should be stored,
+    // it's unknown when the vault is opened with a cached key
+    master_password: Option<SecString>,
     key: SecVec<u8>,
     salt: [u8; SALT_SIZE],
 
-    scrypt_logn: u8,
-    scrypt_r: u32,
-    scrypt_p: u32,
+    kdf: KdfParams,
+
+    // the format version of the file the vault was read from
+    version: u16,
 }
 
 /// Safe password vault storage
 impl Vault {
     /// Create a new vault with the given master password
     pub fn new(master_password: &str) -> Self {
-        let salt = {
-            let mut salt = [0; SALT_SIZE];
-            let mut rng = OsRng::default();
-            rng.fill_bytes(&mut salt);
-            salt
-        };
-
-        let scrypt_logn = SCRYPT_LOGN;
-        let scrypt_r = SCRYPT_R;
-        let scrypt_p = SCRYPT_P;
+        Self::with_kdf(master_password, KdfAlgorithm::default())
+    }
 
+    /// Create a new vault deriving its key with the given algorithm
+    pub fn with_kdf(master_password: &str, algorithm: KdfAlgorithm) -> Self {
         // already tested params, should not be a problem
-        let scrypt_params = scrypt::Params::new(scrypt_logn, scrypt_r, scrypt_p).unwrap();
+        Self::with_params(master_password, KdfParams::default_for(algorithm))
+            .expect("invalid KDF params")
+    }
 
-        let mut key = vec![0; KEY_SIZE];
-        // the params and the key length are right, so this will not panic
-        scrypt::scrypt(master_password.as_bytes(), &salt, &scrypt_params, &mut key).unwrap();
+    /// Create a new vault deriving its key with the given KDF params
+    pub fn with_params(master_password: &str, kdf: KdfParams) -> PwdResult<Self> {
+        let salt = random_salt();
+        let key = kdf.derive_key(master_password, &salt)?;
 
-        Self {
+        Ok(Self {
             schema: VaultSchema::default(),
 
-            master_password: master_password.into(),
-            key: key.into(),
+            master_password: Some(master_password.into()),
+            key,
             salt,
 
-            scrypt_logn,
-            scrypt_r,
-            scrypt_p,
-        }
+            kdf,
+
+            version: FORMAT_VERSION,
+        })
     }
 
-    /// Try to get the vault from a given file
-    pub fn from_file(vault_file: &mut File, master_password: &str) -> PwdResult<Self> {
-        // read the file and write its content into a `Vec`
-        let mut buffer = Vec::new();
-        vault_file.read_to_end(&mut buffer)?;
+    /// Try to get the vault from the file at the given path
+    pub fn from_file<P: AsRef<Path>>(vault_path: P, master_password: &str) -> PwdResult<Self> {
+        // read the file into a `Vec`
+        let buffer = fs::read(vault_path)?;
+
+        Self::from_bytes(buffer, master_password)
+    }
+
+    /// Try to get the vault from the raw content of a vault file
+    pub(crate) fn from_bytes(buffer: Vec<u8>, master_password: &str) -> PwdResult<Self> {
+        // generate the key, the params come from the file
+        let derive_key = |metadata: &Metadata| {
+            metadata
+                .kdf
+                .derive_key(master_password, &metadata.salt)
+                .map_err(|error| match error {
+                    PwdError::InvalidKdfParams => PwdError::InvalidVaultFile,
+                    error => error,
+                })
+        };
+
+        Self::decrypt(buffer, derive_key, Some(master_password.into()))
+    }
+
+    /// Try to get the vault from the file with an already derived key,
+    /// e.g. the one cached by the agent. Without the master password,
+    /// the vault can't be re-keyed.
+    pub fn from_file_with_key<P: AsRef<Path>>(vault_path: P, key: SecVec<u8>) -> PwdResult<Self> {
+        let buffer = fs::read(vault_path)?;
 
+        Self::decrypt(buffer, |_| Ok(key), None)
+    }
+
+    /// Decrypt a vault file with the key given for its metadata
+    fn decrypt<F>(buffer: Vec<u8>, key: F, master_password: Option<SecString>) -> PwdResult<Self>
+    where
+        F: FnOnce(&Metadata) -> PwdResult<SecVec<u8>>,
+    {
         // create the file reader
-        let mut reader = Cursor::new(buffer);
+        let mut reader = Cursor::new(buffer.as_slice());
 
         // read the metadata from the file
-        let metadata = match Metadata::read(&mut reader) {
-            Ok(metadata) => metadata,
-            Err(error) => return Err(PwdError::from(error)),
-        };
+        let metadata = Metadata::read(&mut reader)?;
+
+        // the rest of the file is the encrypted schema
+        let (header, encrypted_schema) = buffer.split_at(reader.position() as usize);
 
-        // read the rest of the file
-        let encrypted_schema = {
-            let mut schema = Vec::new();
-            reader.read_to_end(&mut schema)?;
-            schema
+        // the header is authenticated along with the schema,
+        // except for the older files that were written without it
+        let aad = if metadata.version <= UNAUTHENTICATED_FORMAT_VERSION {
+            &[]
+        } else {
+            header
         };
 
-        // generate the key
-        let mut key = vec![0; KEY_SIZE];
-        // the key lenght is ok, should not panic
-        scrypt::scrypt(
-            master_password.as_bytes(),
-            &metadata.salt,
-            &metadata.scrypt.into(),
-            &mut key,
-        )
-        .unwrap();
+        let key = key(&metadata)?;
+        if key.len() != KEY_SIZE {
+            return Err(PwdError::AuthenticationFailed);
+        }
 
-        let cipher = Aes256Gcm::new((*key).into());
+        let cipher = Aes256Gcm::new(key.as_slice().into());
         let json_schema = cipher
-            .decrypt(&metadata.nonce.into(), encrypted_schema.as_ref())
-            .unwrap_or_else(|_error| {
-                panic!("Authentication failed");
-            });
-
-        let schema: VaultSchema = {
-            let encoded_schema = String::from_utf8_lossy(&json_schema);
-            match serde_json::from_str(&encoded_schema) {
-                Ok(schema) => schema,
-                Err(error) => {
-                    panic!("Invalid json file: {}", error.to_string())
-                }
-            }
-        };
+            .decrypt(
+                &metadata.nonce.into(),
+                Payload {
+                    msg: encrypted_schema,
+                    aad,
+                },
+            )
+            .map_err(|_| PwdError::AuthenticationFailed)?;
+
+        let schema: VaultSchema =
+            serde_json::from_slice(&json_schema).map_err(|_| PwdError::InvalidVaultFile)?;
 
         let vault = Self {
             schema,
 
-            master_password: master_password.into(),
-            key: key.into(),
+            master_password,
+            key,
             salt: metadata.salt,
 
-            scrypt_logn: metadata.scrypt.logn,
-            scrypt_r: metadata.scrypt.r,
-            scrypt_p: metadata.scrypt.p,
+            kdf: metadata.kdf,
+
+            version: metadata.version,
         };
 
         Ok(vault)
     }
 
+    /// Change the master password, re-keying the vault with a fresh salt.
+    /// The vault is re-encrypted with the new key on the next sync.
+    pub fn change_master_password(&mut self, old: &str, new: &str) -> PwdResult<()> {
+        // compared in constant time, not to leak how much of it is right
+        let matches = self
+            .master_password
+            .as_ref()
+            .is_some_and(|master| bool::from(master.as_bytes().ct_eq(old.as_bytes())));
+        if !matches {
+            return Err(PwdError::AuthenticationFailed);
+        }
+
+        if new.is_empty() {
+            return Err(PwdError::InvalidPassword);
+        }
+
+        self.rekey(new, self.kdf)
+    }
+
+    /// Change the KDF params, re-keying the vault with a fresh salt.
+    /// The vault is re-encrypted with the new key on the next sync.
+    pub fn set_kdf_params(&mut self, kdf: KdfParams) -> PwdResult<()> {
+        let master_password = self
+            .master_password
+            .clone()
+            .ok_or(PwdError::AuthenticationFailed)?;
+        self.rekey(&master_password, kdf)
+    }
+
+    /// Derive a new key with a fresh salt
+    fn rekey(&mut self, master_password: &str, kdf: KdfParams) -> PwdResult<()> {
+        let salt = random_salt();
+
+        self.key = kdf.derive_key(master_password, &salt)?;
+        self.salt = salt;
+        self.kdf = kdf;
+        self.master_password = Some(master_password.into());
+
+        Ok(())
+    }
+
+    /// Encrypt a schema with its own password and KDF params,
+    /// using the vault file format
+    pub(crate) fn seal_schema(
+        schema: &VaultSchema,
+        password: &str,
+        kdf: KdfParams,
+    ) -> PwdResult<Vec<u8>> {
+        let vault = Self::with_params(password, kdf)?;
+        let json_schema = serde_json::to_vec(schema).map_err(io::Error::from)?;
+
+        Ok(vault.seal(&json_schema)?)
+    }
+
+    /// Replace all the entries of the vault
+    pub fn replace_schema(&mut self, schema: VaultSchema) {
+        self.schema = schema;
+    }
+
+    /// Add the entries of the schema whose IDs are not in the vault,
+    /// returning how many were added
+    pub fn insert_missing(&mut self, schema: VaultSchema) -> usize {
+        let mut added = 0;
+
+        for (group, entries) in schema.passwords {
+            for entry in entries {
+                if self.entry(entry.id()).is_some() {
+                    continue;
+                }
+
+                self.schema
+                    .passwords
+                    .entry(group.clone())
+                    .or_default()
+                    .push(entry);
+                added += 1;
+            }
+        }
+
+        added
+    }
+
     /// Add a new password to the vault
     pub fn insert_entry(&mut self, group: &str, entry: Entry) -> Result<(), PasswordError> {
-        if entry.password().len() == 0 {
+        if entry.password().is_empty() {
             return Err(PasswordError::EmptyPassword);
         }
 
@@ -169,8 +481,367 @@ impl Vault {
         Ok(())
     }
 
-    /// Sync the passwords with the vault file
-    pub fn sync(&self, vault_file: &mut File) -> io::Result<()> {
+    /// Get the entry with the given ID along with its group
+    pub fn entry(&self, id: &str) -> Option<(&str, &Entry)> {
+        self.schema.passwords.iter().find_map(|(group, entries)| {
+            entries
+                .iter()
+                .find(|entry| entry.id() == id)
+                .map(|entry| (group.as_str(), entry))
+        })
+    }
+
+    /// Get the full ID of the entry with the given ID or unambiguous ID prefix
+    pub fn resolve_id(&self, id: &str) -> PwdResult<String> {
+        if self.entry(id).is_some() {
+            return Ok(String::from(id));
+        }
+        if id.len() < MIN_ID_PREFIX_LEN {
+            return Err(PwdError::EntryNotFound);
+        }
+
+        let mut matches = self
+            .schema
+            .passwords
+            .values()
+            .flatten()
+            .map(Entry::id)
+            .filter(|entry_id| entry_id.starts_with(id));
+
+        match (matches.next(), matches.next()) {
+            (Some(entry_id), None) => Ok(String::from(entry_id)),
+            (Some(_), Some(_)) => Err(PwdError::AmbiguousEntry),
+            (None, _) => Err(PwdError::EntryNotFound),
+        }
+    }
+
+    /// The length of the shortened entry IDs, the shortest one
+    /// keeping all the IDs in the vault unique
+    pub fn short_id_len(&self) -> usize {
+        let mut ids: Vec<&str> = self
+            .schema
+            .passwords
+            .values()
+            .flatten()
+            .map(Entry::id)
+            .collect();
+        ids.sort_unstable();
+
+        // the IDs sharing the longest prefix are next to each other once sorted,
+        // duplicated IDs can't be told apart, so never go past the longest one
+        let longest = ids.iter().map(|id| id.len()).max().unwrap_or(SHORT_ID_LEN);
+        ids.windows(2)
+            .map(|pair| common_prefix_len(pair[0], pair[1]) + 1)
+            .fold(SHORT_ID_LEN, usize::max)
+            .min(longest.max(SHORT_ID_LEN))
+    }
+
+    /// Find the entries matching the query, along with their groups
+    pub fn search<'a>(&'a self, query: &'a Query) -> impl Iterator<Item = (&'a str, &'a Entry)> {
+        self.schema
+            .passwords
+            .iter()
+            .flat_map(move |(group, entries)| {
+                entries
+                    .iter()
+                    .filter(move |entry| query.matches(group, entry))
+                    .map(move |entry| (group.as_str(), entry))
+            })
+    }
+
+    /// Get the group and position of the entry with the given ID
+    fn entry_position(&self, id: &str) -> PwdResult<(String, usize)> {
+        self.schema
+            .passwords
+            .iter()
+            .find_map(|(group, entries)| {
+                entries
+                    .iter()
+                    .position(|entry| entry.id() == id)
+                    .map(|i| (group.clone(), i))
+            })
+            .ok_or(PwdError::EntryNotFound)
+    }
+
+    /// Remove the entry with the given ID, returning it.
+    /// Groups left empty are removed too, and the removal
+    /// is recorded for the next merges.
+    pub fn remove_entry(&mut self, id: &str) -> PwdResult<Entry> {
+        let entry = self.take_entry(id)?;
+        self.schema.removed.insert(String::from(id), now());
+
+        Ok(entry)
+    }
+
+    /// Take the entry with the given ID out of its group, e.g. to move it.
+    /// Groups left empty are removed too.
+    fn take_entry(&mut self, id: &str) -> PwdResult<Entry> {
+        let (group, i) = self.entry_position(id)?;
+
+        let entries = self.schema.passwords.get_mut(&group).unwrap();
+        let entry = entries.remove(i);
+
+        if entries.is_empty() {
+            self.schema.passwords.remove(&group);
+        }
+
+        Ok(entry)
+    }
+
+    /// Update the entry with the given ID
+    pub fn update_entry(&mut self, id: &str, update: EntryUpdate) -> PwdResult<()> {
+        let (group, i) = self.entry_position(id)?;
+
+        let entry = &mut self.schema.passwords.get_mut(&group).unwrap()[i];
+        entry.update(update)?;
+
+        Ok(())
+    }
+
+    /// Move the entry with the given ID to another group,
+    /// creating the group if it doesn't exist
+    pub fn move_entry(&mut self, id: &str, group: &str) -> PwdResult<()> {
+        // already there, the entry isn't modified
+        if self.entry_position(id)?.0 == group {
+            return Ok(());
+        }
+
+        let entry = self.take_entry(id)?;
+
+        self.schema
+            .passwords
+            .entry(String::from(group))
+            .or_default()
+            .push(entry);
+
+        // the removed entry was pushed last
+        let entries = self.schema.passwords.get_mut(group).unwrap();
+        entries.last_mut().unwrap().touch();
+
+        Ok(())
+    }
+
+    /// Generate the one-time password of the entry with the given ID at
+    /// the given unix time. HOTP counters are incremented, so the vault
+    /// must be synced afterwards.
+    pub fn generate_otp(&mut self, id: &str, time: u64) -> PwdResult<OtpCode> {
+        let (group, i) = self.entry_position(id)?;
+        let entry = &mut self.schema.passwords.get_mut(&group).unwrap()[i];
+
+        let mut otp = Otp::parse(entry.otp().ok_or(PwdError::OtpNotFound)?)?;
+        let code = otp.generate(time)?;
+
+        if let OtpKind::Hotp { .. } = otp.kind() {
+            let update = EntryUpdate {
+                otp: Some(otp.to_uri()),
+                ..EntryUpdate::default()
+            };
+            entry.update(update)?;
+        }
+
+        Ok(code)
+    }
+
+    /// Merge the entries of another copy of the vault, matching them by ID.
+    ///
+    /// The revisions stand for the common ancestor: an entry is a later
+    /// version of the other one when it descends from its revision (see
+    /// `Entry::supersedes`). The entries changed in both vaults are
+    /// conflicts, merged as `resolve` decides. The password of the losing
+    /// version is kept in the history, and its other values in the
+    /// discarded versions of the entry.
+    ///
+    /// The entries only in this vault are kept, unless the other vault
+    /// removed them since their last change. Likewise, the entries removed
+    /// in this vault are only added back if they were changed since.
+    pub fn merge<F>(&mut self, other: &VaultSchema, mut resolve: F) -> MergeReport
+    where
+        F: FnMut(&MergeConflict) -> Resolution,
+    {
+        let mut report = MergeReport::default();
+
+        // sorted, so the conflicts are resolved in a stable order
+        let mut theirs: Vec<(&str, &Entry)> = other
+            .passwords
+            .iter()
+            .flat_map(|(group, entries)| entries.iter().map(move |entry| (group.as_str(), entry)))
+            .collect();
+        theirs.sort_by_key(|(group, entry)| (*group, entry.name()));
+
+        for (their_group, their_entry) in theirs {
+            let label = format!("{}: {}", their_group, their_entry.name());
+
+            let (group, ours) = match self.entry(their_entry.id()) {
+                Some((group, ours)) => (String::from(group), ours.clone()),
+                None => {
+                    match self.schema.removed.get(their_entry.id()) {
+                        Some(&removed_at) if their_entry.updated_at() <= removed_at => {
+                            report.kept_removed.push(label);
+                            continue;
+                        }
+                        Some(_) => {
+                            self.schema.removed.remove(their_entry.id());
+                            report.readded.push(label);
+                        }
+                        None => report.added.push(label),
+                    }
+
+                    self.schema
+                        .passwords
+                        .entry(String::from(their_group))
+                        .or_default()
+                        .push(their_entry.clone());
+                    continue;
+                }
+            };
+
+            if group == their_group && ours == *their_entry {
+                continue;
+            }
+
+            let ours_later = ours.supersedes(their_entry);
+            let theirs_later = their_entry.supersedes(&ours);
+            // the same version in two groups, the revisions can't
+            // tell which copy moved it
+            let moved_apart = ours_later && theirs_later && group != their_group;
+
+            let mut conflict = false;
+            let resolution = if ours_later && !moved_apart {
+                Resolution::Ours
+            } else if theirs_later && !moved_apart {
+                report.updated.push(label);
+                Resolution::Theirs
+            } else {
+                conflict = true;
+                let conflict = MergeConflict {
+                    group: &group,
+                    ours: &ours,
+                    their_group,
+                    theirs: their_entry,
+                };
+                let resolution = resolve(&conflict);
+                report.conflicts.push((label, resolution));
+                resolution
+            };
+
+            let (winner_group, mut winner, loser) = match resolution {
+                Resolution::Ours => (group.as_str(), ours.clone(), their_entry),
+                Resolution::Theirs => (their_group, their_entry.clone(), &ours),
+            };
+            winner.merge_history(loser);
+            if conflict {
+                winner.keep_discarded(loser);
+            }
+
+            // the entry exists, it was found above
+            let (_, i) = self.entry_position(winner.id()).unwrap();
+            if winner_group == group {
+                self.schema.passwords.get_mut(&group).unwrap()[i] = winner;
+            } else {
+                self.take_entry(winner.id()).unwrap();
+                self.schema
+                    .passwords
+                    .entry(String::from(winner_group))
+                    .or_default()
+                    .push(winner);
+            }
+        }
+
+        // the entries removed in the other vault since their last change
+        let mut removed: Vec<(&String, &u64)> = other.removed.iter().collect();
+        removed.sort();
+        for (id, &removed_at) in removed {
+            if let Some((group, entry)) = self.entry(id) {
+                if entry.updated_at() > removed_at {
+                    continue;
+                }
+
+                report.removed.push(format!("{}: {}", group, entry.name()));
+                self.take_entry(id).unwrap();
+            }
+
+            let known = self.schema.removed.entry(id.clone()).or_insert(removed_at);
+            *known = (*known).max(removed_at);
+        }
+
+        report
+    }
+
+    /// Restore the password at `index` in the entry history,
+    /// the current password is moved to the history
+    pub fn restore_password(&mut self, id: &str, index: usize) -> PwdResult<()> {
+        let (group, i) = self.entry_position(id)?;
+
+        let entry = &mut self.schema.passwords.get_mut(&group).unwrap()[i];
+        entry
+            .restore_password(index)
+            .ok_or(PwdError::HistoryNotFound)
+    }
+
+    /// Rename a group.
+    /// If a group with the new name already exists, the entries are merged into it.
+    pub fn rename_group(&mut self, old: &str, new: &str) -> PwdResult<()> {
+        if old == new {
+            return if self.schema.passwords.contains_key(old) {
+                Ok(())
+            } else {
+                Err(PwdError::GroupNotFound)
+            };
+        }
+
+        let mut entries = self
+            .schema
+            .passwords
+            .remove(old)
+            .ok_or(PwdError::GroupNotFound)?;
+        // like `move_entry`, so merges take the new group
+        entries.iter_mut().for_each(Entry::touch);
+
+        self.schema
+            .passwords
+            .entry(String::from(new))
+            .or_default()
+            .extend(entries);
+
+        Ok(())
+    }
+
+    /// Sync the passwords with the vault file at the given path.
+    ///
+    /// The vault is written to a temporary file in the same directory,
+    /// which is flushed to the disk and then renamed over the vault file,
+    /// so a crash never leaves a partially written vault behind.
+    /// The previous version of the vault is kept as `<vault>.bak`.
+    pub fn sync<P: AsRef<Path>>(&self, vault_path: P) -> PwdResult<()> {
+        let vault_path = vault_path.as_ref();
+
+        let schema = serde_json::to_vec(&self.schema).map_err(io::Error::from)?;
+        let buffer = self.seal(&schema)?;
+
+        // write the new vault to the temporary file
+        let temp_path = sibling_path(vault_path, "tmp");
+        {
+            let mut temp_file = create_private_file(&temp_path)?;
+            temp_file.write_all(&buffer)?;
+            temp_file.sync_all()?;
+        }
+
+        // keep the previous version as a backup
+        if vault_path.exists() {
+            let backup_path = backup_path(vault_path);
+            fs::copy(vault_path, &backup_path)?;
+            File::open(&backup_path)?.sync_all()?;
+        }
+
+        // atomically replace the vault
+        fs::rename(&temp_path, vault_path)?;
+        sync_parent_dir(vault_path)?;
+
+        Ok(())
+    }
+
+    /// Encrypt the plaintext and prepend the vault metadata to it
+    fn seal(&self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
         // create the aes cipher
         let key: &[u8] = &self.key;
         let cipher = Aes256Gcm::new(key.into());
@@ -178,65 +849,139 @@ impl Vault {
         // generate a random nonce
         let nonce = {
             let mut nonce = [0; NONCE_SIZE];
-            let mut rng = OsRng::default();
+            let mut rng = OsRng;
             rng.fill_bytes(&mut nonce);
             nonce
         };
 
-        let schema = serde_json::to_string(&self.schema)?;
-        // encrypt the schema
-        let schema = cipher
-            .encrypt(&nonce.into(), schema.as_ref())
-            .unwrap_or_else(|error| {
-                panic!("Encryption error: {}", error.to_string());
-            });
-
         // write the metadata
         let mut writer = Cursor::new(Vec::new());
         self.metadata(nonce).write(&mut writer)?;
-        // write the encrypted schema
-        writer.write_all(&schema)?;
 
-        // write the buffer content to the vault file
-        // a bit more safe than writing directly into
-        // the file
-        vault_file.write_all(writer.get_ref())?;
+        // encrypt the schema, authenticating the metadata
+        let ciphertext = cipher
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad: writer.get_ref(),
+                },
+            )
+            .map_err(|error| io::Error::other(format!("Encryption error: {}", error)))?;
 
-        Ok(())
+        // write the encrypted schema
+        writer.write_all(&ciphertext)?;
+
+        Ok(writer.into_inner())
     }
 
     /// Return the vault's metadata
     fn metadata(&self, nonce: [u8; NONCE_SIZE]) -> Metadata {
         Metadata {
-            scrypt: ScryptMetadata {
-                logn: self.scrypt_logn,
-                r: self.scrypt_r,
-                p: self.scrypt_p,
-            },
+            version: FORMAT_VERSION,
+            kdf: self.kdf,
             nonce,
             salt: self.salt,
         }
     }
 
+    /// The key derivation function used by the vault
+    pub fn kdf_algorithm(&self) -> KdfAlgorithm {
+        self.kdf.algorithm()
+    }
+
+    /// The key derivation params used by the vault
+    pub fn kdf_params(&self) -> KdfParams {
+        self.kdf
+    }
+
+    /// The format version of the vault file.
+    /// Vaults read from an older format are upgraded
+    /// to the current one on the next sync.
+    pub fn format_version(&self) -> u16 {
+        self.version
+    }
+
+    /// Whether the vault file uses an older format
+    pub fn needs_upgrade(&self) -> bool {
+        self.version < FORMAT_VERSION
+    }
+
+    /// The key derived from the master password, as cached by the agent
+    pub(crate) fn key(&self) -> &SecVec<u8> {
+        &self.key
+    }
+
     /// Schema getter
     pub fn schema(&self) -> &VaultSchema {
         &self.schema
     }
+
+    /// Take the schema out of the vault
+    pub(crate) fn into_schema(self) -> VaultSchema {
+        self.schema
+    }
 }
 
 // Metadata about the vault file
 struct Metadata {
-    scrypt: ScryptMetadata,
+    version: u16,
+    kdf: KdfParams,
     nonce: [u8; NONCE_SIZE],
     salt: [u8; SALT_SIZE],
 }
 
 impl Metadata {
-    fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+    /// Read the metadata from the reader, detecting the
+    /// headerless layout used before the format was versioned.
+    /// The reader is left at the start of the encrypted schema.
+    fn read<R: Read + Seek>(reader: &mut R) -> PwdResult<Self> {
         // rewind the reader
         reader.seek(SeekFrom::Start(0))?;
 
-        let scrypt_metadata = ScryptMetadata::read(reader)?;
+        let mut magic = [0; MAGIC.len()];
+        let has_magic = reader.read_exact(&mut magic).is_ok() && &magic == MAGIC;
+
+        if !has_magic {
+            // legacy file, the header starts straight with the scrypt params
+            reader.seek(SeekFrom::Start(0))?;
+            return Self::read_body(reader, LEGACY_FORMAT_VERSION)
+                .map_err(|_| PwdError::InvalidVaultFile);
+        }
+
+        let version = reader
+            .read_u16::<LittleEndian>()
+            .map_err(|_| PwdError::InvalidVaultFile)?;
+
+        match version {
+            UNAUTHENTICATED_FORMAT_VERSION..=FORMAT_VERSION => {
+                let header_len = reader
+                    .read_u32::<LittleEndian>()
+                    .map_err(|_| PwdError::InvalidVaultFile)?;
+
+                let start = reader.stream_position()?;
+                let metadata =
+                    Self::read_body(reader, version).map_err(|_| PwdError::InvalidVaultFile)?;
+
+                // the header length must match what was actually read
+                if reader.stream_position()? - start != u64::from(header_len) {
+                    return Err(PwdError::InvalidVaultFile);
+                }
+
+                Ok(metadata)
+            }
+            version => Err(PwdError::UnsupportedVaultVersion(version)),
+        }
+    }
+
+    /// Read the encryption informations shared by every format version
+    fn read_body<R: Read + Seek>(reader: &mut R, version: u16) -> io::Result<Self> {
+        let kdf = if version <= SCRYPT_FORMAT_VERSION {
+            // older files always used scrypt, without a KDF id
+            KdfParams::Scrypt(ScryptParams::read(reader)?)
+        } else {
+            KdfParams::read(reader)?
+        };
 
         let nonce = {
             let mut nonce = [0; NONCE_SIZE];
@@ -251,85 +996,317 @@ impl Metadata {
         };
 
         Ok(Self {
-            scrypt: scrypt_metadata,
+            version,
+            kdf,
             nonce,
             salt,
         })
     }
 
     /// Write the metadata to the writer buffer.
-    /// This includes the salt and other encryption
-    /// informations such as the scrypt params used.
+    /// This includes the magic, the format version, the
+    /// salt and other encryption informations such as
+    /// the KDF and its params.
+    /// The metadata is always written with the current
+    /// format version.
     fn write<W: Write + Seek>(self, writer: &mut W) -> io::Result<()> {
         // rewind
         writer.seek(SeekFrom::Start(0))?;
 
-        writer.write_u8(self.scrypt.logn)?;
-        writer.write_u32::<LittleEndian>(self.scrypt.r)?;
-        writer.write_u32::<LittleEndian>(self.scrypt.p)?;
+        // serialize the header body first, so its length is known
+        let mut body = Vec::new();
+        self.kdf.write(&mut body)?;
+        body.write_all(&self.nonce)?;
+        body.write_all(&self.salt)?;
 
-        writer.write_all(&self.nonce)?;
-        writer.write_all(&self.salt)?;
+        writer.write_all(MAGIC)?;
+        writer.write_u16::<LittleEndian>(FORMAT_VERSION)?;
+        writer.write_u32::<LittleEndian>(body.len() as u32)?;
+        writer.write_all(&body)?;
 
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct ScryptMetadata {
-    logn: u8,
-    r: u32,
-    p: u32,
+/// The key derivation function and its cost params
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfParams {
+    Scrypt(ScryptParams),
+    Argon2id(Argon2Params),
 }
 
-impl ScryptMetadata {
-    fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
-        let logn = reader.read_u8()?;
-        let r = reader.read_u32::<LittleEndian>()?;
-        let p = reader.read_u32::<LittleEndian>()?;
-
-        Ok(Self { logn, r, p })
+impl KdfParams {
+    /// The default params for the given algorithm
+    pub fn default_for(algorithm: KdfAlgorithm) -> Self {
+        match algorithm {
+            KdfAlgorithm::Scrypt => Self::Scrypt(ScryptParams {
+                logn: SCRYPT_LOGN,
+                r: SCRYPT_R,
+                p: SCRYPT_P,
+            }),
+            KdfAlgorithm::Argon2id => Self::Argon2id(Argon2Params {
+                m_cost: ARGON2_M_COST,
+                t_cost: ARGON2_T_COST,
+                p_cost: ARGON2_P_COST,
+            }),
+        }
     }
-}
 
-impl Into<scrypt::Params> for ScryptMetadata {
-    fn into(self) -> scrypt::Params {
-        scrypt::Params::new(self.logn, self.r, self.p)
-            .unwrap_or_else(|error| panic!("invalid scrypt params: {}", error))
+    /// The algorithm these params belong to
+    pub fn algorithm(&self) -> KdfAlgorithm {
+        match self {
+            Self::Scrypt(_) => KdfAlgorithm::Scrypt,
+            Self::Argon2id(_) => KdfAlgorithm::Argon2id,
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::fs::OpenOptions;
-
-    use super::*;
-    use crate::password::*;
 
-    const VAULT_PASSWD: &'static str = "123";
-    const VAULT_PATH: &'static str = "target/debug.deck";
+    /// Pick the highest cost for the algorithm whose key derivation
+    /// stays under the target time on this machine.
+    /// The params are never weaker than the defaults, even when
+    /// the defaults already take longer than the target.
+    pub fn calibrate(algorithm: KdfAlgorithm, target: Duration) -> Self {
+        let mut params = Self::default_for(algorithm);
 
-    fn test_vault() -> Vault {
-        let mut test_entries = HashMap::new();
+        while let Some(stronger) = params.stronger() {
+            if stronger.benchmark() > target {
+                break;
+            }
 
-        test_entries.insert(
-            "Reddit",
-            vec![Entry::new("user1", "321foo"), Entry::new("user2", "123bar")],
-        );
-        test_entries.insert("Github", vec![Entry::new("foo@email.com", "baz")]);
-        test_entries.insert(
-            "Google",
-            vec![
-                Entry::new("main", "password"),
-                Entry::new("secondary", "password"),
-            ],
-        );
+            params = stronger;
+        }
 
-        let mut vault = Vault::new(VAULT_PASSWD);
+        params
+    }
 
-        for (group, entries) in test_entries.iter() {
-            for entry in entries.iter() {
-                vault.insert_entry(group, entry.clone()).unwrap();
+    /// The next cost step, doubling the memory (and time) cost
+    /// until the upper bound, then the argon2 iterations
+    fn stronger(&self) -> Option<Self> {
+        match *self {
+            Self::Scrypt(params) if params.logn < SCRYPT_MAX_LOGN => {
+                Some(Self::Scrypt(ScryptParams {
+                    logn: params.logn + 1,
+                    ..params
+                }))
+            }
+            Self::Argon2id(params) if params.m_cost < ARGON2_CALIBRATION_MAX_M_COST => {
+                Some(Self::Argon2id(Argon2Params {
+                    m_cost: params
+                        .m_cost
+                        .saturating_mul(2)
+                        .min(ARGON2_CALIBRATION_MAX_M_COST),
+                    ..params
+                }))
+            }
+            Self::Argon2id(params) if params.t_cost < ARGON2_MAX_T_COST => {
+                Some(Self::Argon2id(Argon2Params {
+                    t_cost: params.t_cost + 1,
+                    ..params
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Time a key derivation with these params
+    fn benchmark(&self) -> Duration {
+        let salt = [0; SALT_SIZE];
+
+        let start = Instant::now();
+        // the params were built from valid ones, so the result can be ignored
+        let _ = self.derive_key("pwdeck calibration", &salt);
+        start.elapsed()
+    }
+
+    /// Derive the vault key from the master password
+    fn derive_key(&self, password: &str, salt: &[u8]) -> PwdResult<SecVec<u8>> {
+        let mut key = vec![0; KEY_SIZE];
+
+        // the key lenght is ok, so only the params can be wrong
+        match self {
+            Self::Scrypt(metadata) => {
+                let params = scrypt::Params::try_from(*metadata)?;
+                scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)
+                    .map_err(|_| PwdError::InvalidKdfParams)?;
+            }
+            Self::Argon2id(metadata) => {
+                let params = argon2::Params::try_from(*metadata)?;
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                    .hash_password_into(password.as_bytes(), salt, &mut key)
+                    .map_err(|_| PwdError::InvalidKdfParams)?;
+            }
+        }
+
+        Ok(key.into())
+    }
+
+    fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+        match reader.read_u8()? {
+            KDF_SCRYPT => Ok(Self::Scrypt(ScryptParams::read(reader)?)),
+            KDF_ARGON2ID => Ok(Self::Argon2id(Argon2Params::read(reader)?)),
+            id => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown KDF id: {}", id),
+            )),
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Self::Scrypt(metadata) => {
+                writer.write_u8(KDF_SCRYPT)?;
+                metadata.write(writer)
+            }
+            Self::Argon2id(metadata) => {
+                writer.write_u8(KDF_ARGON2ID)?;
+                metadata.write(writer)
+            }
+        }
+    }
+}
+
+/// The scrypt cost params
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScryptParams {
+    /// log2 of the CPU/memory cost
+    pub logn: u8,
+    /// the block size
+    pub r: u32,
+    /// the parallelization
+    pub p: u32,
+}
+
+impl ScryptParams {
+    fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+        let logn = reader.read_u8()?;
+        let r = reader.read_u32::<LittleEndian>()?;
+        let p = reader.read_u32::<LittleEndian>()?;
+
+        Ok(Self { logn, r, p })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(self.logn)?;
+        writer.write_u32::<LittleEndian>(self.r)?;
+        writer.write_u32::<LittleEndian>(self.p)?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Scrypt(params) => write!(
+                f,
+                "scrypt (logn={}, r={}, p={})",
+                params.logn, params.r, params.p
+            ),
+            Self::Argon2id(params) => write!(
+                f,
+                "argon2id (memory={} KiB, iterations={}, parallelism={})",
+                params.m_cost, params.t_cost, params.p_cost
+            ),
+        }
+    }
+}
+
+impl TryFrom<ScryptParams> for scrypt::Params {
+    type Error = PwdError;
+
+    fn try_from(metadata: ScryptParams) -> PwdResult<Self> {
+        if metadata.logn > SCRYPT_MAX_LOGN || metadata.r > SCRYPT_MAX_R || metadata.p > SCRYPT_MAX_P
+        {
+            return Err(PwdError::InvalidKdfParams);
+        }
+
+        scrypt::Params::new(metadata.logn, metadata.r, metadata.p)
+            .map_err(|_| PwdError::InvalidKdfParams)
+    }
+}
+
+/// The Argon2id cost params
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// the memory cost in KiB
+    pub m_cost: u32,
+    /// the number of iterations
+    pub t_cost: u32,
+    /// the degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Argon2Params {
+    fn read<R: Read + Seek>(reader: &mut R) -> io::Result<Self> {
+        let m_cost = reader.read_u32::<LittleEndian>()?;
+        let t_cost = reader.read_u32::<LittleEndian>()?;
+        let p_cost = reader.read_u32::<LittleEndian>()?;
+
+        Ok(Self {
+            m_cost,
+            t_cost,
+            p_cost,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.m_cost)?;
+        writer.write_u32::<LittleEndian>(self.t_cost)?;
+        writer.write_u32::<LittleEndian>(self.p_cost)?;
+
+        Ok(())
+    }
+}
+
+impl TryFrom<Argon2Params> for argon2::Params {
+    type Error = PwdError;
+
+    fn try_from(metadata: Argon2Params) -> PwdResult<Self> {
+        if metadata.m_cost > ARGON2_MAX_M_COST
+            || metadata.t_cost > ARGON2_MAX_T_COST
+            || metadata.p_cost > ARGON2_MAX_P_COST
+        {
+            return Err(PwdError::InvalidKdfParams);
+        }
+
+        argon2::Params::new(
+            metadata.m_cost,
+            metadata.t_cost,
+            metadata.p_cost,
+            Some(KEY_SIZE),
+        )
+        .map_err(|_| PwdError::InvalidKdfParams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::*;
+
+    const VAULT_PASSWD: &str = "123";
+    const VAULT_PATH: &str = "target/debug.deck";
+
+    fn test_vault() -> Vault {
+        let mut test_entries = HashMap::new();
+
+        test_entries.insert(
+            "Reddit",
+            vec![Entry::new("user1", "321foo"), Entry::new("user2", "123bar")],
+        );
+        test_entries.insert("Github", vec![Entry::new("foo@email.com", "baz")]);
+        test_entries.insert(
+            "Google",
+            vec![
+                Entry::new("main", "password"),
+                Entry::new("secondary", "password"),
+            ],
+        );
+
+        let mut vault = Vault::new(VAULT_PASSWD);
+
+        for (group, entries) in test_entries.iter() {
+            for entry in entries.iter() {
+                vault.insert_entry(group, entry.clone()).unwrap();
             }
         }
 
@@ -356,35 +1333,19 @@ mod tests {
     fn sync_file() {
         let vault = test_vault();
 
-        // open write
-        let mut pwdeck_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(VAULT_PATH)
-            .unwrap();
-
-        assert!(vault.sync(&mut pwdeck_file).is_ok());
+        assert!(vault.sync(VAULT_PATH).is_ok());
     }
 
     #[test]
     fn retrieve_vault() {
+        const PATH: &str = "target/debug_retrieve.deck";
+
         {
             let vault = test_vault();
-
-            // open write
-            let mut pwdeck_file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(VAULT_PATH)
-                .unwrap();
-
-            vault.sync(&mut pwdeck_file).unwrap();
+            vault.sync(PATH).unwrap();
         }
 
-        // open read only
-        let mut pwdeck_file = File::open(VAULT_PATH).unwrap();
-
-        let vault = Vault::from_file(&mut pwdeck_file, VAULT_PASSWD);
+        let vault = Vault::from_file(PATH, VAULT_PASSWD);
         assert!(vault.is_ok());
         let vault = vault.unwrap();
 
@@ -392,12 +1353,1055 @@ mod tests {
         assert_eq!(vault.schema.passwords.len(), 3);
     }
 
+    /// Return a vault file content for the test vault
+    fn test_vault_bytes() -> Vec<u8> {
+        let vault = test_vault();
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        vault.seal(&schema).unwrap()
+    }
+
     #[test]
-    #[should_panic]
     fn retrieve_wrong_password() {
-        // open read only
-        let mut pwdeck_file = File::open(VAULT_PATH).unwrap();
+        let vault = Vault::from_bytes(test_vault_bytes(), "Wrong password");
+        assert!(matches!(vault, Err(PwdError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn retrieve_truncated_header() {
+        let mut buffer = test_vault_bytes();
+        buffer.truncate(20);
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    #[test]
+    fn retrieve_invalid_scrypt_params() {
+        let mut buffer = test_vault_bytes();
+        // r follows the KDF id and logn, zero is not allowed by scrypt
+        buffer[12..16].copy_from_slice(&[0; 4]);
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    #[test]
+    fn retrieve_invalid_json() {
+        let vault = test_vault();
+        let buffer = vault.seal(b"{ not json").unwrap();
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    /// Return the test vault content in the headerless legacy layout
+    fn legacy_vault_bytes() -> Vec<u8> {
+        let vault = test_vault();
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let nonce = [0; NONCE_SIZE];
+
+        // legacy files start straight with the header body
+        let mut buffer = Vec::new();
+        match vault.kdf {
+            KdfParams::Scrypt(metadata) => metadata.write(&mut buffer).unwrap(),
+            _ => unreachable!(),
+        }
+        buffer.extend_from_slice(&nonce);
+        buffer.extend_from_slice(&vault.salt);
+
+        // and don't authenticate it
+        let key: &[u8] = &vault.key;
+        let cipher = Aes256Gcm::new(key.into());
+        buffer.extend(cipher.encrypt(&nonce.into(), schema.as_ref()).unwrap());
+
+        buffer
+    }
+
+    #[test]
+    fn header_magic_and_version() {
+        let buffer = test_vault_bytes();
+
+        assert_eq!(&buffer[..4], MAGIC);
+        assert_eq!(u16::from_le_bytes([buffer[4], buffer[5]]), FORMAT_VERSION);
+    }
+
+    #[test]
+    fn retrieve_legacy_vault() {
+        let vault = Vault::from_bytes(legacy_vault_bytes(), VAULT_PASSWD).unwrap();
+
+        assert_eq!(vault.format_version(), LEGACY_FORMAT_VERSION);
+        assert!(vault.needs_upgrade());
+        assert_eq!(vault.schema.passwords.len(), 3);
+    }
+
+    #[test]
+    fn upgrade_legacy_vault() {
+        let vault = Vault::from_bytes(legacy_vault_bytes(), VAULT_PASSWD).unwrap();
+
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let upgraded = Vault::from_bytes(vault.seal(&schema).unwrap(), VAULT_PASSWD).unwrap();
+
+        assert_eq!(upgraded.format_version(), FORMAT_VERSION);
+        assert!(!upgraded.needs_upgrade());
+        assert_eq!(upgraded.schema.passwords.len(), 3);
+    }
+
+    /// Return the test vault content in the versioned layout
+    /// with the scrypt params only, written before the KDF id
+    fn scrypt_vault_bytes(version: u16) -> Vec<u8> {
+        let vault = test_vault();
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let nonce = [0; NONCE_SIZE];
+
+        let mut body = Vec::new();
+        match vault.kdf {
+            KdfParams::Scrypt(metadata) => metadata.write(&mut body).unwrap(),
+            _ => unreachable!(),
+        }
+        body.extend_from_slice(&nonce);
+        body.extend_from_slice(&vault.salt);
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(MAGIC);
+        buffer.extend_from_slice(&version.to_le_bytes());
+        buffer.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&body);
+
+        // the first versioned files didn't authenticate the header
+        let aad = if version == UNAUTHENTICATED_FORMAT_VERSION {
+            Vec::new()
+        } else {
+            buffer.clone()
+        };
+        let key: &[u8] = &vault.key;
+        let cipher = Aes256Gcm::new(key.into());
+        let payload = Payload {
+            msg: &schema,
+            aad: &aad,
+        };
+        buffer.extend(cipher.encrypt(&nonce.into(), payload).unwrap());
+
+        buffer
+    }
+
+    #[test]
+    fn retrieve_older_versions() {
+        for &version in [UNAUTHENTICATED_FORMAT_VERSION, SCRYPT_FORMAT_VERSION].iter() {
+            let vault = Vault::from_bytes(scrypt_vault_bytes(version), VAULT_PASSWD).unwrap();
+
+            assert_eq!(vault.format_version(), version);
+            assert_eq!(vault.kdf_algorithm(), KdfAlgorithm::Scrypt);
+            assert!(vault.needs_upgrade());
+            assert_eq!(vault.schema.passwords.len(), 3);
+        }
+    }
+
+    #[test]
+    fn upgrade_older_versions() {
+        for &version in [UNAUTHENTICATED_FORMAT_VERSION, SCRYPT_FORMAT_VERSION].iter() {
+            let vault = Vault::from_bytes(scrypt_vault_bytes(version), VAULT_PASSWD).unwrap();
+
+            let schema = serde_json::to_vec(&vault.schema).unwrap();
+            let upgraded = Vault::from_bytes(vault.seal(&schema).unwrap(), VAULT_PASSWD).unwrap();
+
+            assert_eq!(upgraded.format_version(), FORMAT_VERSION);
+            assert_eq!(upgraded.schema.passwords.len(), 3);
+        }
+    }
+
+    #[test]
+    fn retrieve_unsupported_version() {
+        let mut buffer = test_vault_bytes();
+        buffer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(
+            vault,
+            Err(PwdError::UnsupportedVaultVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn retrieve_wrong_header_length() {
+        let mut buffer = test_vault_bytes();
+        buffer[6] = buffer[6].wrapping_add(1);
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    #[test]
+    fn retrieve_random_file() {
+        let mut buffer = vec![0; 128];
+        OsRng.fill_bytes(&mut buffer);
+        // without the magic, the file is read with the legacy layout,
+        // where r follows logn: make sure the random params are rejected
+        buffer[1..5].copy_from_slice(&[0; 4]);
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    #[test]
+    fn tampered_header() {
+        // cheap params, since the key is derived once per header byte
+        let kdf = KdfParams::Scrypt(ScryptParams {
+            logn: 4,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        });
+        let mut vault = Vault::with_params(VAULT_PASSWD, kdf).unwrap();
+        vault
+            .insert_entry("Test", Entry::new("test", "test"))
+            .unwrap();
+
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let buffer = vault.seal(&schema).unwrap();
+        let header_len = buffer.len() - schema.len() - 16; // 16 bytes of tag
+
+        for i in 0..header_len {
+            let mut tampered = buffer.clone();
+            tampered[i] ^= 0x01;
+
+            match Vault::from_bytes(tampered, VAULT_PASSWD) {
+                Err(PwdError::InvalidVaultFile) | Err(PwdError::AuthenticationFailed) => {}
+                // the version bytes
+                Err(PwdError::UnsupportedVaultVersion(_)) => assert!((4..6).contains(&i)),
+                Err(error) => panic!("byte {}: unexpected error {:?}", i, error),
+                Ok(_) => panic!("byte {}: tampered header accepted", i),
+            }
+        }
+    }
+
+    #[test]
+    fn lowered_scrypt_logn() {
+        let mut buffer = test_vault_bytes();
+        // logn follows magic, version, length and KDF id
+        buffer[11] -= 1;
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn excessive_scrypt_params() {
+        let mut buffer = test_vault_bytes();
+        buffer[11] = SCRYPT_MAX_LOGN + 1;
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    /// Return a vault using argon2id with cheap params
+    fn argon2_vault() -> Vault {
+        let mut vault = Vault::with_params(VAULT_PASSWD, TEST_KDF).unwrap();
+        vault
+            .insert_entry("Test", Entry::new("test", "test"))
+            .unwrap();
+
+        vault
+    }
+
+    #[test]
+    fn default_kdf_is_scrypt() {
+        let vault = Vault::new(VAULT_PASSWD);
+        assert_eq!(vault.kdf_algorithm(), KdfAlgorithm::Scrypt);
+    }
+
+    #[test]
+    fn retrieve_argon2_vault() {
+        let vault = argon2_vault();
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let buffer = vault.seal(&schema).unwrap();
+
+        assert_eq!(buffer[10], KDF_ARGON2ID);
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD).unwrap();
+        assert_eq!(vault.kdf_algorithm(), KdfAlgorithm::Argon2id);
+        assert_eq!(vault.schema.passwords.len(), 1);
+    }
+
+    #[test]
+    fn argon2_wrong_password() {
+        let vault = argon2_vault();
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let buffer = vault.seal(&schema).unwrap();
+
+        let vault = Vault::from_bytes(buffer, "Wrong password");
+        assert!(matches!(vault, Err(PwdError::AuthenticationFailed)));
+    }
+
+    #[test]
+    fn unknown_kdf() {
+        let mut buffer = test_vault_bytes();
+        buffer[10] = 0xff;
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    #[test]
+    fn excessive_argon2_params() {
+        let vault = argon2_vault();
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let mut buffer = vault.seal(&schema).unwrap();
+        // m_cost follows the KDF id
+        buffer[11..15].copy_from_slice(&(ARGON2_MAX_M_COST + 1).to_le_bytes());
+
+        let vault = Vault::from_bytes(buffer, VAULT_PASSWD);
+        assert!(matches!(vault, Err(PwdError::InvalidVaultFile)));
+    }
+
+    #[test]
+    fn change_master_password() {
+        let mut vault = argon2_vault();
+        let old_salt = vault.salt;
+
+        vault
+            .change_master_password(VAULT_PASSWD, "new password")
+            .unwrap();
+        assert_ne!(vault.salt, old_salt);
+
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let buffer = vault.seal(&schema).unwrap();
+
+        let old = Vault::from_bytes(buffer.clone(), VAULT_PASSWD);
+        assert!(matches!(old, Err(PwdError::AuthenticationFailed)));
+
+        let vault = Vault::from_bytes(buffer, "new password").unwrap();
+        assert_eq!(vault.schema.passwords.len(), 1);
+    }
+
+    #[test]
+    fn change_master_password_wrong_old() {
+        let mut vault = argon2_vault();
+        let old_salt = vault.salt;
+
+        let result = vault.change_master_password("Wrong password", "new password");
+        assert!(matches!(result, Err(PwdError::AuthenticationFailed)));
+        assert_eq!(vault.salt, old_salt);
+    }
+
+    #[test]
+    fn change_master_password_empty() {
+        let mut vault = argon2_vault();
+
+        let result = vault.change_master_password(VAULT_PASSWD, "");
+        assert!(matches!(result, Err(PwdError::InvalidPassword)));
+    }
+
+    #[test]
+    fn open_with_cached_key() {
+        const PATH: &str = "target/debug_cached_key.deck";
+
+        let vault = argon2_vault();
+        vault.sync(PATH).unwrap();
+        let key = SecVec::from(vault.key().to_vec());
+
+        let mut opened = Vault::from_file_with_key(PATH, key).unwrap();
+        assert_eq!(opened.schema.passwords.len(), 1);
+        // re-keying needs the master password
+        assert!(opened.set_kdf_params(opened.kdf).is_err());
+
+        let wrong_key = SecVec::from(vec![0; KEY_SIZE]);
+        assert!(matches!(
+            Vault::from_file_with_key(PATH, wrong_key),
+            Err(PwdError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn sync_shrinking_vault() {
+        const PATH: &str = "target/debug_shrink.deck";
+
+        let mut vault = argon2_vault();
+        vault
+            .insert_entry("Test", Entry::new("other", &"foo".repeat(1024)))
+            .unwrap();
+        vault.sync(PATH).unwrap();
+
+        // a smaller ciphertext must not leave the old bytes behind
+        vault.schema.passwords.get_mut("Test").unwrap().pop();
+        vault.sync(PATH).unwrap();
+
+        let vault = Vault::from_file(PATH, VAULT_PASSWD).unwrap();
+        assert_eq!(vault.schema.passwords["Test"].len(), 1);
+    }
+
+    #[test]
+    fn sync_keeps_backup() {
+        const PATH: &str = "target/debug_backup.deck";
+        let _ = fs::remove_file(PATH);
+        let _ = fs::remove_file(backup_path(PATH));
+
+        let mut vault = argon2_vault();
+        vault.sync(PATH).unwrap();
+        // there is nothing to back up on the first sync
+        assert!(!backup_path(PATH).exists());
+
+        vault
+            .insert_entry("Test", Entry::new("other", "foo"))
+            .unwrap();
+        vault.sync(PATH).unwrap();
+
+        let backup = Vault::from_file(backup_path(PATH), VAULT_PASSWD).unwrap();
+        assert_eq!(backup.schema.passwords["Test"].len(), 1);
+
+        let vault = Vault::from_file(PATH, VAULT_PASSWD).unwrap();
+        assert_eq!(vault.schema.passwords["Test"].len(), 2);
+
+        // the temporary file is renamed over the vault
+        assert!(!sibling_path(Path::new(PATH), "tmp").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sync_private_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const PATH: &str = "target/debug_permissions.deck";
+
+        argon2_vault().sync(PATH).unwrap();
+
+        let mode = fs::metadata(PATH).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sync_over_stale_temp_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const PATH: &str = "target/debug_stale_temp.deck";
+
+        // left by an interrupted sync, readable by everyone
+        let temp_path = sibling_path(Path::new(PATH), "tmp");
+        fs::write(&temp_path, b"stale").unwrap();
+        fs::set_permissions(&temp_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        argon2_vault().sync(PATH).unwrap();
+
+        let mode = fs::metadata(PATH).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn backup_path_name() {
+        assert_eq!(
+            backup_path("some/dir/vault.pwd"),
+            PathBuf::from("some/dir/vault.pwd.bak")
+        );
+        assert_eq!(backup_path("vault.pwd"), PathBuf::from("vault.pwd.bak"));
+    }
+
+    #[test]
+    fn with_invalid_params() {
+        let kdf = KdfParams::Scrypt(ScryptParams {
+            logn: SCRYPT_MAX_LOGN + 1,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        });
+
+        let vault = Vault::with_params(VAULT_PASSWD, kdf);
+        assert!(matches!(vault, Err(PwdError::InvalidKdfParams)));
+    }
+
+    #[test]
+    fn set_kdf_params() {
+        let mut vault = argon2_vault();
+        let old_salt = vault.salt;
+
+        let kdf = KdfParams::Scrypt(ScryptParams {
+            logn: 5,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        });
+        vault.set_kdf_params(kdf).unwrap();
+        assert_ne!(vault.salt, old_salt);
+
+        let schema = serde_json::to_vec(&vault.schema).unwrap();
+        let vault = Vault::from_bytes(vault.seal(&schema).unwrap(), VAULT_PASSWD).unwrap();
+
+        assert_eq!(vault.kdf_params(), kdf);
+        assert_eq!(vault.schema.passwords.len(), 1);
+    }
+
+    #[test]
+    fn calibrate_never_below_default() {
+        let kdf = KdfParams::calibrate(KdfAlgorithm::Scrypt, Duration::from_millis(0));
+        assert_eq!(kdf, KdfParams::default_for(KdfAlgorithm::Scrypt));
+    }
+
+    #[test]
+    fn calibration_steps_stay_in_bounds() {
+        for algorithm in [KdfAlgorithm::Scrypt, KdfAlgorithm::Argon2id].iter() {
+            let mut kdf = KdfParams::default_for(*algorithm);
+            while let Some(stronger) = kdf.stronger() {
+                kdf = stronger;
+            }
+
+            match kdf {
+                KdfParams::Scrypt(params) => {
+                    assert_eq!(params.logn, SCRYPT_MAX_LOGN);
+                    assert!(scrypt::Params::try_from(params).is_ok());
+                }
+                KdfParams::Argon2id(params) => {
+                    assert_eq!(params.m_cost, ARGON2_CALIBRATION_MAX_M_COST);
+                    assert_eq!(params.t_cost, ARGON2_MAX_T_COST);
+                    assert!(argon2::Params::try_from(params).is_ok());
+                }
+            }
+        }
+    }
+
+    /// Return the ID of the first entry with the given name
+    fn entry_id(vault: &Vault, group: &str, name: &str) -> String {
+        vault.schema.passwords[group]
+            .iter()
+            .find(|entry| entry.name() == name)
+            .unwrap()
+            .id()
+            .to_string()
+    }
+
+    #[test]
+    fn get_entry() {
+        let vault = test_vault();
+        let id = entry_id(&vault, "Github", "foo@email.com");
+
+        let (group, entry) = vault.entry(&id).unwrap();
+        assert_eq!(group, "Github");
+        assert_eq!(entry.name(), "foo@email.com");
+
+        assert!(vault.entry("unknown").is_none());
+    }
+
+    #[test]
+    fn remove_entry() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Reddit", "user1");
+
+        let entry = vault.remove_entry(&id).unwrap();
+        assert_eq!(entry.name(), "user1");
+        assert!(vault.entry(&id).is_none());
+        assert_eq!(vault.schema.passwords["Reddit"].len(), 1);
+
+        assert!(matches!(
+            vault.remove_entry(&id),
+            Err(PwdError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn search() {
+        use crate::query::Pattern;
+
+        let vault = test_vault();
+
+        let query = Query::new();
+        assert_eq!(vault.search(&query).count(), 5);
+
+        let query = Query::new().group(Pattern::exact("reddit").case_insensitive());
+        let mut names: Vec<&str> = vault.search(&query).map(|(_, e)| e.name()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["user1", "user2"]);
+
+        let query = Query::new()
+            .group(Pattern::glob("G*"))
+            .name(Pattern::substring("main"));
+        let results: Vec<(&str, &Entry)> = vault.search(&query).collect();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "Google");
+
+        let query = Query::new().name(Pattern::exact("nobody"));
+        assert_eq!(vault.search(&query).count(), 0);
+    }
+
+    #[test]
+    fn fuzzy_search() {
+        let vault = test_vault();
+
+        let matches = vault.schema.fuzzy_search("gihtub");
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].group, "Github");
+        assert!(matches[0].is_confident());
+
+        // exact matches rank first
+        let matches = vault.schema.fuzzy_search("user1");
+        assert_eq!(matches[0].entry.name(), "user1");
+        assert!(matches.iter().all(|m| m.group == "Reddit"));
+        assert!(matches[0].score > matches[1].score);
+
+        assert!(vault.schema.fuzzy_search("zzzz").is_empty());
+    }
+
+    #[test]
+    fn resolve_id() {
+        let vault = test_vault();
+        let id = entry_id(&vault, "Github", "foo@email.com");
+
+        assert_eq!(vault.resolve_id(&id).unwrap(), id);
+        assert_eq!(vault.resolve_id(&id[..vault.short_id_len()]).unwrap(), id);
+
+        // too short prefixes are rejected
+        assert!(matches!(
+            vault.resolve_id(&id[..MIN_ID_PREFIX_LEN - 1]),
+            Err(PwdError::EntryNotFound)
+        ));
+        assert!(matches!(
+            vault.resolve_id("unknown"),
+            Err(PwdError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn ambiguous_id_prefix() {
+        let mut vault = Vault::new(VAULT_PASSWD);
+        let entry: Entry =
+            serde_json::from_str(r#"{"id":"abcdef1","name":"a","password":"1"}"#).unwrap();
+        vault.insert_entry("Test", entry).unwrap();
+        let entry: Entry =
+            serde_json::from_str(r#"{"id":"abcdef2","name":"b","password":"2"}"#).unwrap();
+        vault.insert_entry("Test", entry).unwrap();
+
+        assert!(matches!(
+            vault.resolve_id("abcd"),
+            Err(PwdError::AmbiguousEntry)
+        ));
+        assert_eq!(vault.resolve_id("abcdef2").unwrap(), "abcdef2");
+    }
+
+    #[test]
+    fn short_id_len() {
+        assert_eq!(Vault::new(VAULT_PASSWD).short_id_len(), SHORT_ID_LEN);
+
+        let mut vault = Vault::new(VAULT_PASSWD);
+        for id in &["0123456789a", "0123456789b"] {
+            let json = format!(r#"{{"id":"{}","name":"a","password":"1"}}"#, id);
+            let entry: Entry = serde_json::from_str(&json).unwrap();
+            vault.insert_entry("Test", entry).unwrap();
+        }
+
+        assert_eq!(vault.short_id_len(), 11);
+    }
+
+    #[test]
+    fn short_id_len_with_duplicated_ids() {
+        let mut vault = Vault::new(VAULT_PASSWD);
+        for _ in 0..2 {
+            let json = r#"{"id":"0123456789","name":"a","password":"1"}"#;
+            let entry: Entry = serde_json::from_str(json).unwrap();
+            vault.insert_entry("Test", entry).unwrap();
+        }
+
+        assert_eq!(vault.short_id_len(), 10);
+    }
+
+    #[test]
+    fn short_ids() {
+        assert_eq!(short_id("0123456789", 7), "0123456");
+        assert_eq!(short_id("abc", 7), "abc");
+        // cut inside a multibyte character
+        assert_eq!(short_id("abcdeféé", 7), "abcdeféé");
+    }
+
+    #[test]
+    fn remove_last_entry_of_group() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Github", "foo@email.com");
+
+        vault.remove_entry(&id).unwrap();
+        assert!(!vault.schema.passwords.contains_key("Github"));
+    }
+
+    #[test]
+    fn update_entry() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Reddit", "user1");
+
+        let update = EntryUpdate {
+            name: Some(String::from("user3")),
+            password: Some(SecString::from("new password")),
+            ..EntryUpdate::default()
+        };
+        vault.update_entry(&id, update).unwrap();
+
+        let (_, entry) = vault.entry(&id).unwrap();
+        assert_eq!(entry.name(), "user3");
+        assert_eq!(entry.password().as_str(), "new password");
+
+        // fields set to `None` are kept
+        vault.update_entry(&id, EntryUpdate::default()).unwrap();
+        let (_, entry) = vault.entry(&id).unwrap();
+        assert_eq!(entry.name(), "user3");
+        assert_eq!(entry.history()[0].1.as_str(), "321foo");
+    }
+
+    #[test]
+    fn restore_password() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Reddit", "user1");
+
+        let update = EntryUpdate {
+            password: Some(SecString::from("leaked")),
+            ..EntryUpdate::default()
+        };
+        vault.update_entry(&id, update).unwrap();
+        vault.restore_password(&id, 0).unwrap();
+
+        let (_, entry) = vault.entry(&id).unwrap();
+        assert_eq!(entry.password().as_str(), "321foo");
+        assert_eq!(entry.history()[0].1.as_str(), "leaked");
+
+        assert!(matches!(
+            vault.restore_password(&id, 1),
+            Err(PwdError::HistoryNotFound)
+        ));
+        assert!(matches!(
+            vault.restore_password("unknown", 0),
+            Err(PwdError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn update_entry_empty_password() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Reddit", "user1");
+
+        let update = EntryUpdate {
+            password: Some(SecString::from("")),
+            ..EntryUpdate::default()
+        };
+        assert!(matches!(
+            vault.update_entry(&id, update),
+            Err(PwdError::InvalidPassword)
+        ));
+        assert_eq!(vault.entry(&id).unwrap().1.password().as_str(), "321foo");
+    }
+
+    #[test]
+    fn move_entry() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Github", "foo@email.com");
+
+        vault.move_entry(&id, "GitHub").unwrap();
+
+        assert_eq!(vault.entry(&id).unwrap().0, "GitHub");
+        assert!(!vault.schema.passwords.contains_key("Github"));
+
+        assert!(matches!(
+            vault.move_entry("unknown", "GitHub"),
+            Err(PwdError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn rename_group() {
+        let mut vault = test_vault();
+
+        vault.rename_group("Reddit", "reddit").unwrap();
+        assert!(!vault.schema.passwords.contains_key("Reddit"));
+        assert_eq!(vault.schema.passwords["reddit"].len(), 2);
+
+        // renaming into an existing group merges the entries
+        vault.rename_group("reddit", "Google").unwrap();
+        assert_eq!(vault.schema.passwords["Google"].len(), 4);
+
+        assert!(matches!(
+            vault.rename_group("Reddit", "reddit"),
+            Err(PwdError::GroupNotFound)
+        ));
+    }
+
+    #[test]
+    fn insert_missing() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Github", "foo@email.com");
+
+        let mut schema = VaultSchema::default();
+        let mut known = vault.entry(&id).unwrap().1.clone();
+        known
+            .update(EntryUpdate {
+                password: Some(SecString::from("changed")),
+                ..EntryUpdate::default()
+            })
+            .unwrap();
+        schema
+            .passwords
+            .insert(String::from("Github"), vec![known, Entry::new("bar", "x")]);
+
+        assert_eq!(vault.insert_missing(schema), 1);
+        assert_eq!(vault.schema().entry_count(), 6);
+        // the entries already in the vault are kept
+        assert_eq!(vault.entry(&id).unwrap().1.password().as_str(), "baz");
+    }
+
+    fn change_password(vault: &mut Vault, id: &str, password: &str) {
+        let update = EntryUpdate {
+            password: Some(SecString::from(password)),
+            ..EntryUpdate::default()
+        };
+        vault.update_entry(id, update).unwrap();
+    }
+
+    fn copy(vault: &Vault) -> Vault {
+        let mut copy = Vault::new(VAULT_PASSWD);
+        copy.replace_schema(
+            serde_json::from_str(&serde_json::to_string(&vault.schema).unwrap()).unwrap(),
+        );
+        copy
+    }
+
+    #[test]
+    fn merge_added_and_updated() {
+        let mut vault = test_vault();
+        let mut other = copy(&vault);
+
+        let id = entry_id(&other, "Github", "foo@email.com");
+        change_password(&mut other, &id, "new");
+        other.move_entry(&id, "GitHub").unwrap();
+        other
+            .insert_entry("Gitlab", Entry::new("me", "123"))
+            .unwrap();
+        // only in this vault, kept
+        vault
+            .insert_entry("Local", Entry::new("me", "456"))
+            .unwrap();
+
+        let report = vault.merge(other.schema(), |_| panic!("no conflicts"));
+        assert_eq!(report.added, ["Gitlab: me"]);
+        assert_eq!(report.updated, ["GitHub: foo@email.com"]);
+        assert!(report.conflicts.is_empty());
+
+        let (group, entry) = vault.entry(&id).unwrap();
+        assert_eq!(group, "GitHub");
+        assert_eq!(entry.password().as_str(), "new");
+        assert_eq!(entry.history()[0].1.as_str(), "baz");
+        assert_eq!(vault.schema().entry_count(), 7);
+
+        // merging again changes nothing
+        let report = vault.merge(other.schema(), |_| panic!("no conflicts"));
+        assert!(report.added.is_empty() && report.updated.is_empty());
+    }
+
+    #[test]
+    fn merge_older_entry() {
+        let mut vault = test_vault();
+        let other = copy(&vault);
+
+        let id = entry_id(&vault, "Reddit", "user1");
+        change_password(&mut vault, &id, "new");
+
+        let report = vault.merge(other.schema(), |_| panic!("no conflicts"));
+        assert!(report.updated.is_empty());
+        assert_eq!(vault.entry(&id).unwrap().1.password().as_str(), "new");
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        let mut vault = test_vault();
+        let mut other = copy(&vault);
+
+        let reddit = entry_id(&vault, "Reddit", "user1");
+        let google = entry_id(&vault, "Google", "main");
+        for (id, ours, theirs) in [(&reddit, "ours", "theirs"), (&google, "ours", "theirs")] {
+            change_password(&mut vault, id, ours);
+            change_password(&mut other, id, theirs);
+        }
+
+        let report = vault.merge(other.schema(), |conflict| {
+            assert_eq!(conflict.ours.password().as_str(), "ours");
+            assert_eq!(conflict.theirs.password().as_str(), "theirs");
+            match conflict.group {
+                "Google" => Resolution::Ours,
+                _ => Resolution::Theirs,
+            }
+        });
+        assert_eq!(
+            report.conflicts,
+            [
+                (String::from("Google: main"), Resolution::Ours),
+                (String::from("Reddit: user1"), Resolution::Theirs),
+            ]
+        );
+
+        // the losing password is kept in the history
+        let (_, entry) = vault.entry(&google).unwrap();
+        assert_eq!(entry.password().as_str(), "ours");
+        assert!(entry.history().iter().any(|(_, p)| p.as_str() == "theirs"));
+
+        let (_, entry) = vault.entry(&reddit).unwrap();
+        assert_eq!(entry.password().as_str(), "theirs");
+        assert!(entry.history().iter().any(|(_, p)| p.as_str() == "ours"));
+    }
+
+    fn change_url(vault: &mut Vault, id: &str, url: &str) {
+        let update = EntryUpdate {
+            url: Some(String::from(url)),
+            ..EntryUpdate::default()
+        };
+        vault.update_entry(id, update).unwrap();
+    }
+
+    #[test]
+    fn merge_renamed_group() {
+        let mut vault = test_vault();
+        let mut other = copy(&vault);
+
+        let id = entry_id(&vault, "Github", "foo@email.com");
+        other.rename_group("Github", "GitHub").unwrap();
+
+        let report = vault.merge(other.schema(), |_| panic!("no conflicts"));
+        assert_eq!(report.updated, ["GitHub: foo@email.com"]);
+        assert_eq!(vault.entry(&id).unwrap().0, "GitHub");
+        assert!(!vault.schema().passwords.contains_key("Github"));
+    }
+
+    #[test]
+    fn merge_moved_entry_both_ways() {
+        let vault = test_vault();
+        let id = entry_id(&vault, "Reddit", "user1");
+
+        let mut moved = copy(&vault);
+        moved.move_entry(&id, "Social").unwrap();
+
+        // merged into the unchanged copy
+        let mut unchanged = copy(&vault);
+        unchanged.merge(moved.schema(), |_| panic!("no conflicts"));
+        assert_eq!(unchanged.entry(&id).unwrap().0, "Social");
+
+        // and the unchanged copy merged into it
+        moved.merge(vault.schema(), |_| panic!("no conflicts"));
+        assert_eq!(moved.entry(&id).unwrap().0, "Social");
+    }
+
+    #[test]
+    fn merge_same_version_in_other_group() {
+        let mut vault = test_vault();
+        let mut other = copy(&vault);
+
+        // moved without a new revision, e.g. by an older version
+        let id = entry_id(&vault, "Reddit", "user1");
+        let entry = other.take_entry(&id).unwrap();
+        other
+            .schema
+            .passwords
+            .entry(String::from("Social"))
+            .or_default()
+            .push(entry);
+
+        let report = vault.merge(other.schema(), |conflict| {
+            assert_eq!((conflict.group, conflict.their_group), ("Reddit", "Social"));
+            Resolution::Theirs
+        });
+        assert_eq!(
+            report.conflicts,
+            [(String::from("Social: user1"), Resolution::Theirs)]
+        );
+        assert_eq!(vault.entry(&id).unwrap().0, "Social");
+    }
+
+    #[test]
+    fn merge_details() {
+        let mut vault = test_vault();
+        let mut other = copy(&vault);
+
+        // changed in the other vault only
+        let id = entry_id(&vault, "Reddit", "user1");
+        change_url(&mut other, &id, "https://reddit.com");
+
+        let report = vault.merge(other.schema(), |_| panic!("no conflicts"));
+        assert_eq!(report.updated, ["Reddit: user1"]);
+        assert_eq!(
+            vault.entry(&id).unwrap().1.url(),
+            Some("https://reddit.com")
+        );
+
+        // changed in both vaults, without touching the password
+        change_url(&mut vault, &id, "https://ours.example");
+        change_url(&mut other, &id, "https://theirs.example");
+
+        let report = vault.merge(other.schema(), |conflict| {
+            assert_eq!(conflict.ours.differences(conflict.theirs), ["url"]);
+            Resolution::Ours
+        });
+        assert_eq!(
+            report.conflicts,
+            [(String::from("Reddit: user1"), Resolution::Ours)]
+        );
+
+        // the losing values are kept
+        let (_, entry) = vault.entry(&id).unwrap();
+        assert_eq!(entry.url(), Some("https://ours.example"));
+        assert_eq!(entry.discarded()[0].url(), Some("https://theirs.example"));
+
+        // the other vault now takes the merged entry
+        let report = other.merge(vault.schema(), |_| panic!("no conflicts"));
+        assert_eq!(report.updated, ["Reddit: user1"]);
+        assert_eq!(
+            other.entry(&id).unwrap().1.url(),
+            Some("https://ours.example")
+        );
+    }
+
+    #[test]
+    fn merge_removed_entries() {
+        let mut vault = test_vault();
+        let mut other = copy(&vault);
+
+        let id = entry_id(&vault, "Github", "foo@email.com");
+        vault.remove_entry(&id).unwrap();
+
+        // not brought back by the other vault
+        let report = vault.merge(other.schema(), |_| panic!("no conflicts"));
+        assert!(report.added.is_empty());
+        assert_eq!(report.kept_removed, ["Github: foo@email.com"]);
+        assert!(vault.entry(&id).is_none());
+
+        // and removed from it
+        let report = other.merge(vault.schema(), |_| panic!("no conflicts"));
+        assert_eq!(report.removed, ["Github: foo@email.com"]);
+        assert!(other.entry(&id).is_none());
+        assert!(other.schema().removed.contains_key(&id));
+    }
+
+    #[test]
+    fn merge_entry_changed_since_removal() {
+        let mut vault = test_vault();
+        let other = copy(&vault);
+
+        let id = entry_id(&vault, "Github", "foo@email.com");
+        vault.remove_entry(&id).unwrap();
+        // removed before its last change in the other vault
+        vault.schema.removed.insert(id.clone(), 0);
+
+        let report = vault.merge(other.schema(), |_| panic!("no conflicts"));
+        assert_eq!(report.readded, ["Github: foo@email.com"]);
+        assert!(vault.entry(&id).is_some());
+        assert!(!vault.schema().removed.contains_key(&id));
+    }
+
+    #[test]
+    fn generate_otp() {
+        let mut vault = test_vault();
+        let id = entry_id(&vault, "Github", "foo@email.com");
+        assert!(matches!(
+            vault.generate_otp(&id, 59),
+            Err(PwdError::OtpNotFound)
+        ));
+
+        // the RFC 4226 secret
+        let uri = "otpauth://hotp/test?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&counter=1";
+        let update = EntryUpdate {
+            otp: Some(SecString::from(uri)),
+            ..EntryUpdate::default()
+        };
+        vault.update_entry(&id, update).unwrap();
 
-        let _ = Vault::from_file(&mut pwdeck_file, "Wrong password").unwrap();
+        // the counter is saved
+        assert_eq!(vault.generate_otp(&id, 0).unwrap().code, "287082");
+        assert_eq!(vault.generate_otp(&id, 0).unwrap().code, "359152");
+        let otp = vault.entry(&id).unwrap().1.otp().unwrap();
+        assert!(otp.ends_with("&counter=3"));
     }
 }
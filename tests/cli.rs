@@ -0,0 +1,44 @@
+use std::process::Command;
+
+/// Run pwdeck with the given args, returning its exit code
+fn pwdeck(args: &[&str]) -> Option<i32> {
+    Command::new(env!("CARGO_BIN_EXE_pwdeck"))
+        .args(args)
+        .env("PWDECK_VAULT", "target/cli_test.deck")
+        .env("PWDECK_AGENT_SOCK", "target/cli_test_agent.sock")
+        .output()
+        .expect("Can't run pwdeck")
+        .status
+        .code()
+}
+
+#[test]
+fn invalid_policy_exit_code() {
+    let code = pwdeck(&[
+        "generate",
+        "--no-lowercase",
+        "--no-uppercase",
+        "--no-digits",
+        "--no-symbols",
+    ]);
+    assert_eq!(code, Some(4));
+}
+
+#[test]
+fn agent_not_running_exit_code() {
+    assert_eq!(pwdeck(&["agent", "--stop"]), Some(11));
+}
+
+#[test]
+fn usage_error_exit_code() {
+    assert_eq!(pwdeck(&["generate", "bogus"]), Some(1));
+}
+
+#[test]
+fn kdf_options_of_existing_vault() {
+    // the options are rejected before reading the vault
+    std::fs::write("target/cli_test.deck", b"").unwrap();
+
+    let code = pwdeck(&["new", "-s", "test", "-u", "test", "--kdf", "argon2id"]);
+    assert_eq!(code, Some(1));
+}